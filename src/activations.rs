@@ -1,12 +1,178 @@
 use std::f64::MAX;
+use std::io;
+use std::io::{Read, Write};
 
 use plotters::data::float::FloatPrettyPrinter;
 
 use crate::{constants::FloatPrecision, math::DMatrix};
+use crate::layers::Parameter;
+use crate::serialize::{read_matrix, write_matrix};
 
-pub struct Activation {
+// Every column of net/out is one sample, so vector valued activations
+// like softmax act on a whole column at once. Activations are Send + Sync so
+// that inference models can be shared between threads.
+pub trait Activation: Send + Sync {
+    // Stable name including parameters, e.g. "leaky_relu(0.3)", that the
+    // registry can turn back into the same activation
+    fn name(&self) -> String;
+
+    // A copy with the same parameters, e.g. for an InferenceModel
+    fn boxed(&self) -> Box<dyn Activation>;
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix); // out = f(net)
+
+    // Turns delta = dE/d(out) into dE/d(net) in place, i.e. applies the
+    // transposed Jacobian of f to delta. Learnable activations also store
+    // the gradient of their own parameters here.
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix);
+
+    // Gradient step on the activation's own parameters, if it has any
+    fn update(&mut self, rate: FloatPrecision) {}
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Learned parameters with the gradients stored by backward
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        Vec::new()
+    }
+
+    // f for activations that map every element on its own, so that fused
+    // kernels can apply it while computing net
+    fn pointwise(&self) -> Option<Pointwise> {
+        None
+    }
+
+    // Whether f is the identity, so that affine maps around it can be folded
+    fn is_linear(&self) -> bool {
+        false
+    }
+}
+
+pub type Pointwise = Box<dyn Fn(FloatPrecision) -> FloatPrecision + Send + Sync>;
+
+// Lets a Box<dyn Activation>, e.g. from the registry, be used wherever an activation is expected
+impl<T: Activation + ?Sized> Activation for Box<T> {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        (**self).boxed()
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        (**self).forward(net, out)
+    }
+
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        (**self).backward(net, out, delta)
+    }
+
+    fn update(&mut self, rate: FloatPrecision) {
+        (**self).update(rate)
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        (**self).save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        (**self).load(r)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        (**self).parameters()
+    }
+
+    fn pointwise(&self) -> Option<Pointwise> {
+        (**self).pointwise()
+    }
+
+    fn is_linear(&self) -> bool {
+        (**self).is_linear()
+    }
+}
+
+// An activation that is applied to each element on its own
+#[derive(Clone)]
+pub struct Elementwise {
+    pub name: &'static str,
     pub f: fn(FloatPrecision) -> FloatPrecision,
     pub fd: fn(FloatPrecision) -> FloatPrecision,
+    // f' written in terms of y = f(x), if that is cheaper than fd. The
+    // backward pass then reuses the forward result instead of recomputing f.
+    pub fd_out: Option<fn(FloatPrecision) -> FloatPrecision>,
+    pub linear: bool, // f(x) = x
+}
+
+impl Elementwise {
+    pub const fn new(
+        name: &'static str,
+        f: fn(FloatPrecision) -> FloatPrecision,
+        fd: fn(FloatPrecision) -> FloatPrecision,
+    ) -> Self {
+        Self {
+            name,
+            f,
+            fd,
+            fd_out: None,
+            linear: false,
+        }
+    }
+
+    pub const fn with_fd_out(mut self, fd_out: fn(FloatPrecision) -> FloatPrecision) -> Self {
+        self.fd_out = Some(fd_out);
+        self
+    }
+
+    // Marks f as the identity, see Activation::is_linear
+    pub const fn linear(mut self) -> Self {
+        self.linear = true;
+        self
+    }
+}
+
+impl Activation for Elementwise {
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        mwrap(self.f, net, out);
+    }
+
+    fn pointwise(&self) -> Option<Pointwise> {
+        Some(Box::new(self.f))
+    }
+
+    fn is_linear(&self) -> bool {
+        self.linear
+    }
+
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        match self.fd_out {
+            Some(fd_out) => {
+                for i in 0..delta.data.len() {
+                    delta.data[i] *= fd_out(out.data[i]);
+                }
+            }
+            None => {
+                for i in 0..delta.data.len() {
+                    delta.data[i] *= (self.fd)(net.data[i]);
+                }
+            }
+        }
+    }
 }
 
 pub fn mwrap(f: fn(FloatPrecision) -> FloatPrecision, m: &DMatrix, result: &mut DMatrix) {
@@ -39,24 +205,13 @@ fn relu_derivative(x: FloatPrecision) -> FloatPrecision {
     }
 }
 
-const ALPHA: FloatPrecision = 0.3;
-fn leakyrelu(x: FloatPrecision) -> FloatPrecision {
-    if x >= 0. {
-        x
-    } else {
-        ALPHA * x
-    }
+// relu(x) > 0 exactly when x > 0, so the same test works on the output
+fn relu_derivative_out(y: FloatPrecision) -> FloatPrecision {
+    relu_derivative(y)
 }
 
-fn leakyrelu_derivative(x: FloatPrecision) -> FloatPrecision {
-    if x >= 0. {
-        1.
-    } else {
-        ALPHA
-    }
-}
 /// Sigmoid activation function
-fn sigmoid(x: FloatPrecision) -> FloatPrecision {
+pub fn sigmoid(x: FloatPrecision) -> FloatPrecision {
     1. / (1. + (-x).exp())
 }
 
@@ -66,20 +221,588 @@ fn sigmoid_derivative(x: FloatPrecision) -> FloatPrecision {
     s * (1.0 - s)
 }
 
-pub const SIGMOID: Activation = Activation {
-    f: sigmoid,
-    fd: sigmoid_derivative,
-};
-pub const RELU: Activation = Activation {
-    f: relu,
-    fd: relu_derivative,
-};
-pub const LEAKYRELU: Activation = Activation {
-    f: leakyrelu,
-    fd: leakyrelu_derivative,
-};
-
-pub const LINEAR: Activation = Activation {
-    f: linear,
-    fd: linear_derivative,
-};
\ No newline at end of file
+/// Derivative of the sigmoid in terms of its output s = sigmoid(x)
+fn sigmoid_derivative_out(s: FloatPrecision) -> FloatPrecision {
+    s * (1.0 - s)
+}
+
+fn tanh(x: FloatPrecision) -> FloatPrecision {
+    x.tanh()
+}
+
+fn tanh_derivative(x: FloatPrecision) -> FloatPrecision {
+    let t = x.tanh();
+    1. - t * t
+}
+
+fn tanh_derivative_out(t: FloatPrecision) -> FloatPrecision {
+    1. - t * t
+}
+
+// Constants from Klambauer et al., "Self-Normalizing Neural Networks"
+pub const SELU_LAMBDA: FloatPrecision = 1.0507009873554805;
+pub const SELU_ALPHA: FloatPrecision = 1.6732632423543772;
+fn selu(x: FloatPrecision) -> FloatPrecision {
+    if x > 0. {
+        SELU_LAMBDA * x
+    } else {
+        SELU_LAMBDA * SELU_ALPHA * x.exp_m1()
+    }
+}
+
+fn selu_derivative(x: FloatPrecision) -> FloatPrecision {
+    if x > 0. {
+        SELU_LAMBDA
+    } else {
+        SELU_LAMBDA * SELU_ALPHA * x.exp()
+    }
+}
+
+/// GELU using the tanh approximation, since std has no erf
+const GELU_C: FloatPrecision = 0.7978845608028654; // sqrt(2/pi)
+fn gelu(x: FloatPrecision) -> FloatPrecision {
+    let u = GELU_C * (x + 0.044715 * x * x * x);
+    0.5 * x * (1. + u.tanh())
+}
+
+fn gelu_derivative(x: FloatPrecision) -> FloatPrecision {
+    let u = GELU_C * (x + 0.044715 * x * x * x);
+    let du = GELU_C * (1. + 3. * 0.044715 * x * x);
+    let t = u.tanh();
+    0.5 * (1. + t) + 0.5 * x * (1. - t * t) * du
+}
+
+/// ln(1 + e^x), written so that it neither overflows for large x nor loses precision for small x
+fn softplus(x: FloatPrecision) -> FloatPrecision {
+    if x > 0. {
+        x + (-x).exp().ln_1p()
+    } else {
+        x.exp().ln_1p()
+    }
+}
+
+fn softplus_derivative(x: FloatPrecision) -> FloatPrecision {
+    sigmoid(x)
+}
+
+fn mish(x: FloatPrecision) -> FloatPrecision {
+    x * softplus(x).tanh()
+}
+
+fn mish_derivative(x: FloatPrecision) -> FloatPrecision {
+    let t = softplus(x).tanh();
+    t + x * (1. - t * t) * sigmoid(x)
+}
+
+/// Piecewise linear approximation of the sigmoid, relu6(x + 3) / 6
+fn hardsigmoid(x: FloatPrecision) -> FloatPrecision {
+    if x <= -3. {
+        0.
+    } else if x >= 3. {
+        1.
+    } else {
+        x / 6. + 0.5
+    }
+}
+
+fn hardsigmoid_derivative(x: FloatPrecision) -> FloatPrecision {
+    if x > -3. && x < 3. {
+        1. / 6.
+    } else {
+        0.
+    }
+}
+
+pub const SIGMOID: Elementwise = Elementwise::new("sigmoid", sigmoid, sigmoid_derivative).with_fd_out(sigmoid_derivative_out);
+pub const RELU: Elementwise = Elementwise::new("relu", relu, relu_derivative).with_fd_out(relu_derivative_out);
+pub const LINEAR: Elementwise = Elementwise::new("linear", linear, linear_derivative).with_fd_out(linear_derivative).linear();
+pub const TANH: Elementwise = Elementwise::new("tanh", tanh, tanh_derivative).with_fd_out(tanh_derivative_out);
+pub const SELU: Elementwise = Elementwise::new("selu", selu, selu_derivative);
+pub const GELU: Elementwise = Elementwise::new("gelu", gelu, gelu_derivative);
+pub const SOFTPLUS: Elementwise = Elementwise::new("softplus", softplus, softplus_derivative);
+pub const MISH: Elementwise = Elementwise::new("mish", mish, mish_derivative);
+pub const HARDSIGMOID: Elementwise = Elementwise::new("hard_sigmoid", hardsigmoid, hardsigmoid_derivative);
+
+// Numerically stable softmax, shifts every column by its maximum before exponentiating.
+#[derive(Clone)]
+pub struct Softmax;
+
+impl Activation for Softmax {
+    fn name(&self) -> String {
+        "softmax".to_string()
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        let (n, m) = net.shape;
+        for j in 0..m {
+            let mut max = net.data[j];
+            for i in 1..n {
+                max = max.max(net.data[i * m + j]);
+            }
+            let mut sum = 0.;
+            for i in 0..n {
+                let e = (net.data[i * m + j] - max).exp();
+                out.data[i * m + j] = e;
+                sum += e;
+            }
+            for i in 0..n {
+                out.data[i * m + j] /= sum;
+            }
+        }
+    }
+
+    // J = diag(s) - s sT, so JT delta = s * (delta - <s, delta>)
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        let (n, m) = out.shape;
+        for j in 0..m {
+            let mut dot = 0.;
+            for i in 0..n {
+                dot += out.data[i * m + j] * delta.data[i * m + j];
+            }
+            for i in 0..n {
+                let index = i * m + j;
+                delta.data[index] = out.data[index] * (delta.data[index] - dot);
+            }
+        }
+    }
+}
+
+// log(softmax(x)) computed as x - max - log(sum(exp(x - max)))
+#[derive(Clone)]
+pub struct LogSoftmax;
+
+impl Activation for LogSoftmax {
+    fn name(&self) -> String {
+        "log_softmax".to_string()
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        let (n, m) = net.shape;
+        for j in 0..m {
+            let mut max = net.data[j];
+            for i in 1..n {
+                max = max.max(net.data[i * m + j]);
+            }
+            let mut sum = 0.;
+            for i in 0..n {
+                sum += (net.data[i * m + j] - max).exp();
+            }
+            let lse = max + sum.ln();
+            for i in 0..n {
+                out.data[i * m + j] = net.data[i * m + j] - lse;
+            }
+        }
+    }
+
+    // J = I - 1 sT, so JT delta = delta - s * sum(delta)
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        let (n, m) = out.shape;
+        for j in 0..m {
+            let mut sum = 0.;
+            for i in 0..n {
+                sum += delta.data[i * m + j];
+            }
+            for i in 0..n {
+                let index = i * m + j;
+                delta.data[index] -= out.data[index].exp() * sum;
+            }
+        }
+    }
+}
+
+// Sparsemax (Martins & Astudillo, 2016): the euclidean projection of a
+// column onto the probability simplex, which may put exact zeros on classes.
+#[derive(Clone)]
+pub struct Sparsemax;
+
+impl Activation for Sparsemax {
+    fn name(&self) -> String {
+        "sparsemax".to_string()
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        let (n, m) = net.shape;
+        let mut z: Vec<FloatPrecision> = vec![0.; n];
+        for j in 0..m {
+            for i in 0..n {
+                z[i] = net.data[i * m + j];
+            }
+            z.sort_by(|a, b| b.total_cmp(a));
+
+            // Find the size k of the support and the threshold tau
+            let mut cumsum = 0.;
+            let mut tau = 0.;
+            for k in 0..n {
+                cumsum += z[k];
+                if 1. + (k + 1) as FloatPrecision * z[k] > cumsum {
+                    tau = (cumsum - 1.) / (k + 1) as FloatPrecision;
+                }
+            }
+            for i in 0..n {
+                out.data[i * m + j] = (net.data[i * m + j] - tau).max(0.);
+            }
+        }
+    }
+
+    // On the support S, J = I - 1 1T / |S|, elsewhere the gradient is zero
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        let (n, m) = out.shape;
+        for j in 0..m {
+            let mut sum = 0.;
+            let mut support = 0;
+            for i in 0..n {
+                if out.data[i * m + j] > 0. {
+                    sum += delta.data[i * m + j];
+                    support += 1;
+                }
+            }
+            let mean = sum / support as FloatPrecision;
+            for i in 0..n {
+                let index = i * m + j;
+                if out.data[index] > 0. {
+                    delta.data[index] -= mean;
+                } else {
+                    delta.data[index] = 0.;
+                }
+            }
+        }
+    }
+}
+
+pub const SOFTMAX: Softmax = Softmax;
+pub const LOGSOFTMAX: LogSoftmax = LogSoftmax;
+pub const SPARSEMAX: Sparsemax = Sparsemax;
+
+#[derive(Clone)]
+pub struct LeakyRelu {
+    pub alpha: FloatPrecision,
+}
+
+impl LeakyRelu {
+    pub fn new(alpha: FloatPrecision) -> Self {
+        Self { alpha }
+    }
+}
+
+impl Activation for LeakyRelu {
+    fn name(&self) -> String {
+        format!("leaky_relu({})", self.alpha)
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        for i in 0..net.data.len() {
+            let x = net.data[i];
+            out.data[i] = if x >= 0. { x } else { self.alpha * x };
+        }
+    }
+
+    fn pointwise(&self) -> Option<Pointwise> {
+        let alpha = self.alpha;
+        Some(Box::new(move |x| if x >= 0. { x } else { alpha * x }))
+    }
+
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        for i in 0..delta.data.len() {
+            if net.data[i] < 0. {
+                delta.data[i] *= self.alpha;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Elu {
+    pub alpha: FloatPrecision,
+}
+
+impl Elu {
+    pub fn new(alpha: FloatPrecision) -> Self {
+        Self { alpha }
+    }
+}
+
+impl Activation for Elu {
+    fn name(&self) -> String {
+        format!("elu({})", self.alpha)
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        for i in 0..net.data.len() {
+            let x = net.data[i];
+            out.data[i] = if x > 0. { x } else { self.alpha * x.exp_m1() };
+        }
+    }
+
+    fn pointwise(&self) -> Option<Pointwise> {
+        let alpha = self.alpha;
+        Some(Box::new(move |x| if x > 0. { x } else { alpha * x.exp_m1() }))
+    }
+
+    // For x <= 0, alpha * e^x = out + alpha, which saves the exp
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        for i in 0..delta.data.len() {
+            if net.data[i] <= 0. {
+                delta.data[i] *= out.data[i] + self.alpha;
+            }
+        }
+    }
+}
+
+// x * sigmoid(beta * x), with beta = 1 this is SiLU
+#[derive(Clone)]
+pub struct Swish {
+    pub beta: FloatPrecision,
+}
+
+impl Swish {
+    pub fn new(beta: FloatPrecision) -> Self {
+        Self { beta }
+    }
+}
+
+impl Activation for Swish {
+    fn name(&self) -> String {
+        format!("swish({})", self.beta)
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        for i in 0..net.data.len() {
+            let x = net.data[i];
+            out.data[i] = x * sigmoid(self.beta * x);
+        }
+    }
+
+    fn pointwise(&self) -> Option<Pointwise> {
+        let beta = self.beta;
+        Some(Box::new(move |x| x * sigmoid(beta * x)))
+    }
+
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        for i in 0..delta.data.len() {
+            let x = net.data[i];
+            let s = sigmoid(self.beta * x);
+            delta.data[i] *= s + self.beta * x * s * (1. - s);
+        }
+    }
+}
+
+// Leaky ReLU with one learned slope per unit (He et al., 2015)
+#[derive(Clone)]
+pub struct PRelu {
+    pub alpha: DMatrix,
+    pub dalpha: DMatrix,
+}
+
+impl PRelu {
+    pub fn new(units: usize) -> Self {
+        Self {
+            alpha: DMatrix::new(vec![0.25; units], (units, 1)),
+            dalpha: DMatrix::new(vec![0.; units], (units, 1)),
+        }
+    }
+}
+
+impl Activation for PRelu {
+    fn name(&self) -> String {
+        format!("prelu({})", self.alpha.data.len())
+    }
+
+    fn boxed(&self) -> Box<dyn Activation> {
+        Box::new(self.clone())
+    }
+
+    fn forward(&self, net: &DMatrix, out: &mut DMatrix) {
+        let (n, m) = net.shape;
+        for i in 0..n {
+            for j in 0..m {
+                let index = i * m + j;
+                let x = net.data[index];
+                out.data[index] = if x >= 0. { x } else { self.alpha.data[i] * x };
+            }
+        }
+    }
+
+    fn backward(&mut self, net: &DMatrix, out: &DMatrix, delta: &mut DMatrix) {
+        let (n, m) = net.shape;
+        for i in 0..n {
+            self.dalpha.data[i] = 0.;
+            for j in 0..m {
+                let index = i * m + j;
+                let x = net.data[index];
+                if x < 0. {
+                    self.dalpha.data[i] += delta.data[index] * x; // -dE/dalpha, since delta holds -dE/dout
+                    delta.data[index] *= self.alpha.data[i];
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, rate: FloatPrecision) {
+        for i in 0..self.alpha.data.len() {
+            self.alpha.data[i] += rate * self.dalpha.data[i];
+        }
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.alpha)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        read_matrix(r, &mut self.alpha)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![Parameter::new("alpha", &mut self.alpha, &mut self.dalpha)]
+    }
+}
+
+pub const LEAKYRELU: LeakyRelu = LeakyRelu { alpha: 0.3 };
+pub const ELU: Elu = Elu { alpha: 1. };
+pub const SWISH: Swish = Swish { beta: 1. };
+pub const SILU: Swish = SWISH;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{assert_passed, check_activation, failures, TOLERANCE};
+
+    // Both sides of 0, far out and 1e-3 around the kinks at 0 and +-3,
+    // two samples per column
+    fn net() -> DMatrix {
+        let values = vec![-4., -3.001, -2.999, -1.3, -0.5, -1e-3, 1e-3, 0.4, 1.7, 2.999, 3.001, 4.5];
+        DMatrix::new(values, (6, 2))
+    }
+
+    // Close values, so that sparsemax keeps two or three of every column and
+    // drops the others by far more than the step of the differences
+    fn scores() -> DMatrix {
+        DMatrix::new(vec![0.3, -0.4, 1.2, 0.1, 0.2, 0.9, -0.2, 0.5, -1., 0.25, -0.3, 1.1], (4, 3))
+    }
+
+    fn check_at<A: Activation>(mut activation: A, net: &DMatrix) {
+        let checks = check_activation(&mut activation, net);
+        let failed = failures(&checks, TOLERANCE);
+        assert!(failed.is_empty(), "{}: {:?}", activation.name(), failed);
+    }
+
+    fn check<A: Activation>(activation: A) {
+        check_at(activation, &net());
+    }
+
+    #[test]
+    fn linear() {
+        assert!(LINEAR.is_linear());
+        assert!(!RELU.is_linear() && !LeakyRelu::new(1.).is_linear());
+        assert!(LINEAR.boxed().is_linear());
+    }
+
+    #[test]
+    fn tanh() {
+        check(TANH);
+    }
+
+    #[test]
+    fn selu() {
+        check(SELU);
+    }
+
+    #[test]
+    fn gelu() {
+        check(GELU);
+    }
+
+    #[test]
+    fn softplus() {
+        check(SOFTPLUS);
+    }
+
+    #[test]
+    fn mish() {
+        check(MISH);
+    }
+
+    #[test]
+    fn hardsigmoid() {
+        check(HARDSIGMOID);
+    }
+
+    #[test]
+    fn elu() {
+        check(ELU);
+        check(Elu::new(0.5));
+    }
+
+    #[test]
+    fn softmax() {
+        check_at(SOFTMAX, &scores());
+        check_at(SOFTMAX, &net());
+    }
+
+    #[test]
+    fn log_softmax() {
+        check_at(LOGSOFTMAX, &scores());
+        check_at(LOGSOFTMAX, &net());
+    }
+
+    #[test]
+    fn sparsemax() {
+        check_at(SPARSEMAX, &scores());
+        let mut out = DMatrix::zeros((4, 3));
+        SPARSEMAX.forward(&scores(), &mut out);
+        for j in 0..3 {
+            let column: Vec<FloatPrecision> = (0..4).map(|i| out.data[i * 3 + j]).collect();
+            assert!((column.iter().sum::<FloatPrecision>() - 1.).abs() < 1e-12);
+            assert!(column.contains(&0.));
+        }
+    }
+
+    // A NaN only spoils its own column instead of panicking in the sort
+    #[test]
+    fn sparsemax_nan() {
+        let mut net = scores();
+        net.data[0] = FloatPrecision::NAN;
+        let mut out = DMatrix::zeros((4, 3));
+        SPARSEMAX.forward(&net, &mut out);
+        let second: FloatPrecision = (0..4).map(|i| out.data[i * 3 + 1]).sum();
+        assert!((second - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn prelu() {
+        let mut prelu = PRelu::new(6);
+        prelu.alpha.data = vec![0.1, 0.25, -0.2, 0.5, 1.5, 0.3];
+        let checks = check_activation(&mut prelu, &net());
+        assert!(checks.iter().any(|c| c.name == "alpha"));
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn swish() {
+        check(SWISH);
+        check(Swish::new(2.));
+    }
+}
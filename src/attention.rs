@@ -0,0 +1,716 @@
+use std::io;
+use std::io::{Read, Write};
+
+use rand::Rng;
+
+use crate::activations::LINEAR;
+use crate::activations::RELU;
+use crate::constants::FloatPrecision;
+use crate::inference;
+use crate::inference::Inference;
+use crate::layers::Layer;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::math::addm;
+use crate::math::addm_assign;
+use crate::math::mtmulm;
+use crate::math::mulm;
+use crate::math::rowsum;
+use crate::math::saddm_assign;
+use crate::math::smmulmt;
+use crate::math::DMatrix;
+use crate::normalization::LayerNorm;
+use crate::registry::Registry;
+use crate::serialize::{read_matrix, write_matrix};
+
+// Sequences are stored time major like in recurrent.rs, feature f of step t
+// is row t * dim + f. Layers that work on every token on its own (the
+// projections, the feed forward network and layer norm) instead see them as
+// tokens, one column per step and sample.
+
+// (steps * dim, batch) -> (dim, steps * batch), column t * batch + b is step t of sample b
+pub fn to_tokens(seq: &DMatrix, dim: usize, tokens: &mut DMatrix) {
+    let batch = seq.shape.1;
+    let steps = seq.shape.0 / dim;
+    let n = steps * batch;
+    for t in 0..steps {
+        for f in 0..dim {
+            for b in 0..batch {
+                tokens.data[f * n + t * batch + b] = seq.data[(t * dim + f) * batch + b];
+            }
+        }
+    }
+}
+
+// Inverse of to_tokens
+pub fn from_tokens(tokens: &DMatrix, dim: usize, seq: &mut DMatrix) {
+    let batch = seq.shape.1;
+    let steps = seq.shape.0 / dim;
+    let n = steps * batch;
+    for t in 0..steps {
+        for f in 0..dim {
+            for b in 0..batch {
+                seq.data[(t * dim + f) * batch + b] = tokens.data[f * n + t * batch + b];
+            }
+        }
+    }
+}
+
+// Copies the rows offset.. of the tokens of sample b into m, one column per step
+pub fn gather(tokens: &DMatrix, offset: usize, b: usize, m: &mut DMatrix) {
+    let n = tokens.shape.1;
+    let (size, steps) = m.shape;
+    let batch = n / steps;
+    for r in 0..size {
+        for t in 0..steps {
+            m.data[r * steps + t] = tokens.data[(offset + r) * n + t * batch + b];
+        }
+    }
+}
+
+pub fn scatter(m: &DMatrix, offset: usize, b: usize, tokens: &mut DMatrix) {
+    let n = tokens.shape.1;
+    let (size, steps) = m.shape;
+    let batch = n / steps;
+    for r in 0..size {
+        for t in 0..steps {
+            tokens.data[(offset + r) * n + t * batch + b] = m.data[r * steps + t];
+        }
+    }
+}
+
+// softmax(qT k / sqrt(dk)) applied to v, for the queries, keys and values of
+// one sequence with one column per step. Row i of weights (queries, keys) is
+// how much query i attends to every key, with a causal mask it only sees keys
+// up to its own step.
+pub fn scaled_dot_product(q: &DMatrix, k: &DMatrix, v: &DMatrix, causal: bool, weights: &mut DMatrix, out: &mut DMatrix) {
+    let scale = 1. / (q.shape.0 as FloatPrecision).sqrt();
+    mtmulm(q, k, weights);
+    let (n, m) = weights.shape;
+    for i in 0..n {
+        let row = &mut weights.data[i * m..(i + 1) * m];
+        let visible = if causal { (i + 1).min(m) } else { m };
+        let max = row[..visible].iter().fold(FloatPrecision::NEG_INFINITY, |a, &b| a.max(b));
+        let mut sum = 0.;
+        for x in row[..visible].iter_mut() {
+            *x = ((*x - max) * scale).exp();
+            sum += *x;
+        }
+        for x in row[..visible].iter_mut() {
+            *x /= sum;
+        }
+        for x in row[visible..].iter_mut() {
+            *x = 0.;
+        }
+    }
+    smmulmt(1., v, weights, out); // out[:, i] = sum_j weights[i, j] * v[:, j]
+}
+
+// Deltas of q, k and v from the delta of the output of scaled_dot_product
+#[allow(clippy::too_many_arguments)]
+pub fn scaled_dot_product_backward(
+    q: &DMatrix,
+    k: &DMatrix,
+    v: &DMatrix,
+    weights: &DMatrix,
+    delta: &DMatrix,
+    dq: &mut DMatrix,
+    dk: &mut DMatrix,
+    dv: &mut DMatrix,
+) {
+    let scale = 1. / (q.shape.0 as FloatPrecision).sqrt();
+    mulm(delta, weights, dv);
+    let mut dweights = DMatrix::zeros(weights.shape);
+    mtmulm(delta, v, &mut dweights);
+
+    // Through the softmax of every row, masked weights are 0 and stay so
+    let (n, m) = weights.shape;
+    for i in 0..n {
+        let row = i * m;
+        let mut dot = 0.;
+        for j in 0..m {
+            dot += weights.data[row + j] * dweights.data[row + j];
+        }
+        for j in 0..m {
+            dweights.data[row + j] = weights.data[row + j] * (dweights.data[row + j] - dot) * scale;
+        }
+    }
+    smmulmt(1., k, &dweights, dq); // dq[:, i] = sum_j ds[i, j] * k[:, j]
+    mulm(q, &dweights, dk); // dk[:, j] = sum_i ds[i, j] * q[:, i]
+}
+
+// Multi-head self-attention (Vaswani et al., 2017) over sequences of dim
+// features. Every head attends with its own slice of dim / heads features of
+// the projected queries, keys and values, the heads are then concatenated
+// and projected back.
+pub struct MultiHeadAttention {
+    dim: usize,
+    heads: usize,
+    causal: bool,
+    pub query: Layer,
+    pub key: Layer,
+    pub value: Layer,
+    pub output: Layer,
+    tokens: DMatrix,
+    weights: Vec<DMatrix>, // attention weights, b * heads + h for sample b and head h
+    context: DMatrix,      // outputs of all heads stacked, (dim, steps * batch)
+    dtokens: DMatrix,
+    dq: DMatrix,
+    dk: DMatrix,
+    dv: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl MultiHeadAttention {
+    pub fn new(dim: usize, heads: usize, rate: FloatPrecision) -> Self {
+        if heads == 0 || dim % heads != 0 {
+            panic!("Cannot split {dim} features into {heads} heads.");
+        }
+        Self {
+            dim,
+            heads,
+            causal: false,
+            query: Layer::new(dim, dim, LINEAR, rate),
+            key: Layer::new(dim, dim, LINEAR, rate),
+            value: Layer::new(dim, dim, LINEAR, rate),
+            output: Layer::new(dim, dim, LINEAR, rate),
+            tokens: DMatrix::zeros((0, 0)),
+            weights: Vec::new(),
+            context: DMatrix::zeros((0, 0)),
+            dtokens: DMatrix::zeros((0, 0)),
+            dq: DMatrix::zeros((0, 0)),
+            dk: DMatrix::zeros((0, 0)),
+            dv: DMatrix::zeros((0, 0)),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    // Every step only attends to itself and the steps before it
+    pub fn causal(mut self) -> Self {
+        self.causal = true;
+        self
+    }
+
+    // Attention weights (steps, steps) of the last forward
+    pub fn attention_weights(&self, sample: usize, head: usize) -> &DMatrix {
+        &self.weights[sample * self.heads + head]
+    }
+
+    fn op(&self) -> Option<inference::Attention> {
+        Some(inference::Attention {
+            dim: self.dim,
+            heads: self.heads,
+            causal: self.causal,
+            query: self.query.inference()?,
+            key: self.key.inference()?,
+            value: self.value.inference()?,
+            output: self.output.inference()?,
+        })
+    }
+}
+
+impl Module for MultiHeadAttention {
+    fn forward(&mut self, input: &DMatrix) {
+        let (rows, batch) = input.shape;
+        if rows % self.dim != 0 {
+            panic!("Expected sequences of {} features, got {} rows.", self.dim, rows);
+        }
+        let steps = rows / self.dim;
+        if self.out.shape != input.shape {
+            let tokens = (self.dim, steps * batch);
+            self.tokens = DMatrix::zeros(tokens);
+            self.weights = (0..batch * self.heads).map(|_| DMatrix::zeros((steps, steps))).collect();
+            self.context = DMatrix::zeros(tokens);
+            self.dtokens = DMatrix::zeros(tokens);
+            self.dq = DMatrix::zeros(tokens);
+            self.dk = DMatrix::zeros(tokens);
+            self.dv = DMatrix::zeros(tokens);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        to_tokens(input, self.dim, &mut self.tokens);
+        self.query.forward(&self.tokens);
+        self.key.forward(&self.tokens);
+        self.value.forward(&self.tokens);
+
+        let size = self.dim / self.heads;
+        let mut q = DMatrix::zeros((size, steps));
+        let mut k = DMatrix::zeros((size, steps));
+        let mut v = DMatrix::zeros((size, steps));
+        let mut head = DMatrix::zeros((size, steps));
+        for b in 0..batch {
+            for h in 0..self.heads {
+                gather(&self.query.out, h * size, b, &mut q);
+                gather(&self.key.out, h * size, b, &mut k);
+                gather(&self.value.out, h * size, b, &mut v);
+                scaled_dot_product(&q, &k, &v, self.causal, &mut self.weights[b * self.heads + h], &mut head);
+                scatter(&head, h * size, b, &mut self.context);
+            }
+        }
+
+        self.output.forward(&self.context);
+        from_tokens(&self.output.out, self.dim, &mut self.out);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let (rows, batch) = delta.shape;
+        let steps = rows / self.dim;
+        to_tokens(delta, self.dim, &mut self.dtokens);
+        Module::backward(&mut self.output, &self.context, &self.dtokens);
+
+        let size = self.dim / self.heads;
+        let mut dq = DMatrix::zeros((size, steps));
+        let mut dk = DMatrix::zeros((size, steps));
+        let mut dv = DMatrix::zeros((size, steps));
+        let mut q = DMatrix::zeros((size, steps));
+        let mut k = DMatrix::zeros((size, steps));
+        let mut v = DMatrix::zeros((size, steps));
+        let mut dhead = DMatrix::zeros((size, steps));
+        for b in 0..batch {
+            for h in 0..self.heads {
+                gather(&self.query.out, h * size, b, &mut q);
+                gather(&self.key.out, h * size, b, &mut k);
+                gather(&self.value.out, h * size, b, &mut v);
+                gather(self.output.delta(), h * size, b, &mut dhead);
+                let weights = &self.weights[b * self.heads + h];
+                scaled_dot_product_backward(&q, &k, &v, weights, &dhead, &mut dq, &mut dk, &mut dv);
+                scatter(&dq, h * size, b, &mut self.dq);
+                scatter(&dk, h * size, b, &mut self.dk);
+                scatter(&dv, h * size, b, &mut self.dv);
+            }
+        }
+
+        // The input feeds all three projections
+        Module::backward(&mut self.query, &self.tokens, &self.dq);
+        Module::backward(&mut self.key, &self.tokens, &self.dk);
+        Module::backward(&mut self.value, &self.tokens, &self.dv);
+        addm(self.query.delta(), self.key.delta(), &mut self.dtokens);
+        addm_assign(&mut self.dtokens, self.value.delta());
+        from_tokens(&self.dtokens, self.dim, &mut self.input_delta);
+    }
+
+    fn update(&mut self) {
+        Module::update(&mut self.query);
+        Module::update(&mut self.key);
+        Module::update(&mut self.value);
+        Module::update(&mut self.output);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.query.set_trainable(trainable);
+        self.key.set_trainable(trainable);
+        self.value.set_trainable(trainable);
+        self.output.set_trainable(trainable);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let n: usize = input_shape.iter().product();
+        if n % self.dim != 0 {
+            panic!("Expected sequences of {} features, got shape {:?}.", self.dim, input_shape);
+        }
+        vec![n / self.dim, self.dim]
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.query.save(w)?;
+        self.key.save(w)?;
+        self.value.save(w)?;
+        self.output.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.query.load(r, registry)?;
+        self.key.load(r, registry)?;
+        self.value.load(r, registry)?;
+        self.output.load(r, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = Vec::new();
+        params.extend(self.query.parameters().into_iter().map(|p| p.prefixed("query")));
+        params.extend(self.key.parameters().into_iter().map(|p| p.prefixed("key")));
+        params.extend(self.value.parameters().into_iter().map(|p| p.prefixed("value")));
+        params.extend(self.output.parameters().into_iter().map(|p| p.prefixed("output")));
+        params
+    }
+
+    fn penalty(&self) -> FloatPrecision {
+        self.query.penalty() + self.key.penalty() + self.value.penalty() + self.output.penalty()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(self.op()?))
+    }
+}
+
+// Adds sin and cos of the step at geometrically decreasing frequencies, so
+// that attention can tell the steps apart. Works for sequences of any length.
+pub fn sinusoid(t: usize, f: usize, dim: usize) -> FloatPrecision {
+    let frequency = (10000. as FloatPrecision).powf(-((f - f % 2) as FloatPrecision) / dim as FloatPrecision);
+    let angle = t as FloatPrecision * frequency;
+    if f % 2 == 0 {
+        angle.sin()
+    } else {
+        angle.cos()
+    }
+}
+
+pub struct SinusoidalEncoding {
+    dim: usize,
+    encoding: DMatrix, // (steps * dim, 1)
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl SinusoidalEncoding {
+    pub fn new(dim: usize) -> Self {
+        Self {
+            dim,
+            encoding: DMatrix::zeros((0, 1)),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for SinusoidalEncoding {
+    fn forward(&mut self, input: &DMatrix) {
+        let (rows, batch) = input.shape;
+        if rows % self.dim != 0 {
+            panic!("Expected sequences of {} features, got {} rows.", self.dim, rows);
+        }
+        if self.encoding.shape.0 != rows {
+            self.encoding = DMatrix::zeros((rows, 1));
+            for t in 0..rows / self.dim {
+                for f in 0..self.dim {
+                    self.encoding.data[t * self.dim + f] = sinusoid(t, f, self.dim);
+                }
+            }
+        }
+        if self.out.shape != input.shape {
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for i in 0..rows {
+            for j in 0..batch {
+                self.out.data[i * batch + j] = input.data[i * batch + j] + self.encoding.data[i];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        self.input_delta.data.copy_from_slice(&delta.data);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Sinusoidal { dim: self.dim }))
+    }
+}
+
+// Adds a learned vector for every step, so sequences have a fixed length
+pub struct LearnedEncoding {
+    pub encoding: DMatrix, // (steps * dim, 1)
+    pub dencoding: DMatrix,
+    trainable: bool,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl LearnedEncoding {
+    pub fn new(steps: usize, dim: usize, rate: FloatPrecision) -> Self {
+        let mut rng = rand::thread_rng();
+        let data: Vec<FloatPrecision> = (0..steps * dim).map(|_| rng.gen_range(-0.05..0.05)).collect();
+        Self {
+            encoding: DMatrix::new(data, (steps * dim, 1)),
+            dencoding: DMatrix::zeros((steps * dim, 1)),
+            trainable: true,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+            rate,
+        }
+    }
+}
+
+impl Module for LearnedEncoding {
+    fn forward(&mut self, input: &DMatrix) {
+        let (rows, batch) = input.shape;
+        if rows != self.encoding.shape.0 {
+            panic!("LearnedEncoding expects {} rows, got {}.", self.encoding.shape.0, rows);
+        }
+        if self.out.shape != input.shape {
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for i in 0..rows {
+            for j in 0..batch {
+                self.out.data[i * batch + j] = input.data[i * batch + j] + self.encoding.data[i];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        if self.trainable {
+            rowsum(delta, &mut self.dencoding);
+        }
+        self.input_delta.data.copy_from_slice(&delta.data);
+    }
+
+    fn update(&mut self) {
+        if self.trainable {
+            saddm_assign(self.rate, &mut self.encoding, &self.dencoding);
+        }
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape.iter().product::<usize>() != self.encoding.shape.0 {
+            panic!("LearnedEncoding expects {} inputs, got shape {:?}.", self.encoding.shape.0, input_shape);
+        }
+        input_shape.to_vec()
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.encoding)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.encoding)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![Parameter::new("encoding", &mut self.encoding, &mut self.dencoding)]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Affine {
+            scale: DMatrix::new(vec![1.; self.encoding.shape.0], self.encoding.shape),
+            shift: self.encoding.clone(),
+        }))
+    }
+}
+
+// Post norm transformer encoder block:
+// x = LayerNorm(x + Attention(x)), out = LayerNorm(x + FFN(x))
+// where the feed forward network is applied to every step on its own.
+pub struct TransformerEncoder {
+    dim: usize,
+    pub attention: MultiHeadAttention,
+    pub norm1: LayerNorm,
+    pub hidden: Layer,
+    pub projection: Layer,
+    pub norm2: LayerNorm,
+    residual1: DMatrix, // tokens of x + Attention(x)
+    residual2: DMatrix,
+    dtokens: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl TransformerEncoder {
+    pub fn new(dim: usize, heads: usize, hidden: usize, rate: FloatPrecision) -> Self {
+        Self {
+            dim,
+            attention: MultiHeadAttention::new(dim, heads, rate),
+            norm1: LayerNorm::new(dim, rate),
+            hidden: Layer::new(dim, hidden, RELU, rate),
+            projection: Layer::new(hidden, dim, LINEAR, rate),
+            norm2: LayerNorm::new(dim, rate),
+            residual1: DMatrix::zeros((0, 0)),
+            residual2: DMatrix::zeros((0, 0)),
+            dtokens: DMatrix::zeros((0, 0)),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    pub fn causal(mut self) -> Self {
+        self.attention = self.attention.causal();
+        self
+    }
+}
+
+impl Module for TransformerEncoder {
+    fn forward(&mut self, input: &DMatrix) {
+        self.attention.forward(input);
+        let (rows, batch) = input.shape;
+        if self.out.shape != input.shape {
+            let tokens = (self.dim, rows / self.dim * batch);
+            self.residual1 = DMatrix::zeros(tokens);
+            self.residual2 = DMatrix::zeros(tokens);
+            self.dtokens = DMatrix::zeros(tokens);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        to_tokens(input, self.dim, &mut self.residual1);
+        to_tokens(&self.attention.out, self.dim, &mut self.dtokens);
+        addm_assign(&mut self.residual1, &self.dtokens);
+        self.norm1.forward(&self.residual1);
+
+        self.hidden.forward(&self.norm1.out);
+        self.projection.forward(&self.hidden.out);
+        addm(&self.norm1.out, &self.projection.out, &mut self.residual2);
+        self.norm2.forward(&self.residual2);
+        from_tokens(&self.norm2.out, self.dim, &mut self.out);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        to_tokens(delta, self.dim, &mut self.dtokens);
+        self.norm2.backward(&self.residual2, &self.dtokens);
+        Module::backward(&mut self.projection, &self.hidden.out, self.norm2.delta());
+        Module::backward(&mut self.hidden, &self.norm1.out, self.projection.delta());
+        addm(self.norm2.delta(), self.hidden.delta(), &mut self.dtokens);
+        self.norm1.backward(&self.residual1, &self.dtokens);
+
+        from_tokens(self.norm1.delta(), self.dim, &mut self.input_delta);
+        self.attention.backward(input, &self.input_delta);
+        addm_assign(&mut self.input_delta, self.attention.delta());
+    }
+
+    fn update(&mut self) {
+        self.attention.update();
+        self.norm1.update();
+        Module::update(&mut self.hidden);
+        Module::update(&mut self.projection);
+        self.norm2.update();
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.attention.set_trainable(trainable);
+        self.norm1.set_trainable(trainable);
+        self.hidden.set_trainable(trainable);
+        self.projection.set_trainable(trainable);
+        self.norm2.set_trainable(trainable);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.attention.output_shape(input_shape)
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.attention.save(w)?;
+        self.norm1.save(w)?;
+        self.hidden.save(w)?;
+        self.projection.save(w)?;
+        self.norm2.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.attention.load(r, registry)?;
+        self.norm1.load(r, registry)?;
+        self.hidden.load(r, registry)?;
+        self.projection.load(r, registry)?;
+        self.norm2.load(r, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = Vec::new();
+        params.extend(self.attention.parameters().into_iter().map(|p| p.prefixed("attention")));
+        params.extend(self.norm1.parameters().into_iter().map(|p| p.prefixed("norm1")));
+        params.extend(self.hidden.parameters().into_iter().map(|p| p.prefixed("hidden")));
+        params.extend(self.projection.parameters().into_iter().map(|p| p.prefixed("projection")));
+        params.extend(self.norm2.parameters().into_iter().map(|p| p.prefixed("norm2")));
+        params
+    }
+
+    fn penalty(&self) -> FloatPrecision {
+        self.attention.penalty() + self.hidden.penalty() + self.projection.penalty()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Encoder {
+            dim: self.dim,
+            attention: self.attention.op()?,
+            norm1: self.norm1.inference()?,
+            hidden: self.hidden.inference()?,
+            projection: self.projection.inference()?,
+            norm2: self.norm2.inference()?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{assert_passed, check_module, matrix};
+    use crate::losses::Mse;
+    use crate::models::NeuralNetwork;
+
+    // 3 steps of 4 features for 2 sequences
+    fn sequences(seed: usize) -> DMatrix {
+        matrix((12, 2), seed)
+    }
+
+    fn check(module: &mut dyn Module) {
+        let checks = check_module(module, &Mse, &sequences(1), &sequences(2));
+        for name in ["input", "query.weights", "key.weights", "value.weights", "output.weights"] {
+            assert!(checks.iter().any(|c| c.name.ends_with(name)), "{} is not checked", name);
+        }
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn attention() {
+        check(&mut MultiHeadAttention::new(4, 2, 0.1));
+        check(&mut MultiHeadAttention::new(4, 2, 0.1).causal());
+    }
+
+    #[test]
+    fn transformer_encoder() {
+        check(&mut TransformerEncoder::new(4, 2, 6, 0.1));
+        check(&mut TransformerEncoder::new(4, 1, 6, 0.1).causal());
+    }
+
+    #[test]
+    fn inference() {
+        let mut nn = NeuralNetwork::new(12);
+        nn.add(LearnedEncoding::new(3, 4, 0.1));
+        nn.add(SinusoidalEncoding::new(4));
+        nn.add(TransformerEncoder::new(4, 2, 6, 0.1).causal());
+        nn.add(MultiHeadAttention::new(4, 2, 0.1));
+        let mut out = DMatrix::zeros((0, 0));
+        nn.inference().predict(&sequences(1), &mut out);
+        for (a, b) in out.data.iter().zip(nn.predict(&sequences(1)).data.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+}
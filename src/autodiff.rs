@@ -0,0 +1,544 @@
+use std::io;
+use std::io::{Read, Write};
+
+use crate::activations::Activation;
+use crate::constants::FloatPrecision;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::math::addm_assign;
+use crate::math::mmulmt_add;
+use crate::math::mtmulm;
+use crate::math::mulm;
+use crate::math::saddm_assign;
+use crate::math::DMatrix;
+use crate::registry::Registry;
+use crate::serialize::{read_matrix, write_matrix};
+
+// Reverse mode automatic differentiation. Every operation on a Tape appends
+// its result, backward then walks the tape in reverse and applies the chain
+// rule, so only the forward computation has to be written.
+
+// A value recorded on a Tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Var(usize);
+
+enum Op {
+    Leaf,
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    MatMul(usize, usize),
+    Scale(usize, FloatPrecision),
+    // Elementwise function, the derivative is given the input and the output
+    Map(usize, fn(FloatPrecision, FloatPrecision) -> FloatPrecision),
+    // The input and the vars of the learned parameters of the activation
+    Activation(usize, Box<dyn Activation>, Vec<usize>),
+    Sum(usize),
+    Transpose(usize),
+}
+
+struct Entry {
+    op: Op,
+    value: DMatrix,
+    grad: DMatrix,
+}
+
+pub struct Tape {
+    entries: Vec<Entry>,
+}
+
+fn transpose(m: &DMatrix) -> DMatrix {
+    let (n, k) = m.shape;
+    let mut t = DMatrix::zeros((k, n));
+    for i in 0..n {
+        for j in 0..k {
+            t.data[j * n + i] = m.data[i * k + j];
+        }
+    }
+    t
+}
+
+fn map(m: &DMatrix, f: fn(FloatPrecision) -> FloatPrecision) -> DMatrix {
+    DMatrix::new(m.data.iter().map(|&x| f(x)).collect(), m.shape)
+}
+
+// lhs + rhs or lhs - rhs, where rhs may also be a column added to every column of lhs
+fn broadcast(lhs: &DMatrix, rhs: &DMatrix, s: FloatPrecision) -> DMatrix {
+    let (n, m) = lhs.shape;
+    if rhs.shape != lhs.shape && rhs.shape != (n, 1) {
+        panic!("Cannot combine matrices of shapes {:?} and {:?}.", lhs.shape, rhs.shape);
+    }
+    let column = rhs.shape != lhs.shape;
+    let mut result = lhs.clone();
+    for i in 0..n {
+        for j in 0..m {
+            result.data[i * m + j] += s * rhs.data[if column { i } else { i * m + j }];
+        }
+    }
+    result
+}
+
+// Adds the gradient of the result of broadcast to the one of rhs
+fn unbroadcast(grad: &DMatrix, s: FloatPrecision, rhs: &mut DMatrix) {
+    let (n, m) = grad.shape;
+    let column = rhs.shape != grad.shape;
+    for i in 0..n {
+        for j in 0..m {
+            rhs.data[if column { i } else { i * m + j }] += s * grad.data[i * m + j];
+        }
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    fn push(&mut self, op: Op, value: DMatrix) -> Var {
+        let grad = DMatrix::zeros((0, 0));
+        self.entries.push(Entry { op, value, grad });
+        Var(self.entries.len() - 1)
+    }
+
+    // Records an input or parameter
+    pub fn var(&mut self, value: &DMatrix) -> Var {
+        self.push(Op::Leaf, value.clone())
+    }
+
+    pub fn value(&self, v: Var) -> &DMatrix {
+        &self.entries[v.0].value
+    }
+
+    // dE/dv of the last backward. Note the sign, layer deltas are -dE.
+    pub fn grad(&self, v: Var) -> &DMatrix {
+        &self.entries[v.0].grad
+    }
+
+    // Forgets all recorded values, e.g. before the next batch
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let value = broadcast(self.value(a), self.value(b), 1.);
+        self.push(Op::Add(a.0, b.0), value)
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var {
+        let value = broadcast(self.value(a), self.value(b), -1.);
+        self.push(Op::Sub(a.0, b.0), value)
+    }
+
+    // Elementwise product
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let (x, y) = (self.value(a), self.value(b));
+        if x.shape != y.shape {
+            panic!("Cannot multiply matrices of shapes {:?} and {:?} elementwise.", x.shape, y.shape);
+        }
+        let value = DMatrix::new(x.data.iter().zip(y.data.iter()).map(|(p, q)| p * q).collect(), x.shape);
+        self.push(Op::Mul(a.0, b.0), value)
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let (x, y) = (self.value(a), self.value(b));
+        if x.shape.1 != y.shape.0 {
+            panic!("Cannot multiply matrices of shapes {:?} and {:?}.", x.shape, y.shape);
+        }
+        let mut value = DMatrix::zeros((x.shape.0, y.shape.1));
+        mulm(x, y, &mut value);
+        self.push(Op::MatMul(a.0, b.0), value)
+    }
+
+    pub fn scale(&mut self, a: Var, s: FloatPrecision) -> Var {
+        let x = self.value(a);
+        let value = DMatrix::new(x.data.iter().map(|v| s * v).collect(), x.shape);
+        self.push(Op::Scale(a.0, s), value)
+    }
+
+    pub fn exp(&mut self, a: Var) -> Var {
+        let value = map(self.value(a), FloatPrecision::exp);
+        self.push(Op::Map(a.0, |_, y| y), value)
+    }
+
+    pub fn ln(&mut self, a: Var) -> Var {
+        let value = map(self.value(a), FloatPrecision::ln);
+        self.push(Op::Map(a.0, |x, _| 1. / x), value)
+    }
+
+    pub fn square(&mut self, a: Var) -> Var {
+        let value = map(self.value(a), |x| x * x);
+        self.push(Op::Map(a.0, |x, _| 2. * x), value)
+    }
+
+    // Any activation without learned parameters, e.g. TANH or SOFTMAX, using its own backward
+    pub fn activation<A: Activation + 'static>(&mut self, a: Var, activation: A) -> Var {
+        self.activation_with(a, activation, &[])
+    }
+
+    // Activation whose learned parameters, e.g. alpha of PRelu, are the
+    // values of params in the order of Activation::parameters, so that they
+    // get gradients like every other var
+    pub fn activation_with<A: Activation + 'static>(&mut self, a: Var, mut activation: A, params: &[Var]) -> Var {
+        let name = activation.name();
+        let mut own = activation.parameters();
+        if own.len() != params.len() {
+            panic!("Activation {} has {} learned parameters, got {} vars.", name, own.len(), params.len());
+        }
+        for (p, v) in own.iter_mut().zip(params.iter()) {
+            let value = &self.entries[v.0].value;
+            if value.shape != p.value.shape {
+                panic!("Parameter {} of shape {:?} got a var of shape {:?}.", p.name, p.value.shape, value.shape);
+            }
+            p.value.data.copy_from_slice(&value.data);
+        }
+        drop(own);
+
+        let net = self.value(a);
+        let mut value = DMatrix::zeros(net.shape);
+        activation.forward(net, &mut value);
+        let params = params.iter().map(|v| v.0).collect();
+        self.push(Op::Activation(a.0, Box::new(activation), params), value)
+    }
+
+    // Sum of all entries as 1 x 1 matrix
+    pub fn sum(&mut self, a: Var) -> Var {
+        let sum = self.value(a).data.iter().sum();
+        self.push(Op::Sum(a.0), DMatrix::new(vec![sum], (1, 1)))
+    }
+
+    pub fn mean(&mut self, a: Var) -> Var {
+        let n = self.value(a).data.len();
+        let sum = self.sum(a);
+        self.scale(sum, 1. / n as FloatPrecision)
+    }
+
+    pub fn transpose(&mut self, a: Var) -> Var {
+        let value = transpose(self.value(a));
+        self.push(Op::Transpose(a.0), value)
+    }
+
+    // Gradients of a scalar, i.e. 1 x 1, value with respect to everything recorded before it
+    pub fn backward(&mut self, v: Var) {
+        let shape = self.value(v).shape;
+        if shape != (1, 1) {
+            panic!("Only scalars can be differentiated without seed, got shape {:?}.", shape);
+        }
+        self.backward_with(v, &DMatrix::new(vec![1.], (1, 1)));
+    }
+
+    // Propagates seed as the gradient of v, e.g. the delta a layer got
+    pub fn backward_with(&mut self, v: Var, seed: &DMatrix) {
+        if seed.shape != self.value(v).shape {
+            panic!("Seed of shape {:?} does not fit value of shape {:?}.", seed.shape, self.value(v).shape);
+        }
+        for entry in self.entries.iter_mut() {
+            entry.grad = DMatrix::zeros(entry.value.shape);
+        }
+        self.entries[v.0].grad.data.copy_from_slice(&seed.data);
+
+        for i in (0..=v.0).rev() {
+            let (before, rest) = self.entries.split_at_mut(i);
+            let Entry { op, value, grad } = &mut rest[0];
+            match op {
+                Op::Leaf => {}
+                Op::Add(a, b) => {
+                    addm_assign(&mut before[*a].grad, grad);
+                    unbroadcast(grad, 1., &mut before[*b].grad);
+                }
+                Op::Sub(a, b) => {
+                    addm_assign(&mut before[*a].grad, grad);
+                    unbroadcast(grad, -1., &mut before[*b].grad);
+                }
+                Op::Mul(a, b) => {
+                    let (a, b) = (*a, *b);
+                    for k in 0..grad.data.len() {
+                        let (x, y) = (before[a].value.data[k], before[b].value.data[k]);
+                        before[a].grad.data[k] += grad.data[k] * y;
+                        before[b].grad.data[k] += grad.data[k] * x;
+                    }
+                }
+                Op::MatMul(a, b) => {
+                    mmulmt_add(grad, &before[*b].value, &mut before[*a].grad); // dA += G * BT
+                    let mut db = DMatrix::zeros(before[*b].value.shape);
+                    mtmulm(&before[*a].value, grad, &mut db); // dB += AT * G
+                    addm_assign(&mut before[*b].grad, &db);
+                }
+                Op::Scale(a, s) => saddm_assign(*s, &mut before[*a].grad, grad),
+                Op::Map(a, fd) => {
+                    let input = &mut before[*a];
+                    for k in 0..grad.data.len() {
+                        input.grad.data[k] += grad.data[k] * fd(input.value.data[k], value.data[k]);
+                    }
+                }
+                Op::Activation(a, activation, params) => {
+                    let mut delta = grad.clone();
+                    activation.backward(&before[*a].value, value, &mut delta);
+                    addm_assign(&mut before[*a].grad, &delta);
+                    for (p, &k) in activation.parameters().iter().zip(params.iter()) {
+                        addm_assign(&mut before[k].grad, p.grad);
+                    }
+                }
+                Op::Sum(a) => {
+                    for x in before[*a].grad.data.iter_mut() {
+                        *x += grad.data[0];
+                    }
+                }
+                Op::Transpose(a) => addm_assign(&mut before[*a].grad, &transpose(grad)),
+            }
+        }
+    }
+}
+
+// A layer written as forward code on a Tape. f gets the input and the
+// parameters as vars and returns the output, the backward pass is derived
+// from the recorded operations.
+pub struct Custom<F: Fn(&mut Tape, Var, &[Var]) -> Var> {
+    f: F,
+    pub params: Vec<DMatrix>,
+    pub grads: Vec<DMatrix>, // -dE/dparam, like the gradients of the other layers
+    tape: Tape,
+    vars: Vec<Var>, // input, then the parameters
+    output: Var,
+    input_delta: DMatrix,
+    trainable: bool,
+    rate: FloatPrecision,
+}
+
+impl<F: Fn(&mut Tape, Var, &[Var]) -> Var> Custom<F> {
+    pub fn new(params: Vec<DMatrix>, rate: FloatPrecision, f: F) -> Self {
+        let grads = params.iter().map(|p| DMatrix::zeros(p.shape)).collect();
+        Self {
+            f,
+            params,
+            grads,
+            tape: Tape::new(),
+            vars: Vec::new(),
+            output: Var(0),
+            input_delta: DMatrix::zeros((0, 0)),
+            trainable: true,
+            rate,
+        }
+    }
+
+    fn record(&self, tape: &mut Tape, input: &DMatrix) -> (Vec<Var>, Var) {
+        let mut vars = vec![tape.var(input)];
+        for p in self.params.iter() {
+            vars.push(tape.var(p));
+        }
+        let output = (self.f)(tape, vars[0], &vars[1..]);
+        (vars, output)
+    }
+}
+
+impl<F: Fn(&mut Tape, Var, &[Var]) -> Var> Module for Custom<F> {
+    fn forward(&mut self, input: &DMatrix) {
+        let mut tape = std::mem::replace(&mut self.tape, Tape::new());
+        tape.clear();
+        let (vars, output) = self.record(&mut tape, input);
+        self.tape = tape;
+        self.vars = vars;
+        self.output = output;
+    }
+
+    // Seeding with the delta -dE/dout yields -dE for all inputs
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        self.tape.backward_with(self.output, delta);
+        self.input_delta = self.tape.grad(self.vars[0]).clone();
+        if self.trainable {
+            for (k, grad) in self.grads.iter_mut().enumerate() {
+                grad.data.copy_from_slice(&self.tape.grad(self.vars[k + 1]).data);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        for (param, grad) in self.params.iter_mut().zip(self.grads.iter()) {
+            saddm_assign(self.rate, param, grad);
+        }
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn out(&self) -> &DMatrix {
+        self.tape.value(self.output)
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    // Found by running f on a single sample of zeros
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let mut tape = Tape::new();
+        let (_, output) = self.record(&mut tape, &DMatrix::zeros((input_shape.iter().product(), 1)));
+        vec![tape.value(output).shape.0]
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for param in self.params.iter() {
+            write_matrix(w, param)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        for param in self.params.iter_mut() {
+            read_matrix(r, param)?;
+        }
+        Ok(())
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let names = (0..self.params.len()).map(|k| k.to_string());
+        let params = self.params.iter_mut().zip(self.grads.iter_mut());
+        names.zip(params).map(|(name, (value, grad))| Parameter::new(&name, value, grad)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{PRelu, TANH};
+    use crate::gradcheck::{assert_passed, check_module, check_tape, matrix};
+    use crate::losses::Mse;
+
+    // sum(w * v) with fixed, irregular w, so that every entry of v gets another gradient
+    fn weighted(tape: &mut Tape, v: Var) -> Var {
+        let shape = tape.value(v).shape;
+        let w = tape.var(&DMatrix::new((0..shape.0 * shape.1).map(|i| ((i + 1) as FloatPrecision * 0.7).sin()).collect(), shape));
+        let product = tape.mul(v, w);
+        tape.sum(product)
+    }
+
+    // Positive values for ln
+    fn positive(shape: (usize, usize), seed: usize) -> DMatrix {
+        DMatrix::new(matrix(shape, seed).data.iter().map(|x| x.abs() + 0.5).collect(), shape)
+    }
+
+    #[test]
+    fn sub() {
+        let checks = check_tape(&[matrix((3, 4), 1), matrix((3, 4), 2)], &|tape, x| {
+            let d = tape.sub(x[0], x[1]);
+            weighted(tape, d)
+        });
+        assert_passed(&checks);
+        // A column subtracted from every column
+        let checks = check_tape(&[matrix((3, 4), 1), matrix((3, 1), 2)], &|tape, x| {
+            let d = tape.sub(x[0], x[1]);
+            weighted(tape, d)
+        });
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn mul() {
+        let checks = check_tape(&[matrix((3, 4), 1), matrix((3, 4), 2)], &|tape, x| {
+            let p = tape.mul(x[0], x[1]);
+            weighted(tape, p)
+        });
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn elementwise() {
+        let checks = check_tape(&[matrix((3, 4), 1)], &|tape, x| {
+            let e = tape.exp(x[0]);
+            weighted(tape, e)
+        });
+        assert_passed(&checks);
+        let checks = check_tape(&[positive((3, 4), 1)], &|tape, x| {
+            let l = tape.ln(x[0]);
+            weighted(tape, l)
+        });
+        assert_passed(&checks);
+        let checks = check_tape(&[matrix((3, 4), 1)], &|tape, x| {
+            let s = tape.square(x[0]);
+            weighted(tape, s)
+        });
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn transpose() {
+        let checks = check_tape(&[matrix((3, 4), 1), matrix((3, 2), 2)], &|tape, x| {
+            let t = tape.transpose(x[0]);
+            let p = tape.matmul(t, x[1]);
+            weighted(tape, p)
+        });
+        assert_passed(&checks);
+    }
+
+    // Scalar backward of a mean of squared errors, i.e. the usual loss
+    #[test]
+    fn mean() {
+        let checks = check_tape(&[matrix((3, 4), 1), matrix((3, 4), 2)], &|tape, x| {
+            let d = tape.sub(x[0], x[1]);
+            let s = tape.square(d);
+            tape.mean(s)
+        });
+        assert_eq!(checks.len(), 2);
+        assert_passed(&checks);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only scalars can be differentiated")]
+    fn backward_needs_scalar() {
+        let mut tape = Tape::new();
+        let x = tape.var(&matrix((3, 2), 0));
+        let s = tape.square(x);
+        tape.backward(s);
+    }
+
+    // PRelu(W x) with W and alpha as parameters of the layer
+    fn prelu_layer() -> Custom<impl Fn(&mut Tape, Var, &[Var]) -> Var> {
+        let alpha = DMatrix::new(vec![0.1, 0.2, 0.3], (3, 1));
+        Custom::new(vec![matrix((3, 4), 1), alpha], 0.1, |tape, x, p| {
+            let net = tape.matmul(p[0], x);
+            tape.activation_with(net, PRelu::new(3), &p[1..])
+        })
+    }
+
+    #[test]
+    fn custom() {
+        let mut layer = Custom::new(vec![matrix((3, 4), 1), matrix((3, 1), 2)], 0.1, |tape, x, p| {
+            let net = tape.matmul(p[0], x);
+            let net = tape.add(net, p[1]);
+            tape.activation(net, TANH)
+        });
+        let checks = check_module(&mut layer, &Mse, &matrix((4, 5), 3), &matrix((3, 5), 4));
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn learned_activation() {
+        let mut layer = prelu_layer();
+        let checks = check_module(&mut layer, &Mse, &matrix((4, 5), 3), &matrix((3, 5), 4));
+        assert_eq!(checks.len(), 3);
+        assert_passed(&checks);
+    }
+
+    #[test]
+    #[should_panic(expected = "has 1 learned parameters")]
+    fn learned_activation_without_vars() {
+        let mut tape = Tape::new();
+        let x = tape.var(&matrix((3, 2), 0));
+        tape.activation(x, PRelu::new(3));
+    }
+
+    #[test]
+    fn frozen_custom() {
+        let mut layer = prelu_layer();
+        layer.set_trainable(false);
+        let input = matrix((4, 5), 3);
+        layer.forward(&input);
+        layer.backward(&input, &matrix((3, 5), 4));
+        layer.update();
+        assert_eq!(layer.params[0].data, matrix((3, 4), 1).data);
+        assert_eq!(layer.params[1].data, vec![0.1, 0.2, 0.3]);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::constants::FloatPrecision;
+use crate::layers::Parameter;
+
+// Limits the gradients between backward and update, so that a single bad
+// batch cannot blow up the weights
+#[derive(Debug, Clone, Copy)]
+pub enum Clipping {
+    Value(FloatPrecision),      // every entry into [-v, v]
+    Norm(FloatPrecision),       // every tensor to at most this L2 norm
+    GlobalNorm(FloatPrecision), // all tensors together, keeping their direction
+}
+
+fn norm(p: &Parameter) -> FloatPrecision {
+    p.grad.data.iter().map(|g| g * g).sum::<FloatPrecision>().sqrt()
+}
+
+// L2 norm of all gradients as if they were one vector
+pub fn global_norm(params: &[Parameter]) -> FloatPrecision {
+    params.iter().map(|p| norm(p).powi(2)).sum::<FloatPrecision>().sqrt()
+}
+
+fn scale(p: &mut Parameter, s: FloatPrecision) {
+    p.grad.data.iter_mut().for_each(|g| *g *= s);
+}
+
+impl Clipping {
+    // Clips in place and returns the global norm from before
+    pub fn apply(&self, params: &mut [Parameter]) -> FloatPrecision {
+        let total = global_norm(params);
+        match *self {
+            Clipping::Value(max) => {
+                for p in params.iter_mut() {
+                    p.grad.data.iter_mut().for_each(|g| *g = g.clamp(-max, max));
+                }
+            }
+            Clipping::Norm(max) => {
+                for p in params.iter_mut() {
+                    let n = norm(p);
+                    if n > max {
+                        scale(p, max / n);
+                    }
+                }
+            }
+            Clipping::GlobalNorm(max) => {
+                if total > max {
+                    for p in params.iter_mut() {
+                        scale(p, max / total);
+                    }
+                }
+            }
+        }
+        total
+    }
+}
+
+// Applies clipping if there is one, returns the global norm from before either way
+pub fn clip(clipping: Option<Clipping>, params: &mut [Parameter]) -> FloatPrecision {
+    match clipping {
+        Some(clipping) => clipping.apply(params),
+        None => global_norm(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::DMatrix;
+
+    // Gradients with the norms 5 and 1, so 26 is the squared global norm
+    fn clipped(clipping: Option<Clipping>) -> (FloatPrecision, Vec<FloatPrecision>, Vec<FloatPrecision>) {
+        let (mut w1, mut w2) = (DMatrix::zeros((2, 1)), DMatrix::zeros((3, 1)));
+        let mut g1 = DMatrix::new(vec![3., 4.], (2, 1));
+        let mut g2 = DMatrix::new(vec![0.6, -0.8, 0.], (3, 1));
+        let mut params = vec![Parameter::new("a", &mut w1, &mut g1), Parameter::new("b", &mut w2, &mut g2)];
+        let norm = clip(clipping, &mut params);
+        drop(params);
+        (norm, g1.data, g2.data)
+    }
+
+    fn assert_close(a: &[FloatPrecision], b: &[FloatPrecision]) {
+        assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-12), "{:?} vs {:?}", a, b);
+    }
+
+    #[test]
+    fn value() {
+        let (norm, a, b) = clipped(Some(Clipping::Value(0.7)));
+        assert!((norm - 26f64.sqrt()).abs() < 1e-12);
+        assert_close(&a, &[0.7, 0.7]);
+        assert_close(&b, &[0.6, -0.7, 0.]);
+    }
+
+    #[test]
+    fn norm() {
+        let (norm, a, b) = clipped(Some(Clipping::Norm(2.)));
+        assert!((norm - 26f64.sqrt()).abs() < 1e-12);
+        assert_close(&a, &[1.2, 1.6]);
+        assert_close(&b, &[0.6, -0.8, 0.]);
+    }
+
+    #[test]
+    fn global_norm() {
+        let (norm, a, b) = clipped(Some(Clipping::GlobalNorm(2.)));
+        assert!((norm - 26f64.sqrt()).abs() < 1e-12);
+        let s = 2. / 26f64.sqrt();
+        assert_close(&a, &[3. * s, 4. * s]);
+        assert_close(&b, &[0.6 * s, -0.8 * s, 0.]);
+
+        // Within the limit nothing changes, without clipping the norm is still returned
+        let (_, a, _) = clipped(Some(Clipping::GlobalNorm(10.)));
+        assert_close(&a, &[3., 4.]);
+        let (norm, a, _) = clipped(None);
+        assert!((norm - 26f64.sqrt()).abs() < 1e-12);
+        assert_close(&a, &[3., 4.]);
+    }
+}
@@ -0,0 +1,382 @@
+use std::io;
+use std::io::{Read, Write};
+
+use rand::Rng;
+
+use crate::activations::Activation;
+use crate::constants::FloatPrecision;
+use crate::inference;
+use crate::inference::Inference;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::math::linm;
+use crate::math::mtmulm;
+use crate::math::rowsum;
+use crate::math::saddm_assign;
+use crate::math::smmulmt;
+use crate::math::DMatrix;
+use crate::registry::Registry;
+use crate::regularization::{Constraint, Penalties, Regularizer};
+use crate::serialize::{read_activation, read_matrix, write_activation, write_matrix};
+
+// Images are stored channel first, one image per column, i.e. pixel (c, y, x)
+// of an image with size (h, w) is row c * h * w + y * w + x.
+
+// Where a sliding window of size kernel visits an image
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub channels: usize,
+    pub input: (usize, usize),
+    pub kernel: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+    pub dilation: (usize, usize),
+}
+
+impl Window {
+    pub fn new(channels: usize, input: (usize, usize), kernel: (usize, usize)) -> Self {
+        Self {
+            channels,
+            input,
+            kernel,
+            stride: (1, 1),
+            padding: (0, 0),
+            dilation: (1, 1),
+        }
+    }
+
+    pub fn output(&self) -> (usize, usize) {
+        (
+            output_size(self.input.0, self.kernel.0, self.stride.0, self.padding.0, self.dilation.0),
+            output_size(self.input.1, self.kernel.1, self.stride.1, self.padding.1, self.dilation.1),
+        )
+    }
+
+    // Shape (channels, height, width) of the output images with the given number of channels
+    pub fn output_shape(&self, input_shape: &[usize], channels: usize) -> Vec<usize> {
+        let expected = [self.channels, self.input.0, self.input.1];
+        if input_shape.iter().product::<usize>() != expected.iter().product::<usize>() {
+            panic!("Expected inputs of shape {:?}, got {:?}.", expected, input_shape);
+        }
+        let (ho, wo) = self.output();
+        vec![channels, ho, wo]
+    }
+
+    // Position in the input of kernel element (ky, kx) at output (oy, ox), None if it lies in the padding
+    pub fn source(&self, oy: usize, ox: usize, ky: usize, kx: usize) -> Option<(usize, usize)> {
+        let y = (oy * self.stride.0 + ky * self.dilation.0) as isize - self.padding.0 as isize;
+        let x = (ox * self.stride.1 + kx * self.dilation.1) as isize - self.padding.1 as isize;
+        if y < 0 || x < 0 || y >= self.input.0 as isize || x >= self.input.1 as isize {
+            None
+        } else {
+            Some((y as usize, x as usize))
+        }
+    }
+}
+
+fn output_size(input: usize, kernel: usize, stride: usize, padding: usize, dilation: usize) -> usize {
+    let span = dilation * (kernel - 1) + 1;
+    if input + 2 * padding < span {
+        panic!("Kernel of size {kernel} with dilation {dilation} does not fit into input of size {input} with padding {padding}.");
+    }
+    (input + 2 * padding - span) / stride + 1
+}
+
+// Copies the patches of column `sample` of images into the columns
+// offset..offset + ho * wo of cols, which has channels * kh * kw rows.
+pub fn im2col(window: &Window, images: &DMatrix, sample: usize, cols: &mut DMatrix, offset: usize) {
+    let (h, w) = window.input;
+    let (kh, kw) = window.kernel;
+    let (ho, wo) = window.output();
+    let batch = images.shape.1;
+    let m = cols.shape.1;
+    for c in 0..window.channels {
+        for ky in 0..kh {
+            for kx in 0..kw {
+                let row = (c * kh + ky) * kw + kx;
+                for oy in 0..ho {
+                    for ox in 0..wo {
+                        let index = row * m + offset + oy * wo + ox;
+                        cols.data[index] = match window.source(oy, ox, ky, kx) {
+                            Some((y, x)) => images.data[(c * h * w + y * w + x) * batch + sample],
+                            None => 0.,
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Inverse of im2col, adds every patch entry back onto the pixel it came from
+pub fn col2im(window: &Window, cols: &DMatrix, offset: usize, images: &mut DMatrix, sample: usize) {
+    let (h, w) = window.input;
+    let (kh, kw) = window.kernel;
+    let (ho, wo) = window.output();
+    let batch = images.shape.1;
+    let m = cols.shape.1;
+    for c in 0..window.channels {
+        for y in 0..h {
+            for x in 0..w {
+                images.data[(c * h * w + y * w + x) * batch + sample] = 0.;
+            }
+        }
+        for ky in 0..kh {
+            for kx in 0..kw {
+                let row = (c * kh + ky) * kw + kx;
+                for oy in 0..ho {
+                    for ox in 0..wo {
+                        if let Some((y, x)) = window.source(oy, ox, ky, kx) {
+                            images.data[(c * h * w + y * w + x) * batch + sample] += cols.data[row * m + offset + oy * wo + ox];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 2D convolution (strictly speaking cross-correlation) over channel first images
+pub struct Conv2d {
+    pub window: Window,
+    out_channels: usize,
+    pub weights: DMatrix, // (out_channels, in_channels * kh * kw)
+    pub bias: DMatrix,
+    pub activation: Box<dyn Activation>,
+    cols: DMatrix,    // patches of the whole batch side by side
+    product: DMatrix, // weights * cols + bias, (out_channels, batch * ho * wo)
+    pub net: DMatrix,
+    pub out: DMatrix,
+    dcols: DMatrix,
+    input_delta: DMatrix,
+    pub dw: DMatrix,
+    pub db: DMatrix,
+    pub penalties: Penalties,
+    trainable: bool,
+    rate: FloatPrecision,
+    rate_multiplier: FloatPrecision,
+}
+
+impl Conv2d {
+    pub fn new<A: Activation + 'static>(
+        in_channels: usize,
+        out_channels: usize,
+        input: (usize, usize),
+        kernel: (usize, usize),
+        activation: A,
+        rate: FloatPrecision,
+    ) -> Self {
+        let fan_in = in_channels * kernel.0 * kernel.1;
+        // Scaled by the fan in, since kernels with many channels would saturate with the range of Layer
+        let bound = 1. / (fan_in as FloatPrecision).sqrt();
+        let mut rng = rand::thread_rng();
+        let weights_data: Vec<FloatPrecision> = (0..out_channels * fan_in).map(|_| rng.gen_range(-bound..bound)).collect();
+        let bias_data: Vec<FloatPrecision> = (0..out_channels).map(|_| rng.gen_range(-bound..bound)).collect();
+        Self {
+            window: Window::new(in_channels, input, kernel),
+            out_channels,
+            weights: DMatrix::new(weights_data, (out_channels, fan_in)),
+            bias: DMatrix::new(bias_data, (out_channels, 1)),
+            activation: Box::new(activation),
+            cols: DMatrix::zeros((0, 0)),
+            product: DMatrix::zeros((0, 0)),
+            net: DMatrix::zeros((0, 0)),
+            out: DMatrix::zeros((0, 0)),
+            dcols: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+            dw: DMatrix::zeros((out_channels, fan_in)),
+            db: DMatrix::zeros((out_channels, 1)),
+            penalties: Penalties::default(),
+            trainable: true,
+            rate,
+            rate_multiplier: 1.,
+        }
+    }
+
+    pub fn with_stride(mut self, stride: (usize, usize)) -> Self {
+        self.window.stride = stride;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: (usize, usize)) -> Self {
+        self.window.padding = padding;
+        self
+    }
+
+    pub fn with_dilation(mut self, dilation: (usize, usize)) -> Self {
+        self.window.dilation = dilation;
+        self
+    }
+
+    pub fn with_kernel_regularizer(mut self, regularizer: Regularizer) -> Self {
+        self.penalties.kernel = Some(regularizer);
+        self
+    }
+
+    pub fn with_bias_regularizer(mut self, regularizer: Regularizer) -> Self {
+        self.penalties.bias = Some(regularizer);
+        self
+    }
+
+    pub fn with_weight_decay(mut self, weight_decay: FloatPrecision) -> Self {
+        self.penalties.weight_decay = weight_decay;
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: Constraint) -> Self {
+        self.penalties.constraint = Some(constraint);
+        self
+    }
+}
+
+impl Module for Conv2d {
+    fn forward(&mut self, input: &DMatrix) {
+        let (h, w) = self.window.input;
+        let (ho, wo) = self.window.output();
+        let positions = ho * wo;
+        let batch = input.shape.1;
+        if input.shape.0 != self.window.channels * h * w {
+            panic!("Conv2d expects {} x {} x {} images, got {} rows.", self.window.channels, h, w, input.shape.0);
+        }
+        if self.out.shape.1 != batch || self.cols.shape.1 != batch * positions {
+            let k = self.weights.shape.1;
+            self.cols = DMatrix::zeros((k, batch * positions));
+            self.dcols = DMatrix::zeros((k, batch * positions));
+            self.product = DMatrix::zeros((self.out_channels, batch * positions));
+            self.net = DMatrix::zeros((self.out_channels * positions, batch));
+            self.out = DMatrix::zeros((self.out_channels * positions, batch));
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        for b in 0..batch {
+            im2col(&self.window, input, b, &mut self.cols, b * positions);
+        }
+        linm(&self.weights, &self.cols, &self.bias, &mut self.product); // W * cols + b
+
+        // (out_channels, batch * positions) -> (out_channels * positions, batch)
+        let m = batch * positions;
+        for c in 0..self.out_channels {
+            for b in 0..batch {
+                for p in 0..positions {
+                    self.net.data[(c * positions + p) * batch + b] = self.product.data[c * m + b * positions + p];
+                }
+            }
+        }
+        self.activation.forward(&self.net, &mut self.out);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let (ho, wo) = self.window.output();
+        let positions = ho * wo;
+        let batch = delta.shape.1;
+
+        let mut delta = delta.clone();
+        self.activation.backward(&self.net, &self.out, &mut delta); // dE * f'(net)
+
+        // Back into the layout of product, reusing its buffer
+        let m = batch * positions;
+        for c in 0..self.out_channels {
+            for b in 0..batch {
+                for p in 0..positions {
+                    self.product.data[c * m + b * positions + p] = delta.data[(c * positions + p) * batch + b];
+                }
+            }
+        }
+        if self.trainable {
+            smmulmt(1., &self.product, &self.cols, &mut self.dw); // dW = delta * colsT
+            rowsum(&self.product, &mut self.db);
+            self.penalties.gradients(&self.weights, &self.bias, &mut self.dw, &mut self.db);
+        }
+        mtmulm(&self.weights, &self.product, &mut self.dcols);
+        for b in 0..batch {
+            col2im(&self.window, &self.dcols, b * positions, &mut self.input_delta, b);
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(self.rate, &mut self.weights, &self.dw);
+        saddm_assign(self.rate, &mut self.bias, &self.db);
+        self.penalties.update(self.rate * self.rate_multiplier, &mut self.weights);
+        self.activation.update(self.rate);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.window.output_shape(input_shape, self.out_channels)
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn set_rate_multiplier(&mut self, multiplier: FloatPrecision) {
+        self.rate_multiplier = multiplier;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.weights)?;
+        write_matrix(w, &self.bias)?;
+        write_activation(w, &*self.activation)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.weights)?;
+        read_matrix(r, &mut self.bias)?;
+        read_activation(r, &mut self.activation, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = vec![
+            Parameter::new("weights", &mut self.weights, &mut self.dw).prunable(),
+            Parameter::new("bias", &mut self.bias, &mut self.db),
+        ];
+        params.extend(self.activation.parameters().into_iter().map(|p| p.prefixed("activation")));
+        params
+    }
+
+    fn penalty(&self) -> FloatPrecision {
+        self.penalties.penalty(&self.weights, &self.bias)
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Convolution::new(
+            self.window,
+            self.weights.clone(),
+            self.bias.clone(),
+            self.activation.boxed(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::TANH;
+    use crate::gradcheck::{assert_passed, check_module, matrix};
+    use crate::losses::Mse;
+
+    fn check(conv: Conv2d) {
+        let mut conv = conv;
+        let outputs: usize = conv.output_shape(&[2, 5, 5]).iter().product();
+        let checks = check_module(&mut conv, &Mse, &matrix((50, 2), 1), &matrix((outputs, 2), 2));
+        assert_eq!(checks.len(), 3);
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn conv2d() {
+        check(Conv2d::new(2, 3, (5, 5), (3, 3), TANH, 0.1));
+        check(Conv2d::new(2, 3, (5, 5), (3, 2), TANH, 0.1).with_stride((2, 1)).with_padding((1, 1)));
+        check(Conv2d::new(2, 2, (5, 5), (2, 2), TANH, 0.1).with_dilation((2, 2)));
+    }
+}
@@ -0,0 +1,396 @@
+use std::fmt;
+
+use crate::activations::Activation;
+use crate::autodiff::{Tape, Var};
+use crate::constants::FloatPrecision;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::losses::Loss;
+use crate::math::DMatrix;
+use crate::models::Graph;
+use crate::models::NeuralNetwork;
+
+// Compares the analytic gradients of backward with central differences
+// (E(x + h) - E(x - h)) / 2h. Every forward has to be deterministic, so
+// dropout must be left out while checking.
+
+const H: FloatPrecision = 1e-5;
+
+// Differences below this are rounding noise of the central differences
+const NOISE: FloatPrecision = 1e-8;
+
+// Result for one tensor, the relative error |a - n| / (|a| + |n|) of the
+// analytic gradient a and the numerical one n. Around 1e-7 and below is
+// fine, kinks like the one of relu at 0 can make it larger.
+#[derive(Debug, Clone)]
+pub struct GradCheck {
+    pub name: String,
+    pub error: FloatPrecision,
+    pub difference: FloatPrecision, // |a - n|
+}
+
+impl GradCheck {
+    // Gradients that vanish, e.g. of the key bias in attention, only have a
+    // meaningless relative error and pass if the difference is noise
+    pub fn passed(&self, tolerance: FloatPrecision) -> bool {
+        self.error <= tolerance || self.difference <= NOISE
+    }
+}
+
+impl fmt::Display for GradCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:e} (difference {:e})", self.name, self.error, self.difference)
+    }
+}
+
+fn relative_error(name: &str, analytic: &[FloatPrecision], numerical: &[FloatPrecision]) -> GradCheck {
+    let norm = |v: &mut dyn Iterator<Item = FloatPrecision>| v.map(|x| x * x).sum::<FloatPrecision>().sqrt();
+    let diff = norm(&mut analytic.iter().zip(numerical.iter()).map(|(a, n)| a - n));
+    let scale = norm(&mut analytic.iter().copied()) + norm(&mut numerical.iter().copied());
+    GradCheck {
+        name: name.to_string(),
+        error: if scale == 0. { 0. } else { diff / scale },
+        difference: diff,
+    }
+}
+
+// dE/d(value) of a parameter, which only stores -dE and maybe only some rows
+fn analytic(p: &Parameter) -> Vec<FloatPrecision> {
+    match p.rows {
+        None => p.grad.data.iter().map(|g| -g).collect(),
+        Some(rows) => {
+            let mut dense = vec![0.; p.value.data.len()];
+            let width = p.value.shape.1;
+            for (k, &row) in rows.iter().enumerate() {
+                for j in 0..width {
+                    dense[row * width + j] -= p.grad.data[k * width + j];
+                }
+            }
+            dense
+        }
+    }
+}
+
+// Anything with a scalar error that depends on an input and parameters
+trait Target {
+    fn error(&mut self) -> FloatPrecision;
+
+    // Computes the gradients and returns -dE/d(input), if there is one
+    fn gradients(&mut self) -> Option<DMatrix>;
+
+    fn input(&mut self) -> &mut DMatrix;
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>>;
+}
+
+// Entry i of the input (tensor None) or of parameter tensor k
+fn entry(target: &mut dyn Target, tensor: Option<usize>, i: usize) -> &mut FloatPrecision {
+    match tensor {
+        None => &mut target.input().data[i],
+        Some(k) => {
+            let value = target.parameters().swap_remove(k).value;
+            &mut value.data[i]
+        }
+    }
+}
+
+fn numerical(target: &mut dyn Target, tensor: Option<usize>, len: usize) -> Vec<FloatPrecision> {
+    let mut gradient = Vec::with_capacity(len);
+    for i in 0..len {
+        let old = *entry(target, tensor, i);
+        *entry(target, tensor, i) = old + H;
+        let plus = target.error();
+        *entry(target, tensor, i) = old - H;
+        let minus = target.error();
+        *entry(target, tensor, i) = old;
+        gradient.push((plus - minus) / (2. * H));
+    }
+    gradient
+}
+
+fn check(target: &mut dyn Target) -> Vec<GradCheck> {
+    let mut checks = Vec::new();
+    let input_delta = target.gradients();
+    let analytic: Vec<(String, Vec<FloatPrecision>)> = target.parameters().iter().map(|p| (p.name.clone(), analytic(p))).collect();
+
+    if let Some(delta) = input_delta {
+        let numerical = numerical(target, None, delta.data.len());
+        let analytic: Vec<FloatPrecision> = delta.data.iter().map(|d| -d).collect();
+        checks.push(relative_error("input", &analytic, &numerical));
+    }
+    for (k, (name, analytic)) in analytic.iter().enumerate() {
+        let numerical = numerical(target, Some(k), analytic.len());
+        checks.push(relative_error(name, analytic, &numerical));
+    }
+    checks
+}
+
+struct ModuleTarget<'a> {
+    module: &'a mut dyn Module,
+    loss: &'a dyn Loss,
+    input: DMatrix,
+    label: &'a DMatrix,
+    check_input: bool,
+}
+
+impl<'a> Target for ModuleTarget<'a> {
+    // Includes the regularization penalty like NeuralNetwork::loss
+    fn error(&mut self) -> FloatPrecision {
+        self.module.forward(&self.input);
+        self.loss.loss(self.module.out(), self.label) + self.module.penalty()
+    }
+
+    fn gradients(&mut self) -> Option<DMatrix> {
+        self.module.forward(&self.input);
+        let mut delta = DMatrix::zeros(self.label.shape);
+        self.loss.delta(self.module.out(), self.label, &mut delta);
+        self.module.backward(&self.input, &delta);
+        if self.check_input {
+            Some(self.module.delta().clone())
+        } else {
+            None
+        }
+    }
+
+    fn input(&mut self) -> &mut DMatrix {
+        &mut self.input
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.module.parameters()
+    }
+}
+
+// Checks the delta of the input and the gradients of all parameters of a
+// module, whose output is compared to label with loss.
+pub fn check_module(module: &mut dyn Module, loss: &dyn Loss, input: &DMatrix, label: &DMatrix) -> Vec<GradCheck> {
+    module.set_training(true);
+    check(&mut ModuleTarget {
+        module,
+        loss,
+        input: input.clone(),
+        label,
+        check_input: true,
+    })
+}
+
+// Like check_module but leaves the input alone, for inputs that cannot be
+// perturbed like the ids of an Embedding
+pub fn check_parameters(module: &mut dyn Module, loss: &dyn Loss, input: &DMatrix, label: &DMatrix) -> Vec<GradCheck> {
+    module.set_training(true);
+    check(&mut ModuleTarget {
+        module,
+        loss,
+        input: input.clone(),
+        label,
+        check_input: false,
+    })
+}
+
+struct NetworkTarget<'a> {
+    network: &'a mut NeuralNetwork,
+    input: DMatrix,
+    label: &'a DMatrix,
+}
+
+impl<'a> Target for NetworkTarget<'a> {
+    fn error(&mut self) -> FloatPrecision {
+        self.network.loss(&self.input, self.label)
+    }
+
+    fn gradients(&mut self) -> Option<DMatrix> {
+        self.network.gradients(&self.input, self.label);
+        None
+    }
+
+    fn input(&mut self) -> &mut DMatrix {
+        &mut self.input
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.network.parameters()
+    }
+}
+
+// Checks all parameters of a network with its own loss, in training mode
+pub fn check_model(network: &mut NeuralNetwork, input: &DMatrix, label: &DMatrix) -> Vec<GradCheck> {
+    network.set_training(true);
+    check(&mut NetworkTarget {
+        network,
+        input: input.clone(),
+        label,
+    })
+}
+
+struct GraphTarget<'a> {
+    graph: &'a mut Graph,
+    inputs: Vec<DMatrix>,
+    labels: &'a [&'a DMatrix],
+}
+
+impl<'a> Target for GraphTarget<'a> {
+    fn error(&mut self) -> FloatPrecision {
+        let inputs: Vec<&DMatrix> = self.inputs.iter().collect();
+        self.graph.loss(&inputs, self.labels)
+    }
+
+    fn gradients(&mut self) -> Option<DMatrix> {
+        let inputs: Vec<&DMatrix> = self.inputs.iter().collect();
+        self.graph.gradients(&inputs, self.labels);
+        None
+    }
+
+    fn input(&mut self) -> &mut DMatrix {
+        &mut self.inputs[0]
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.graph.parameters()
+    }
+}
+
+// Checks all parameters of a graph with its own loss summed over the outputs, in training mode
+pub fn check_graph(graph: &mut Graph, inputs: &[&DMatrix], labels: &[&DMatrix]) -> Vec<GradCheck> {
+    graph.set_training(true);
+    check(&mut GraphTarget {
+        graph,
+        inputs: inputs.iter().map(|&input| input.clone()).collect(),
+        labels,
+    })
+}
+
+// Fixed, irregular weights for the outputs, so that E = sum(weights * out)
+// sees every entry of a vector valued activation differently
+fn output_weights(shape: (usize, usize)) -> DMatrix {
+    DMatrix::new((0..shape.0 * shape.1).map(|i| ((i + 1) as FloatPrecision * 0.7).sin()).collect(), shape)
+}
+
+struct ActivationTarget<'a> {
+    activation: &'a mut dyn Activation,
+    net: DMatrix,
+    out: DMatrix,
+    weights: DMatrix,
+}
+
+impl<'a> Target for ActivationTarget<'a> {
+    fn error(&mut self) -> FloatPrecision {
+        self.activation.forward(&self.net, &mut self.out);
+        self.out.data.iter().zip(self.weights.data.iter()).map(|(o, w)| o * w).sum()
+    }
+
+    fn gradients(&mut self) -> Option<DMatrix> {
+        self.activation.forward(&self.net, &mut self.out);
+        let mut delta = DMatrix::new(self.weights.data.iter().map(|w| -w).collect(), self.weights.shape); // -dE/d(out)
+        self.activation.backward(&self.net, &self.out, &mut delta);
+        Some(delta)
+    }
+
+    fn input(&mut self) -> &mut DMatrix {
+        &mut self.net
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.activation.parameters()
+    }
+}
+
+// Checks the backward of an activation at net and the gradients of its parameters
+pub fn check_activation(activation: &mut dyn Activation, net: &DMatrix) -> Vec<GradCheck> {
+    check(&mut ActivationTarget {
+        activation,
+        net: net.clone(),
+        out: DMatrix::zeros(net.shape),
+        weights: output_weights(net.shape),
+    })
+}
+
+struct TapeTarget<'a> {
+    f: &'a dyn Fn(&mut Tape, &[Var]) -> Var,
+    values: Vec<DMatrix>,
+    grads: Vec<DMatrix>, // -dE like the gradients of parameters
+}
+
+impl<'a> TapeTarget<'a> {
+    fn record(&self, tape: &mut Tape) -> (Vec<Var>, Var) {
+        let vars: Vec<Var> = self.values.iter().map(|v| tape.var(v)).collect();
+        let out = (self.f)(tape, &vars);
+        (vars, out)
+    }
+}
+
+impl<'a> Target for TapeTarget<'a> {
+    fn error(&mut self) -> FloatPrecision {
+        let mut tape = Tape::new();
+        let (_, out) = self.record(&mut tape);
+        tape.value(out).data[0]
+    }
+
+    fn gradients(&mut self) -> Option<DMatrix> {
+        let mut tape = Tape::new();
+        let (vars, out) = self.record(&mut tape);
+        tape.backward(out);
+        self.grads = vars.iter().map(|&v| DMatrix::new(tape.grad(v).data.iter().map(|g| -g).collect(), tape.grad(v).shape)).collect();
+        None
+    }
+
+    fn input(&mut self) -> &mut DMatrix {
+        &mut self.values[0]
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let tensors = self.values.iter_mut().zip(self.grads.iter_mut());
+        tensors.enumerate().map(|(k, (value, grad))| Parameter::new(&format!("x{}", k), value, grad)).collect()
+    }
+}
+
+// Checks Tape::backward of the scalar f computes from vars of the values,
+// one check per value named x0, x1, ...
+pub fn check_tape(values: &[DMatrix], f: &dyn Fn(&mut Tape, &[Var]) -> Var) -> Vec<GradCheck> {
+    check(&mut TapeTarget {
+        f,
+        values: values.to_vec(),
+        grads: values.iter().map(|v| DMatrix::zeros(v.shape)).collect(),
+    })
+}
+
+// Checks the delta of a loss for the output out
+pub fn check_loss(loss: &dyn Loss, out: &DMatrix, label: &DMatrix) -> GradCheck {
+    let mut delta = DMatrix::zeros(out.shape);
+    loss.delta(out, label, &mut delta);
+    let analytic: Vec<FloatPrecision> = delta.data.iter().map(|d| -d).collect();
+    let mut out = out.clone();
+    let mut numerical = Vec::with_capacity(out.data.len());
+    for i in 0..out.data.len() {
+        let old = out.data[i];
+        out.data[i] = old + H;
+        let plus = loss.loss(&out, label);
+        out.data[i] = old - H;
+        let minus = loss.loss(&out, label);
+        out.data[i] = old;
+        numerical.push((plus - minus) / (2. * H));
+    }
+    relative_error("out", &analytic, &numerical)
+}
+
+// The checks whose errors are not within tolerance
+pub fn failures(checks: &[GradCheck], tolerance: FloatPrecision) -> Vec<&GradCheck> {
+    checks.iter().filter(|c| !c.passed(tolerance)).collect()
+}
+
+// Fixtures shared by the gradient tests of the modules
+
+#[cfg(test)]
+pub const TOLERANCE: FloatPrecision = 1e-6;
+
+// Deterministic but irregular values in [-1.3, 1.2]
+#[cfg(test)]
+pub fn matrix(shape: (usize, usize), seed: usize) -> DMatrix {
+    DMatrix::new((0..shape.0 * shape.1).map(|i| ((i * 7 + seed) % 11) as FloatPrecision / 4. - 1.3).collect(), shape)
+}
+
+// Fails with every check that is not within TOLERANCE
+#[cfg(test)]
+pub fn assert_passed(checks: &[GradCheck]) {
+    let failed: Vec<String> = failures(checks, TOLERANCE).iter().map(|c| c.to_string()).collect();
+    assert!(failed.is_empty(), "gradient checks failed: {}", failed.join(", "));
+}
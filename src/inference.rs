@@ -0,0 +1,952 @@
+use std::cell::RefCell;
+
+use crate::activations::Activation;
+use crate::activations::sigmoid;
+use crate::activations::Pointwise;
+use crate::attention::from_tokens;
+use crate::attention::gather;
+use crate::attention::scaled_dot_product;
+use crate::attention::scatter;
+use crate::attention::sinusoid;
+use crate::attention::to_tokens;
+use crate::constants::FloatPrecision;
+use crate::conv::im2col;
+use crate::conv::Window;
+use crate::math::addm;
+use crate::math::addm_assign;
+use crate::math::linm;
+use crate::math::linm_map;
+use crate::math::mulm;
+use crate::math::DMatrix;
+use crate::pruning::SparseMatrix;
+use crate::recurrent::get_step;
+use crate::recurrent::set_step;
+
+// Trained models keep buffers for gradients and the last forward pass in every
+// layer, so predicting needs &mut and a model cannot be shared. An
+// InferenceModel is an immutable copy of only the learned tensors, all
+// buffers live in a Scratch that belongs to the caller or the thread.
+
+// The forward pass of one layer in inference mode
+pub trait Inference: Send + Sync {
+    // Writes the output for a batch of inputs, one sample per column, into
+    // out. work holds whatever intermediate buffers the op needs, both are
+    // resized and reused by the next call.
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work);
+
+    // Takes over an Affine on its input, i.e. computes op(scale * x + shift)
+    // from x from now on. Returns false if it cannot.
+    fn fold_before(&mut self, affine: &Affine) -> bool {
+        false
+    }
+
+    // Takes over an Affine on its output, i.e. computes scale * op(x) + shift
+    fn fold_after(&mut self, affine: &Affine) -> bool {
+        false
+    }
+
+    fn as_affine(&self) -> Option<&Affine> {
+        None
+    }
+
+    // Ops like Flatten that only pass their input on
+    fn is_identity(&self) -> bool {
+        false
+    }
+
+    // Memory taken by the learned tensors
+    fn bytes(&self) -> usize {
+        0
+    }
+
+    // Switches to sparse weights if at least min_sparsity of them are zero,
+    // returns whether it did
+    fn sparsify(&mut self, min_sparsity: FloatPrecision) -> bool {
+        false
+    }
+}
+
+// Intermediate buffers of one op
+#[derive(Default)]
+pub struct Work {
+    pub matrices: Vec<DMatrix>,
+    pub bytes: Vec<i8>,
+    pub integers: Vec<i32>,
+    pub nested: Vec<Work>, // of the ops inside this one, e.g. the projections of attention
+}
+
+// Reallocates m only if its shape changes, e.g. for a new batch size
+pub fn fit(m: &mut DMatrix, shape: (usize, usize)) {
+    if m.shape != shape {
+        *m = DMatrix::zeros(shape);
+    }
+}
+
+// The first N entries of a buffer list, created on first use, e.g.
+// let [cols, net] = buffers(&mut work.matrices);
+pub fn buffers<T: Default, const N: usize>(list: &mut Vec<T>) -> &mut [T; N] {
+    if list.len() < N {
+        list.resize_with(N, T::default);
+    }
+    (&mut list[..N]).try_into().unwrap() // exactly N entries
+}
+
+fn matrix_bytes(m: &DMatrix) -> usize {
+    m.data.len() * std::mem::size_of::<FloatPrecision>()
+}
+
+// Moves weights into a sparse matrix if enough of them are zero, leaving
+// weights empty so that only one copy is kept
+fn sparsify_weights(weights: &mut DMatrix, sparse: &mut Option<SparseMatrix>, min_sparsity: FloatPrecision) -> bool {
+    if sparse.is_none() {
+        let zeros = weights.data.iter().filter(|&&w| w == 0.).count();
+        if zeros as FloatPrecision >= min_sparsity * weights.data.len() as FloatPrecision {
+            *sparse = Some(SparseMatrix::new(weights));
+            *weights = DMatrix::default();
+        }
+    }
+    sparse.is_some()
+}
+
+// Shape of the weights, which are empty once they are sparse
+fn weights_shape(weights: &DMatrix, sparse: &Option<SparseMatrix>) -> (usize, usize) {
+    sparse.as_ref().map_or(weights.shape, |sparse| sparse.shape)
+}
+
+fn identity(x: FloatPrecision) -> FloatPrecision {
+    x
+}
+
+// Buffers for the outputs and intermediate results of every layer of a model
+pub struct Scratch {
+    outs: Vec<DMatrix>,
+    work: Vec<Work>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        Self {
+            outs: Vec::new(),
+            work: Vec::new(),
+        }
+    }
+}
+
+impl Default for Scratch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::new());
+}
+
+// See NeuralNetwork::inference
+pub struct InferenceModel {
+    ops: Vec<Box<dyn Inference>>,
+}
+
+impl InferenceModel {
+    pub fn new(ops: Vec<Box<dyn Inference>>) -> Self {
+        if ops.is_empty() {
+            panic!("An inference model needs at least one layer.");
+        }
+        Self { ops }
+    }
+
+    // Output for a batch of inputs, one sample per column. This is the
+    // allocation free path: once the scratch has seen the batch size nothing
+    // is allocated.
+    pub fn predict_with<'s>(&self, input: &DMatrix, scratch: &'s mut Scratch) -> &'s DMatrix {
+        let n = self.ops.len();
+        scratch.outs.resize_with(n, || DMatrix::zeros((0, 0)));
+        scratch.work.resize_with(n, Work::default);
+        let (first, rest) = scratch.outs.split_at_mut(1);
+        self.ops[0].infer(input, &mut first[0], &mut scratch.work[0]);
+        let mut previous = &first[0];
+        for (i, out) in rest.iter_mut().enumerate() {
+            self.ops[i + 1].infer(previous, out, &mut scratch.work[i + 1]);
+            previous = out;
+        }
+        &scratch.outs[n - 1]
+    }
+
+    // Like predict_with, with a scratch that every thread keeps for itself.
+    // The output is copied into out, which is only reallocated if its shape changes.
+    pub fn predict(&self, input: &DMatrix, out: &mut DMatrix) {
+        SCRATCH.with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            let result = self.predict_with(input, &mut scratch);
+            fit(out, result.shape);
+            out.data.copy_from_slice(&result.data);
+        })
+    }
+
+    // Folds batch norms into the weights of neighbouring dense layers, or
+    // into the output pass of a convolution before them, and drops ops that
+    // only pass their input on. The results only differ by rounding.
+    pub fn fold(self) -> Self {
+        let mut ops: Vec<Box<dyn Inference>> = Vec::new();
+        for mut op in self.ops {
+            if op.is_identity() {
+                continue;
+            }
+            if let (Some(affine), Some(previous)) = (op.as_affine(), ops.last_mut()) {
+                if previous.fold_after(affine) {
+                    continue;
+                }
+            }
+            let folded = match ops.last().and_then(|previous| previous.as_affine()) {
+                Some(affine) => op.fold_before(affine),
+                None => false,
+            };
+            if folded {
+                ops.pop();
+            }
+            ops.push(op);
+        }
+        if ops.is_empty() {
+            ops.push(Box::new(Scale(1.)));
+        }
+        Self { ops }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.ops.iter().map(|op| op.bytes()).sum()
+    }
+
+    // Stores the weights of pruned dense and conv ops as sparse matrices.
+    // Below about 70% zeros the dense kernels are usually faster.
+    pub fn sparse(mut self, min_sparsity: FloatPrecision) -> Self {
+        for op in self.ops.iter_mut() {
+            op.sparsify(min_sparsity);
+        }
+        self
+    }
+
+    // Largest absolute value of the input of every op over all batches, e.g.
+    // to calibrate a quantized model
+    pub fn input_ranges(&self, batches: &[DMatrix]) -> Vec<FloatPrecision> {
+        let largest = |m: &DMatrix| m.data.iter().fold(0., |a: FloatPrecision, x| a.max(x.abs()));
+        let mut ranges: Vec<FloatPrecision> = vec![0.; self.ops.len()];
+        let mut scratch = Scratch::new();
+        for batch in batches.iter() {
+            self.predict_with(batch, &mut scratch);
+            ranges[0] = ranges[0].max(largest(batch));
+            for i in 1..self.ops.len() {
+                ranges[i] = ranges[i].max(largest(&scratch.outs[i - 1]));
+            }
+        }
+        ranges
+    }
+}
+
+// act(Wx + b), see Layer. Pointwise activations are applied in the same
+// pass as the product, others like softmax need net as a whole.
+pub struct Dense {
+    pub weights: DMatrix, // empty once sparse
+    pub bias: DMatrix,
+    pub activation: Box<dyn Activation>,
+    pointwise: Option<Pointwise>,
+    pub sparse: Option<SparseMatrix>, // used instead of weights, see InferenceModel::sparse
+}
+
+impl Dense {
+    pub fn new(weights: DMatrix, bias: DMatrix, activation: Box<dyn Activation>) -> Self {
+        Self {
+            weights,
+            bias,
+            pointwise: activation.pointwise(),
+            activation,
+            sparse: None,
+        }
+    }
+
+    // f(Wx + b) with the sparse weights if there are any
+    fn product(&self, input: &DMatrix, f: &dyn Fn(FloatPrecision) -> FloatPrecision, result: &mut DMatrix) {
+        match &self.sparse {
+            Some(sparse) => sparse.linm_map(input, &self.bias, f, result),
+            None => linm_map(&self.weights, input, &self.bias, f, result),
+        }
+    }
+}
+
+impl Inference for Dense {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let shape = (weights_shape(&self.weights, &self.sparse).0, input.shape.1);
+        fit(out, shape);
+        match &self.pointwise {
+            Some(f) => self.product(input, f, out),
+            None => {
+                let [net] = buffers(&mut work.matrices);
+                fit(net, shape);
+                self.product(input, &identity, net);
+                self.activation.forward(net, out);
+            }
+        }
+    }
+
+    // W(scale * x + shift) + b = (W * diag(scale)) x + (W * shift + b)
+    fn fold_before(&mut self, affine: &Affine) -> bool {
+        let (n, k) = self.weights.shape;
+        if self.sparse.is_some() || affine.scale.data.len() != k {
+            return false;
+        }
+        for i in 0..n {
+            for j in 0..k {
+                self.bias.data[i] += self.weights.data[i * k + j] * affine.shift.data[j];
+                self.weights.data[i * k + j] *= affine.scale.data[j];
+            }
+        }
+        true
+    }
+
+    // Only without activation, scale * (Wx + b) + shift = (diag(scale) * W) x + (scale * b + shift)
+    fn fold_after(&mut self, affine: &Affine) -> bool {
+        let (n, k) = self.weights.shape;
+        if self.sparse.is_some() || !self.activation.is_linear() || affine.scale.data.len() != n {
+            return false;
+        }
+        for i in 0..n {
+            let s = affine.scale.data[i];
+            self.weights.data[i * k..(i + 1) * k].iter_mut().for_each(|w| *w *= s);
+            self.bias.data[i] = s * self.bias.data[i] + affine.shift.data[i];
+        }
+        true
+    }
+
+    fn bytes(&self) -> usize {
+        let weights = matrix_bytes(&self.weights) + self.sparse.as_ref().map_or(0, |sparse| sparse.bytes());
+        weights + matrix_bytes(&self.bias)
+    }
+
+    fn sparsify(&mut self, min_sparsity: FloatPrecision) -> bool {
+        sparsify_weights(&mut self.weights, &mut self.sparse, min_sparsity)
+    }
+}
+
+// act(W * patches + b) for every position of the window, see Conv2d
+pub struct Convolution {
+    pub window: Window,
+    pub weights: DMatrix, // (out_channels, in_channels * kh * kw), empty once sparse
+    pub bias: DMatrix,
+    pub activation: Box<dyn Activation>,
+    pointwise: Option<Pointwise>,
+    // Folded batch norm on the output. Its factors differ per position, so
+    // they cannot go into the shared kernels and are applied while the
+    // product is copied into the output layout instead.
+    pub affine: Option<Affine>,
+    pub sparse: Option<SparseMatrix>,
+}
+
+impl Convolution {
+    pub fn new(window: Window, weights: DMatrix, bias: DMatrix, activation: Box<dyn Activation>) -> Self {
+        Self {
+            window,
+            weights,
+            bias,
+            pointwise: activation.pointwise(),
+            activation,
+            affine: None,
+            sparse: None,
+        }
+    }
+}
+
+impl Inference for Convolution {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (ho, wo) = self.window.output();
+        let positions = ho * wo;
+        let batch = input.shape.1;
+        let (channels, patch) = weights_shape(&self.weights, &self.sparse);
+        let m = batch * positions;
+        let [cols, product, net] = buffers(&mut work.matrices);
+        fit(cols, (patch, m));
+        fit(product, (channels, m));
+        fit(out, (channels * positions, batch));
+
+        for b in 0..batch {
+            im2col(&self.window, input, b, cols, b * positions);
+        }
+        match &self.sparse {
+            Some(sparse) => sparse.linm_map(cols, &self.bias, &identity, product),
+            None => linm(&self.weights, cols, &self.bias, product),
+        }
+
+        // (out_channels, batch * positions) -> (out_channels * positions, batch),
+        // applying the affine and a pointwise activation on the way
+        let target = match self.pointwise {
+            Some(_) => &mut *out,
+            None => {
+                fit(net, (channels * positions, batch));
+                &mut *net
+            }
+        };
+        for c in 0..channels {
+            for p in 0..positions {
+                let row = c * positions + p;
+                let (s, t) = match &self.affine {
+                    Some(affine) => (affine.scale.data[row], affine.shift.data[row]),
+                    None => (1., 0.),
+                };
+                for b in 0..batch {
+                    let x = s * product.data[c * m + b * positions + p] + t;
+                    target.data[row * batch + b] = match &self.pointwise {
+                        Some(f) => f(x),
+                        None => x,
+                    };
+                }
+            }
+        }
+        if self.pointwise.is_none() {
+            self.activation.forward(net, out);
+        }
+    }
+
+    fn fold_after(&mut self, affine: &Affine) -> bool {
+        let (ho, wo) = self.window.output();
+        if !self.activation.is_linear() || affine.scale.data.len() != self.bias.data.len() * ho * wo {
+            return false;
+        }
+        self.affine = Some(match self.affine.take() {
+            None => affine.clone(),
+            Some(inner) => Affine {
+                scale: DMatrix::new(inner.scale.data.iter().zip(affine.scale.data.iter()).map(|(a, s)| s * a).collect(), inner.scale.shape),
+                shift: DMatrix::new(
+                    (0..inner.shift.data.len()).map(|i| affine.scale.data[i] * inner.shift.data[i] + affine.shift.data[i]).collect(),
+                    inner.shift.shape,
+                ),
+            },
+        });
+        true
+    }
+
+    fn bytes(&self) -> usize {
+        let affine = self.affine.as_ref().map_or(0, |affine| affine.bytes());
+        let weights = matrix_bytes(&self.weights) + self.sparse.as_ref().map_or(0, |sparse| sparse.bytes());
+        weights + matrix_bytes(&self.bias) + affine
+    }
+
+    fn sparsify(&mut self, min_sparsity: FloatPrecision) -> bool {
+        sparsify_weights(&mut self.weights, &mut self.sparse, min_sparsity)
+    }
+}
+
+// Maximum or average of every window, see MaxPool2d and AvgPool2d
+pub struct Pool {
+    pub window: Window,
+    pub max: bool,
+}
+
+impl Inference for Pool {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (h, w) = self.window.input;
+        let (kh, kw) = self.window.kernel;
+        let (ho, wo) = self.window.output();
+        let batch = input.shape.1;
+        fit(out, (self.window.channels * ho * wo, batch));
+
+        let size = (kh * kw) as FloatPrecision;
+        for c in 0..self.window.channels {
+            for oy in 0..ho {
+                for ox in 0..wo {
+                    let row = (c * ho + oy) * wo + ox;
+                    for b in 0..batch {
+                        let mut result = if self.max { FloatPrecision::NEG_INFINITY } else { 0. };
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                if let Some((y, x)) = self.window.source(oy, ox, ky, kx) {
+                                    let value = input.data[(c * h * w + y * w + x) * batch + b];
+                                    result = if self.max { result.max(value) } else { result + value };
+                                }
+                            }
+                        }
+                        out.data[row * batch + b] = if self.max { result } else { result / size };
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Maximum or average of every channel, see GlobalMaxPool and GlobalAvgPool
+pub struct GlobalPool {
+    pub channels: usize,
+    pub size: usize, // pixels per channel
+    pub max: bool,
+}
+
+impl Inference for GlobalPool {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let batch = input.shape.1;
+        fit(out, (self.channels, batch));
+        for c in 0..self.channels {
+            for b in 0..batch {
+                let values = (0..self.size).map(|p| input.data[(c * self.size + p) * batch + b]);
+                out.data[c * batch + b] = if self.max {
+                    values.fold(FloatPrecision::NEG_INFINITY, FloatPrecision::max)
+                } else {
+                    values.sum::<FloatPrecision>() / self.size as FloatPrecision
+                };
+            }
+        }
+    }
+}
+
+// s * x, with s = 1 for layers like Flatten or inverted dropout that do
+// nothing at inference time
+pub struct Scale(pub FloatPrecision);
+
+impl Inference for Scale {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        fit(out, input.shape);
+        for i in 0..input.data.len() {
+            out.data[i] = self.0 * input.data[i];
+        }
+    }
+
+    fn is_identity(&self) -> bool {
+        self.0 == 1.
+    }
+}
+
+// Output row i is input row source[i], see Permute
+pub struct Gather {
+    pub source: Vec<usize>,
+}
+
+impl Inference for Gather {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let batch = input.shape.1;
+        fit(out, (self.source.len(), batch));
+        for (row, &source) in self.source.iter().enumerate() {
+            out.data[row * batch..(row + 1) * batch].copy_from_slice(&input.data[source * batch..(source + 1) * batch]);
+        }
+    }
+}
+
+// scale * x + shift with one factor per row, e.g. BatchNorm with its running statistics
+#[derive(Debug, Clone)]
+pub struct Affine {
+    pub scale: DMatrix,
+    pub shift: DMatrix,
+}
+
+impl Inference for Affine {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (n, m) = input.shape;
+        fit(out, input.shape);
+        for i in 0..n {
+            for j in 0..m {
+                let index = i * m + j;
+                out.data[index] = self.scale.data[i] * input.data[index] + self.shift.data[i];
+            }
+        }
+    }
+
+    fn as_affine(&self) -> Option<&Affine> {
+        Some(self)
+    }
+
+    fn bytes(&self) -> usize {
+        matrix_bytes(&self.scale) + matrix_bytes(&self.shift)
+    }
+}
+
+// gamma * x / std(x) + beta over the features of every sample, see LayerNorm
+// and, without subtracting the mean, RMSNorm
+pub struct Normalize {
+    pub gamma: DMatrix,
+    pub beta: DMatrix,
+    pub center: bool,
+    pub epsilon: FloatPrecision,
+}
+
+impl Inference for Normalize {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (n, m) = input.shape;
+        fit(out, input.shape);
+        for j in 0..m {
+            let mean = if self.center {
+                (0..n).map(|i| input.data[i * m + j]).sum::<FloatPrecision>() / n as FloatPrecision
+            } else {
+                0.
+            };
+            let var = (0..n).map(|i| (input.data[i * m + j] - mean).powi(2)).sum::<FloatPrecision>() / n as FloatPrecision;
+            let inv_std = 1. / (var + self.epsilon).sqrt();
+            for i in 0..n {
+                let index = i * m + j;
+                out.data[index] = self.gamma.data[i] * (input.data[index] - mean) * inv_std + self.beta.data[i];
+            }
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        matrix_bytes(&self.gamma) + matrix_bytes(&self.beta)
+    }
+}
+
+// The vectors of T ids per column, see Embedding
+pub struct Lookup {
+    pub weights: DMatrix,
+}
+
+impl Inference for Lookup {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (vocab_size, dim) = self.weights.shape;
+        let (steps, batch) = input.shape;
+        fit(out, (steps * dim, batch));
+        for t in 0..steps {
+            for b in 0..batch {
+                let x = input.data[t * batch + b];
+                let id = x as usize;
+                if x < 0. || x.fract() != 0. || id >= vocab_size {
+                    panic!("Embedding of {} ids got id {}.", vocab_size, x);
+                }
+                for d in 0..dim {
+                    out.data[(t * dim + d) * batch + b] = self.weights.data[id * dim + d];
+                }
+            }
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        matrix_bytes(&self.weights)
+    }
+}
+
+// Adds sinusoid(t, f) to feature f of step t, see SinusoidalEncoding
+pub struct Sinusoidal {
+    pub dim: usize,
+}
+
+impl Inference for Sinusoidal {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (rows, batch) = input.shape;
+        fit(out, input.shape);
+        for i in 0..rows {
+            let encoding = sinusoid(i / self.dim, i % self.dim, self.dim);
+            for j in 0..batch {
+                out.data[i * batch + j] = input.data[i * batch + j] + encoding;
+            }
+        }
+    }
+}
+
+// Self-attention over time major sequences, see MultiHeadAttention. The
+// projections work on tokens, one column per step and sample.
+pub struct Attention {
+    pub dim: usize,
+    pub heads: usize,
+    pub causal: bool,
+    pub query: Box<dyn Inference>,
+    pub key: Box<dyn Inference>,
+    pub value: Box<dyn Inference>,
+    pub output: Box<dyn Inference>,
+}
+
+impl Attention {
+    fn projections(&mut self) -> [&mut Box<dyn Inference>; 4] {
+        [&mut self.query, &mut self.key, &mut self.value, &mut self.output]
+    }
+}
+
+impl Inference for Attention {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (rows, batch) = input.shape;
+        let steps = rows / self.dim;
+        let size = self.dim / self.heads;
+        let [tokens, query, key, value, context, projected, q, k, v, head, weights] = buffers(&mut work.matrices);
+        let [wq, wk, wv, wo] = buffers(&mut work.nested);
+        fit(tokens, (self.dim, steps * batch));
+        fit(context, (self.dim, steps * batch));
+        for m in [&mut *q, &mut *k, &mut *v, &mut *head] {
+            fit(m, (size, steps));
+        }
+        fit(weights, (steps, steps));
+
+        to_tokens(input, self.dim, tokens);
+        self.query.infer(tokens, query, wq);
+        self.key.infer(tokens, key, wk);
+        self.value.infer(tokens, value, wv);
+        for b in 0..batch {
+            for h in 0..self.heads {
+                gather(query, h * size, b, q);
+                gather(key, h * size, b, k);
+                gather(value, h * size, b, v);
+                scaled_dot_product(q, k, v, self.causal, weights, head);
+                scatter(head, h * size, b, context);
+            }
+        }
+        self.output.infer(context, projected, wo);
+        fit(out, input.shape);
+        from_tokens(projected, self.dim, out);
+    }
+
+    fn bytes(&self) -> usize {
+        [&self.query, &self.key, &self.value, &self.output].iter().map(|op| op.bytes()).sum()
+    }
+
+    fn sparsify(&mut self, min_sparsity: FloatPrecision) -> bool {
+        self.projections().into_iter().fold(false, |any, op| op.sparsify(min_sparsity) | any)
+    }
+}
+
+// Post norm transformer block, see TransformerEncoder
+pub struct Encoder {
+    pub dim: usize,
+    pub attention: Attention,
+    pub norm1: Box<dyn Inference>,
+    pub hidden: Box<dyn Inference>,
+    pub projection: Box<dyn Inference>,
+    pub norm2: Box<dyn Inference>,
+}
+
+impl Inference for Encoder {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let tokens = (self.dim, input.shape.0 / self.dim * input.shape.1);
+        let [attended, residual, step, normalized, hidden, projected] = buffers(&mut work.matrices);
+        let [wa, w1, wh, wp, w2] = buffers(&mut work.nested);
+        fit(residual, tokens);
+        fit(step, tokens);
+
+        // x = LayerNorm(x + Attention(x))
+        self.attention.infer(input, attended, wa);
+        to_tokens(input, self.dim, residual);
+        to_tokens(attended, self.dim, step);
+        addm_assign(residual, step);
+        self.norm1.infer(residual, normalized, w1);
+
+        // out = LayerNorm(x + FFN(x))
+        self.hidden.infer(normalized, hidden, wh);
+        self.projection.infer(hidden, projected, wp);
+        addm(normalized, projected, residual);
+        self.norm2.infer(residual, step, w2);
+        fit(out, input.shape);
+        from_tokens(step, self.dim, out);
+    }
+
+    fn bytes(&self) -> usize {
+        self.attention.bytes() + self.norm1.bytes() + self.hidden.bytes() + self.projection.bytes() + self.norm2.bytes()
+    }
+
+    fn sparsify(&mut self, min_sparsity: FloatPrecision) -> bool {
+        let attention = self.attention.sparsify(min_sparsity);
+        let hidden = self.hidden.sparsify(min_sparsity);
+        self.projection.sparsify(min_sparsity) | hidden | attention
+    }
+}
+
+pub enum Cell {
+    Rnn(Box<dyn Activation>),
+    Lstm,
+    Gru,
+}
+
+// Rnn, Lstm or Gru over time major sequences. Every batch starts from a zero
+// state, stateful layers therefore behave as after reset_state.
+pub struct Recurrent {
+    pub cell: Cell,
+    pub weights: DMatrix,
+    pub recurrent: DMatrix,
+    pub bias: DMatrix,
+    pub input_size: usize,
+    pub hidden_size: usize,
+    pub return_sequences: bool,
+}
+
+impl Inference for Recurrent {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let batch = input.shape.1;
+        let steps = input.shape.0 / self.input_size;
+        let (hidden, gates) = (self.hidden_size, self.weights.shape.0);
+        let n = hidden * batch; // elements of one gate
+        let [x, z, u, h, c] = buffers(&mut work.matrices);
+        fit(x, (self.input_size, batch));
+        fit(z, (gates, batch));
+        fit(u, (gates, batch));
+        fit(h, (hidden, batch));
+        fit(c, (hidden, batch));
+        h.data.iter_mut().for_each(|v| *v = 0.);
+        c.data.iter_mut().for_each(|v| *v = 0.);
+        fit(out, (if self.return_sequences { steps * hidden } else { hidden }, batch));
+
+        for t in 0..steps {
+            get_step(input, t, x);
+            linm(&self.weights, x, &self.bias, z);
+            mulm(&self.recurrent, h, u);
+            match &self.cell {
+                Cell::Rnn(activation) => {
+                    for (a, b) in z.data.iter_mut().zip(u.data.iter()) {
+                        *a += b;
+                    }
+                    activation.forward(z, h);
+                }
+                Cell::Lstm => {
+                    for e in 0..n {
+                        let gate = |k: usize| z.data[k * n + e] + u.data[k * n + e];
+                        let (i, f, g, o) = (sigmoid(gate(0)), sigmoid(gate(1)), gate(2).tanh(), sigmoid(gate(3)));
+                        c.data[e] = f * c.data[e] + i * g;
+                        h.data[e] = o * c.data[e].tanh();
+                    }
+                }
+                Cell::Gru => {
+                    for e in 0..n {
+                        let r = sigmoid(z.data[e] + u.data[e]);
+                        let update = sigmoid(z.data[n + e] + u.data[n + e]);
+                        let candidate = (z.data[2 * n + e] + r * u.data[2 * n + e]).tanh();
+                        h.data[e] = (1. - update) * candidate + update * h.data[e];
+                    }
+                }
+            }
+            if self.return_sequences {
+                set_step(out, t, h);
+            }
+        }
+        if !self.return_sequences {
+            out.data.copy_from_slice(&h.data);
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        matrix_bytes(&self.weights) + matrix_bytes(&self.recurrent) + matrix_bytes(&self.bias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{LINEAR, RELU, SOFTMAX, TANH};
+    use crate::conv::Conv2d;
+    use crate::gradcheck::matrix;
+    use crate::layers::{Dropout, DropoutKind, Flatten, Layer};
+    use crate::losses::CrossEntropy;
+    use crate::models::NeuralNetwork;
+    use crate::normalization::BatchNorm;
+
+    // Trained for a few steps, so that the batch norm statistics are not the initial ones
+    fn mixed() -> NeuralNetwork {
+        let mut nn = NeuralNetwork::new(3).with_input_shape(&[1, 6, 6]).with_loss(CrossEntropy);
+        nn.add(Conv2d::new(1, 2, (6, 6), (3, 3), TANH, 0.1));
+        nn.add(BatchNorm::spatial(2, (4, 4), 0.1));
+        nn.add(Dropout::new(0.3, DropoutKind::Standard));
+        nn.add(Flatten::new());
+        nn.add(Layer::new(32, 8, TANH, 0.1));
+        nn.add(BatchNorm::new(8, 0.1));
+        nn.add(Layer::new(8, 3, SOFTMAX, 0.1));
+        let label = DMatrix::new(vec![1., 0., 0., 0., 1., 0., 0., 0., 1., 1., 0., 0.], (3, 4));
+        for seed in 0..5 {
+            nn.train(&matrix((36, 4), seed), &label);
+        }
+        nn
+    }
+
+    fn assert_close(a: &DMatrix, b: &DMatrix, tolerance: FloatPrecision) {
+        assert_eq!(a.shape, b.shape);
+        for (x, y) in a.data.iter().zip(b.data.iter()) {
+            assert!((x - y).abs() < tolerance, "{} vs {}", x, y);
+        }
+    }
+
+    #[test]
+    fn same_predictions() {
+        let mut nn = mixed();
+        let model = nn.inference();
+        let input = matrix((36, 3), 7);
+        let expected = nn.predict(&input).clone();
+
+        let mut out = DMatrix::default();
+        model.predict(&input, &mut out);
+        assert_close(&out, &expected, 1e-12);
+    }
+
+    // Trains nn for a few steps to move the batch norm statistics, then
+    // compares the folded model with the network
+    fn check_fold(mut nn: NeuralNetwork, input_size: usize, folded_len: usize) {
+        let label = DMatrix::new(vec![1., 0., 0., 0., 1., 0., 0., 0., 1., 1., 0., 0.], (3, 4));
+        for seed in 0..5 {
+            nn.train(&matrix((input_size, 4), seed), &label);
+        }
+        let folded = nn.inference().fold();
+        assert_eq!(folded.len(), folded_len);
+        let input = matrix((input_size, 3), 7);
+        let mut out = DMatrix::default();
+        folded.predict(&input, &mut out);
+        assert_close(&out, nn.predict(&input), 1e-9);
+    }
+
+    // Into the dense layer after the first batch norm and before the second
+    #[test]
+    fn fold_dense() {
+        let mut nn = NeuralNetwork::new(3).with_loss(CrossEntropy);
+        nn.add(BatchNorm::new(5, 0.1));
+        nn.add(Layer::new(5, 6, LINEAR, 0.1));
+        nn.add(BatchNorm::new(6, 0.1));
+        nn.add(Layer::new(6, 3, SOFTMAX, 0.1));
+        check_fold(nn, 5, 2);
+    }
+
+    // Into the output pass of the convolution, twice to compose the affine maps
+    #[test]
+    fn fold_conv() {
+        let mut nn = NeuralNetwork::new(3).with_input_shape(&[1, 5, 5]).with_loss(CrossEntropy);
+        nn.add(Conv2d::new(1, 2, (5, 5), (2, 2), LINEAR, 0.1));
+        nn.add(BatchNorm::spatial(2, (4, 4), 0.1));
+        nn.add(BatchNorm::spatial(2, (4, 4), 0.1));
+        nn.add(Flatten::new());
+        nn.add(Layer::new(32, 3, SOFTMAX, 0.1));
+        check_fold(nn, 25, 2);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        fn shared<T: Send + Sync>(model: &T) -> &T {
+            model
+        }
+        let mut nn = mixed();
+        let model = nn.inference();
+        let model = shared(&model);
+        let inputs: Vec<DMatrix> = (0..4).map(|seed| matrix((36, seed + 1), seed)).collect();
+        let expected: Vec<DMatrix> = inputs.iter().map(|input| nn.predict(input).clone()).collect();
+
+        std::thread::scope(|scope| {
+            for (input, expected) in inputs.iter().zip(expected.iter()) {
+                scope.spawn(move || {
+                    let mut out = DMatrix::default();
+                    for _ in 0..3 {
+                        model.predict(input, &mut out);
+                        assert_close(&out, expected, 1e-12);
+                    }
+                });
+            }
+        });
+    }
+
+    // Sparse weights replace the dense ones, give the same outputs and are
+    // the only copy that is counted
+    #[test]
+    fn sparse_dense() {
+        let weights = DMatrix::new(vec![0., 0.5, 0., 0., -1.5, 0., 0., 2., 0., 0., 0., 0.], (3, 4));
+        let bias = DMatrix::new(vec![0.1, -0.2, 0.3], (3, 1));
+        let input = DMatrix::new((0..8).map(|i| (i as FloatPrecision * 1.3).cos()).collect(), (4, 2));
+        let dense = Dense::new(weights.clone(), bias.clone(), RELU.boxed());
+        let mut sparse = Dense::new(weights, bias, RELU.boxed());
+        assert!(sparse.sparsify(0.7));
+        assert!(sparse.weights.data.is_empty());
+        assert!(sparse.bytes() < dense.bytes());
+        assert!(sparse.sparsify(0.7), "sparsifying again keeps the sparse weights");
+
+        let (mut expected, mut out, mut work) = (DMatrix::default(), DMatrix::default(), Work::default());
+        dense.infer(&input, &mut expected, &mut work);
+        sparse.infer(&input, &mut out, &mut work);
+        assert_eq!(out.shape, expected.shape);
+        for (o, e) in out.data.iter().zip(expected.data.iter()) {
+            assert!((o - e).abs() < 1e-12);
+        }
+    }
+}
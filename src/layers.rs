@@ -6,34 +6,156 @@ use crate::math::linm;
 use crate::math::mulm;
 use crate::math::naive_mulm;
 use crate::math::naive_mulm_assign;
+use crate::math::rowsum;
+use crate::math::mtmulm;
+use crate::math::saddm_assign;
 use crate::math::scale;
 use crate::math::smmulmt;
 use crate::math::DMatrix;
 
 use crate::activations::Activation;
-use crate::activations::mwrap;
+use crate::activations::SELU_ALPHA;
+use crate::activations::SELU_LAMBDA;
+use crate::inference;
+use crate::inference::Inference;
+use crate::quantization::Granularity;
+use crate::quantization::QuantizedDense;
+use crate::registry::Registry;
+use crate::regularization::{Constraint, Penalties, Regularizer};
+use crate::serialize::{read_activation, read_matrix, write_activation, write_matrix};
+use crate::tensor::Tensor;
 use rand::Rng;
+use std::collections::HashMap;
 use std::fmt;
+use std::io;
+use std::io::{Read, Write};
 use std::process::exit;
 
+// Anything that can be stacked in a NeuralNetwork. Like for Layer, every
+// column of the input and output is one sample and deltas are -dE/d(out),
+// so that gradient steps are added.
+pub trait Module {
+    fn forward(&mut self, input: &DMatrix);
+
+    // Computes the gradients of the module's parameters from the delta of its
+    // output and stores the delta of its input, see Module::delta.
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix);
+
+    // Gradient step with the gradients of the last backward
+    fn update(&mut self) {}
+
+    fn out(&self) -> &DMatrix;
+
+    fn delta(&self) -> &DMatrix; // -dE/d(input)
+
+    // Shape of one output sample for inputs of the given shape, e.g.
+    // [channels, height, width] for images. Most modules keep the shape.
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+
+    // Switches between training and inference behaviour, e.g. for dropout
+    fn set_training(&mut self, training: bool) {}
+
+    // Frozen modules keep their parameters. They still propagate deltas but
+    // skip the gradients of their parameters where that saves work.
+    fn set_trainable(&mut self, trainable: bool) {}
+
+    // Scales the steps of update, see NeuralNetwork::set_rate_multiplier. The
+    // gradients arrive scaled already, modules with weight decay or
+    // constraints scale those as well.
+    fn set_rate_multiplier(&mut self, multiplier: FloatPrecision) {}
+
+    // Writes everything that is learned or tracked while training, load
+    // reads it back into a module of the same architecture.
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The learned tensors with their gradients from the last backward
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        Vec::new()
+    }
+
+    // Regularization term that is added to the loss
+    fn penalty(&self) -> FloatPrecision {
+        0.
+    }
+
+    // Immutable copy of the inference mode forward pass, None for modules
+    // that have none yet. See NeuralNetwork::inference. It keeps no state
+    // between calls, so stateful recurrent layers start every sequence from zeros.
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        None
+    }
+
+    // Int8 version of the inference form for inputs within [-input_range,
+    // input_range], None if the module is not quantized
+    fn quantized(&self, input_range: FloatPrecision, granularity: Granularity) -> Option<Box<dyn Inference>> {
+        None
+    }
+}
+
+// A learned tensor of a module. Like the deltas its gradient is
+// -dE/d(value), so that gradient steps are added.
+pub struct Parameter<'a> {
+    pub name: String,
+    pub value: &'a mut DMatrix,
+    pub grad: &'a mut DMatrix,
+    // If set, row k of grad belongs to row rows[k] of value and all other rows have no gradient
+    pub rows: Option<&'a [usize]>,
+    // Weight matrices that magnitude pruning may set to zero, see pruning::is_prunable
+    pub prunable: bool,
+}
+
+impl<'a> Parameter<'a> {
+    pub fn new(name: &str, value: &'a mut DMatrix, grad: &'a mut DMatrix) -> Self {
+        Self {
+            name: name.to_string(),
+            value,
+            grad,
+            rows: None,
+            prunable: false,
+        }
+    }
+
+    pub fn prunable(mut self) -> Self {
+        self.prunable = true;
+        self
+    }
+
+    // Names the parameters of a submodule, e.g. "query.weights"
+    pub fn prefixed(mut self, prefix: &str) -> Self {
+        self.name = format!("{}.{}", prefix, self.name);
+        self
+    }
+}
+
 // A layer that is densly connected with the previous one
 pub struct Layer {
     input_size: usize,
     output_size: usize,
     pub weights: DMatrix,
     pub bias: DMatrix,
-    pub activation: Activation,
+    pub activation: Box<dyn Activation>,
     pub net: DMatrix,
     pub out: DMatrix,
     pub delta: DMatrix,
-    fdnet: DMatrix,
+    input_delta: DMatrix,
     pub dw: DMatrix,
     pub db: DMatrix,
-    rate: FloatPrecision
+    pub penalties: Penalties,
+    trainable: bool,
+    rate: FloatPrecision,
+    rate_multiplier: FloatPrecision,
 }
 
 impl Layer {
-    pub fn new(input_size: usize, output_size: usize, activation: Activation, rate: FloatPrecision) -> Self {
+    pub fn new<A: Activation + 'static>(input_size: usize, output_size: usize, activation: A, rate: FloatPrecision) -> Self {
         let mut rng = rand::thread_rng();
         let weights_data:Vec<FloatPrecision> = (0..output_size*input_size).map(|_| rng.gen_range(-0.5..0.5)).collect();
         let bias_data:Vec<FloatPrecision> = (0..output_size).map(|_| rng.gen_range(-0.5..0.5)).collect();
@@ -45,29 +167,610 @@ impl Layer {
             output_size,
             weights,
             bias,
-            activation,
+            activation: Box::new(activation),
             net: DMatrix::new(vec![0.;output_size], (output_size, 1)),
             out: DMatrix::new(vec![0.;output_size], (output_size, 1)),
-            fdnet: DMatrix::new(vec![0.;output_size], (output_size, 1)),
             delta: DMatrix::new(vec![0.;output_size], (output_size, 1)),
+            input_delta: DMatrix::new(vec![0.;input_size], (input_size, 1)),
             dw: DMatrix::new(vec![0.;output_size*input_size], (output_size, input_size)),
             db: DMatrix::new(vec![0.;output_size], (output_size, 1)),
-            rate
+            penalties: Penalties::default(),
+            trainable: true,
+            rate,
+            rate_multiplier: 1.,
         }
     }
 
+    pub fn with_kernel_regularizer(mut self, regularizer: Regularizer) -> Self {
+        self.penalties.kernel = Some(regularizer);
+        self
+    }
+
+    pub fn with_bias_regularizer(mut self, regularizer: Regularizer) -> Self {
+        self.penalties.bias = Some(regularizer);
+        self
+    }
+
+    pub fn with_weight_decay(mut self, weight_decay: FloatPrecision) -> Self {
+        self.penalties.weight_decay = weight_decay;
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: Constraint) -> Self {
+        self.penalties.constraint = Some(constraint);
+        self
+    }
+
     pub fn forward(&mut self, input: &DMatrix) {
+        let batch = input.shape.1;
+        if self.net.shape.1 != batch {
+            self.net = DMatrix::zeros((self.output_size, batch));
+            self.out = DMatrix::zeros((self.output_size, batch));
+            self.delta = DMatrix::zeros((self.output_size, batch));
+            self.input_delta = DMatrix::zeros((self.input_size, batch));
+        }
         linm(&self.weights, input, &self.bias, &mut self.net); // Wx+b
-        mwrap(self.activation.f, &self.net, &mut self.out); // s(Wx+b)
+        self.activation.forward(&self.net, &mut self.out); // s(Wx+b)
     }
 
     pub fn backward(&mut self, input: &DMatrix) {
-        mwrap(self.activation.fd, &self.net, &mut self.fdnet); // f'(net)
-        naive_mulm_assign(&mut self.delta, &self.fdnet); // dE * f'(net)
-        smmulmt(self.rate, &self.delta, input, &mut self.dw); // dW = rate * delta * inputT
-        scale(self.rate, &self.delta, &mut self.db); // db = rate * delta
+        self.gradients(input);
+        self.update();
+    }
+
+    fn gradients(&mut self, input: &DMatrix) {
+        self.activation.backward(&self.net, &self.out, &mut self.delta); // dE * f'(net)
+        if !self.trainable {
+            return;
+        }
+        smmulmt(1., &self.delta, input, &mut self.dw); // dW = delta * inputT
+        rowsum(&self.delta, &mut self.db); // db = delta, summed over the batch
+        self.penalties.gradients(&self.weights, &self.bias, &mut self.dw, &mut self.db);
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(self.rate, &mut self.weights, &self.dw);
+        saddm_assign(self.rate, &mut self.bias, &self.db);
+        self.penalties.update(self.rate * self.rate_multiplier, &mut self.weights);
+        self.activation.update(self.rate);
+    }
+}
+
+impl Module for Layer {
+    fn forward(&mut self, input: &DMatrix) {
+        Layer::forward(self, input);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        self.delta.data.copy_from_slice(&delta.data);
+        self.gradients(input);
+        mtmulm(&self.weights, &self.delta, &mut self.input_delta);
+    }
+
+    fn update(&mut self) {
+        Layer::update(self);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn set_rate_multiplier(&mut self, multiplier: FloatPrecision) {
+        self.rate_multiplier = multiplier;
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape.iter().product::<usize>() != self.input_size {
+            panic!("Layer expects {} inputs, got shape {:?}.", self.input_size, input_shape);
+        }
+        vec![self.output_size]
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.weights)?;
+        write_matrix(w, &self.bias)?;
+        write_activation(w, &*self.activation)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.weights)?;
+        read_matrix(r, &mut self.bias)?;
+        read_activation(r, &mut self.activation, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = vec![
+            Parameter::new("weights", &mut self.weights, &mut self.dw).prunable(),
+            Parameter::new("bias", &mut self.bias, &mut self.db),
+        ];
+        params.extend(self.activation.parameters().into_iter().map(|p| p.prefixed("activation")));
+        params
+    }
+
+    fn penalty(&self) -> FloatPrecision {
+        self.penalties.penalty(&self.weights, &self.bias)
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Dense::new(self.weights.clone(), self.bias.clone(), self.activation.boxed())))
+    }
+
+    fn quantized(&self, input_range: FloatPrecision, granularity: Granularity) -> Option<Box<dyn Inference>> {
+        Some(Box::new(QuantizedDense::new(
+            &self.weights,
+            &self.bias,
+            self.activation.boxed(),
+            input_range,
+            granularity,
+        )))
+    }
+}
+
+pub enum DropoutKind {
+    // Scales the outputs by 1 - p at inference time
+    Standard,
+    // Scales the kept outputs by 1 / (1 - p) while training, so inference is the identity
+    Inverted,
+    // Keeps mean and variance of SELU activations, see Klambauer et al., 2017
+    Alpha,
+}
+
+// Randomly sets a fraction p of its inputs to zero while training
+pub struct Dropout {
+    p: FloatPrecision,
+    kind: DropoutKind,
+    training: bool,
+    mask: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl Dropout {
+    pub fn new(p: FloatPrecision, kind: DropoutKind) -> Self {
+        if !(0. ..1.).contains(&p) {
+            panic!("Dropout probability must be in [0, 1), got {p}.");
+        }
+        Self {
+            p,
+            kind,
+            training: true,
+            mask: DMatrix::zeros((0, 0)),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    // Affine correction a * x + b of alpha dropout and the value dropped units are set to
+    fn alpha_params(&self) -> (FloatPrecision, FloatPrecision, FloatPrecision) {
+        let alpha = -SELU_LAMBDA * SELU_ALPHA; // SELU's lower bound
+        let q = 1. - self.p;
+        let a = 1. / (q + alpha * alpha * q * self.p).sqrt();
+        let b = -a * alpha * self.p;
+        (a, b, alpha)
+    }
+}
+
+impl Module for Dropout {
+    fn forward(&mut self, input: &DMatrix) {
+        if self.out.shape != input.shape {
+            self.mask = DMatrix::zeros(input.shape);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        let q = 1. - self.p;
+        if !self.training {
+            match self.kind {
+                DropoutKind::Standard => scale(q, input, &mut self.out),
+                DropoutKind::Inverted | DropoutKind::Alpha => self.out.data.copy_from_slice(&input.data),
+            }
+            return;
+        }
+
+        let mut rng = rand::thread_rng();
+        for i in 0..self.mask.data.len() {
+            self.mask.data[i] = if rng.gen::<FloatPrecision>() < self.p { 0. } else { 1. };
+        }
+        match self.kind {
+            DropoutKind::Standard => naive_mulm(input, &self.mask, &mut self.out),
+            DropoutKind::Inverted => {
+                for i in 0..input.data.len() {
+                    self.out.data[i] = input.data[i] * self.mask.data[i] / q;
+                }
+            }
+            DropoutKind::Alpha => {
+                let (a, b, alpha) = self.alpha_params();
+                for i in 0..input.data.len() {
+                    let kept = self.mask.data[i];
+                    self.out.data[i] = a * (input.data[i] * kept + alpha * (1. - kept)) + b;
+                }
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let s = match self.kind {
+            DropoutKind::Standard => 1.,
+            DropoutKind::Inverted => 1. / (1. - self.p),
+            DropoutKind::Alpha => self.alpha_params().0,
+        };
+        for i in 0..delta.data.len() {
+            self.input_delta.data[i] = s * self.mask.data[i] * delta.data[i];
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        match self.kind {
+            DropoutKind::Standard => Some(Box::new(inference::Scale(1. - self.p))),
+            DropoutKind::Inverted | DropoutKind::Alpha => Some(Box::new(inference::Scale(1.))),
+        }
+    }
+}
+
+// Turns images or sequences into plain feature vectors. Samples are already
+// stored flat in their column, so only the shape changes.
+pub struct Flatten {
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
 
-        addm_assign(&mut self.weights, &self.dw);
-        addm_assign(&mut self.bias, &self.db);
+impl Flatten {
+    pub fn new() -> Self {
+        Self {
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for Flatten {
+    fn forward(&mut self, input: &DMatrix) {
+        self.out.clone_from(input);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        self.input_delta.clone_from(delta);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape.iter().product()]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Scale(1.)))
+    }
+}
+
+// Gives every sample a new shape with the same number of elements
+pub struct Reshape {
+    shape: Vec<usize>,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl Reshape {
+    pub fn new(shape: &[usize]) -> Self {
+        Self {
+            shape: shape.to_vec(),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for Reshape {
+    fn forward(&mut self, input: &DMatrix) {
+        if input.shape.0 != self.shape.iter().product::<usize>() {
+            panic!("Cannot reshape samples of {} elements into {:?}.", input.shape.0, self.shape);
+        }
+        self.out.clone_from(input);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        self.input_delta.clone_from(delta);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape.iter().product::<usize>() != self.shape.iter().product::<usize>() {
+            panic!("Cannot reshape {:?} into {:?}.", input_shape, self.shape);
+        }
+        self.shape.clone()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Scale(1.)))
+    }
+}
+
+// Reorders the axes of every sample, e.g. [1, 2, 0] turns channel first
+// images of shape [c, h, w] into channel last ones of shape [h, w, c].
+pub struct Permute {
+    input_shape: Vec<usize>,
+    axes: Vec<usize>,
+    source: Vec<usize>, // input row of every output row
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl Permute {
+    pub fn new(input_shape: &[usize], axes: &[usize]) -> Self {
+        let n: usize = input_shape.iter().product();
+        let rows = Tensor::new((0..n).map(|i| i as FloatPrecision).collect(), input_shape);
+        let source = rows.permute(axes).contiguous().data.iter().map(|&i| i as usize).collect();
+        Self {
+            input_shape: input_shape.to_vec(),
+            axes: axes.to_vec(),
+            source,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for Permute {
+    fn forward(&mut self, input: &DMatrix) {
+        let batch = input.shape.1;
+        if self.out.shape != input.shape {
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for (row, &source) in self.source.iter().enumerate() {
+            for b in 0..batch {
+                self.out.data[row * batch + b] = input.data[source * batch + b];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        for (row, &source) in self.source.iter().enumerate() {
+            for b in 0..batch {
+                self.input_delta.data[source * batch + b] = delta.data[row * batch + b];
+            }
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape != self.input_shape.as_slice() {
+            panic!("Permute expects inputs of shape {:?}, got {:?}.", self.input_shape, input_shape);
+        }
+        self.axes.iter().map(|&a| self.input_shape[a]).collect()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Gather { source: self.source.clone() }))
+    }
+}
+
+// Maps integer ids to learned vectors. Every input column holds a sequence
+// of T ids, the output column the T vectors one after another, like the
+// sequences of the recurrent layers.
+pub struct Embedding {
+    vocab_size: usize,
+    dim: usize,
+    pub weights: DMatrix, // row i is the vector of id i
+    // Gradient only for the ids of the last batch, row k belongs to used[k]
+    pub used: Vec<usize>,
+    pub dw: DMatrix,
+    rows: HashMap<usize, usize>, // row in dw of every used id, kept to reuse its memory
+    ids: Vec<usize>,
+    trainable: bool,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl Embedding {
+    pub fn new(vocab_size: usize, dim: usize, rate: FloatPrecision) -> Self {
+        let mut rng = rand::thread_rng();
+        let weights_data: Vec<FloatPrecision> = (0..vocab_size * dim).map(|_| rng.gen_range(-0.5..0.5)).collect();
+        Self {
+            vocab_size,
+            dim,
+            weights: DMatrix::new(weights_data, (vocab_size, dim)),
+            used: Vec::new(),
+            dw: DMatrix::zeros((0, dim)),
+            rows: HashMap::new(),
+            ids: Vec::new(),
+            trainable: true,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+            rate,
+        }
+    }
+}
+
+impl Module for Embedding {
+    fn forward(&mut self, input: &DMatrix) {
+        let (steps, batch) = input.shape;
+        if self.out.shape != (steps * self.dim, batch) {
+            self.out = DMatrix::zeros((steps * self.dim, batch));
+            self.input_delta = DMatrix::zeros(input.shape); // ids have no gradient
+        }
+        self.ids.clear();
+        for &x in input.data.iter() {
+            let id = x as usize;
+            if x < 0. || x.fract() != 0. || id >= self.vocab_size {
+                panic!("Embedding of {} ids got id {}.", self.vocab_size, x);
+            }
+            self.ids.push(id);
+        }
+        for t in 0..steps {
+            for b in 0..batch {
+                let id = self.ids[t * batch + b];
+                for d in 0..self.dim {
+                    self.out.data[(t * self.dim + d) * batch + b] = self.weights.data[id * self.dim + d];
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        if !self.trainable {
+            return;
+        }
+        let (steps, batch) = input.shape;
+        self.rows.clear();
+        self.used.clear();
+        for &id in self.ids.iter() {
+            if !self.rows.contains_key(&id) {
+                self.rows.insert(id, self.used.len());
+                self.used.push(id);
+            }
+        }
+        // Clearing keeps the memory of earlier batches
+        self.dw.data.clear();
+        self.dw.data.resize(self.used.len() * self.dim, 0.);
+        self.dw.shape = (self.used.len(), self.dim);
+        for t in 0..steps {
+            for b in 0..batch {
+                let k = self.rows[&self.ids[t * batch + b]];
+                for d in 0..self.dim {
+                    self.dw.data[k * self.dim + d] += delta.data[(t * self.dim + d) * batch + b];
+                }
+            }
+        }
+    }
+
+    // Only touches the rows of ids that were in the batch
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        for (k, &id) in self.used.iter().enumerate() {
+            for d in 0..self.dim {
+                self.weights.data[id * self.dim + d] += self.rate * self.dw.data[k * self.dim + d];
+            }
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape.iter().product(), self.dim]
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.weights)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.weights)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut weights = Parameter::new("weights", &mut self.weights, &mut self.dw);
+        weights.rows = Some(&self.used);
+        vec![weights]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Lookup { weights: self.weights.clone() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{SOFTMAX, TANH};
+    use crate::gradcheck::{assert_passed, check_module, check_parameters, matrix};
+    use crate::losses::{CrossEntropy, Mse};
+
+    // One hot labels for 3 samples
+    fn classes(n: usize) -> DMatrix {
+        let mut label = DMatrix::zeros((n, 3));
+        for b in 0..3 {
+            label.data[(b % n) * 3 + b] = 1.;
+        }
+        label
+    }
+
+    #[test]
+    fn dense() {
+        let checks = check_module(&mut Layer::new(4, 3, TANH, 0.1), &Mse, &matrix((4, 3), 1), &matrix((3, 3), 2));
+        assert_eq!(checks.len(), 3);
+        assert_passed(&checks);
+
+        let checks = check_module(&mut Layer::new(4, 3, SOFTMAX, 0.1), &CrossEntropy, &matrix((4, 3), 1), &classes(3));
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn embedding() {
+        let ids = DMatrix::new(vec![0., 3., 1., 3., 2., 0.], (2, 3));
+        let checks = check_parameters(&mut Embedding::new(5, 4, 0.1), &Mse, &ids, &matrix((8, 3), 2));
+        assert_passed(&checks);
+    }
+
+    // The gradient buffers are reused, a batch with other ids leaves no rows behind
+    #[test]
+    fn embedding_batches() {
+        let mut embedding = Embedding::new(5, 4, 0.1);
+        let ids = DMatrix::new(vec![0., 1., 2., 3., 4., 0.], (2, 3));
+        embedding.forward(&ids);
+        embedding.backward(&ids, &matrix((8, 3), 1));
+        assert_eq!(embedding.dw.shape, (5, 4));
+
+        let ids = DMatrix::new(vec![3., 1., 3., 3.], (2, 2));
+        let checks = check_parameters(&mut embedding, &Mse, &ids, &matrix((8, 2), 2));
+        assert_eq!(embedding.used, [3, 1]);
+        assert_eq!(embedding.dw.shape, (2, 4));
+        assert_passed(&checks);
+    }
+}
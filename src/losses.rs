@@ -0,0 +1,62 @@
+use crate::constants::FloatPrecision;
+use crate::math::ssubm;
+use crate::math::DMatrix;
+
+// Error of the output of a model for a label, one sample per column
+pub trait Loss {
+    fn loss(&self, out: &DMatrix, label: &DMatrix) -> FloatPrecision;
+
+    // -dE/d(out), the delta the last layer's backward is fed with
+    fn delta(&self, out: &DMatrix, label: &DMatrix, delta: &mut DMatrix);
+}
+
+// E = sum((out - label)^2) / 2n over all n entries, so that the delta is
+// simply (label - out) / n
+pub struct Mse;
+
+impl Loss for Mse {
+    fn loss(&self, out: &DMatrix, label: &DMatrix) -> FloatPrecision {
+        let n = label.data.len() as FloatPrecision;
+        out.data.iter().zip(label.data.iter()).map(|(o, t)| (o - t) * (o - t)).sum::<FloatPrecision>() / (2. * n)
+    }
+
+    fn delta(&self, out: &DMatrix, label: &DMatrix, delta: &mut DMatrix) {
+        ssubm(1. / label.data.len() as FloatPrecision, label, out, delta);
+    }
+}
+
+// E = -sum(label * ln(out)) / batch for outputs that are probabilities, e.g. of SOFTMAX
+pub struct CrossEntropy;
+
+// Keeps ln away from 0 for outputs that saturated
+const TINY: FloatPrecision = 1e-12;
+
+impl Loss for CrossEntropy {
+    fn loss(&self, out: &DMatrix, label: &DMatrix) -> FloatPrecision {
+        let batch = label.shape.1 as FloatPrecision;
+        -out.data.iter().zip(label.data.iter()).map(|(o, t)| t * o.max(TINY).ln()).sum::<FloatPrecision>() / batch
+    }
+
+    fn delta(&self, out: &DMatrix, label: &DMatrix, delta: &mut DMatrix) {
+        let batch = label.shape.1 as FloatPrecision;
+        for i in 0..delta.data.len() {
+            delta.data[i] = label.data[i] / out.data[i].max(TINY) / batch;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::check_loss;
+
+    #[test]
+    fn deltas() {
+        let out = DMatrix::new(vec![0.2, 0.5, 0.7, 0.3, 0.1, 0.2], (3, 2));
+        let label = DMatrix::new(vec![0., 1., 1., 0., 0., 0.], (3, 2));
+        for (name, loss) in [("mse", &Mse as &dyn Loss), ("cross entropy", &CrossEntropy)] {
+            let check = check_loss(loss, &out, &label);
+            assert!(check.passed(1e-7), "{}: {}", name, check);
+        }
+    }
+}
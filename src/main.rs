@@ -5,14 +5,31 @@ use std::fs;
 use std::io;
 use std::io::Write;
 use std::process::exit;
+use std::thread;
 
 mod activations;
+mod attention;
+mod autodiff;
+mod clipping;
 mod constants;
+mod gradcheck;
+mod inference;
+mod conv;
 mod layers;
 mod load;
+mod losses;
 mod math;
 mod models;
+mod normalization;
 mod plot;
+mod pooling;
+mod pruning;
+mod quantization;
+mod recurrent;
+mod regularization;
+mod registry;
+mod serialize;
+mod tensor;
 
 #[macro_use]
 mod macros;
@@ -32,9 +49,21 @@ use math::argmax;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
+use crate::conv::Conv2d;
+use crate::layers::Dropout;
+use crate::layers::DropoutKind;
+use crate::layers::Flatten;
 use crate::layers::Layer;
 
+use crate::inference::InferenceModel;
+use crate::inference::Scratch;
 use crate::math::DMatrix;
+use crate::normalization::BatchNorm;
+use crate::pooling::MaxPool2d;
+use crate::pruning::Schedule;
+use crate::pruning::Scope;
+use crate::quantization::Granularity;
+use crate::recurrent::Lstm;
 
 use crate::plot::plot;
 
@@ -58,7 +87,7 @@ fn main() {
     let mean = n * 0.05 / 2.;
     let var = heights.iter().map(|x| (x - mean) * (x - mean)).sum::<FloatPrecision>() / (n - 1.);
 
-    let mut inputs: Vec<DMatrix> = steps
+    let inputs: Vec<DMatrix> = steps
         .iter()
         .map(|&x| DMatrix::new(vec![(x - mean) / var], (1, 1)))
         .collect();
@@ -86,19 +115,25 @@ fn main() {
         (min, max),
     );
 
-    let mut nn = models::NeuralNetwork5::new(
-        Layer::new(1, 128, activations::LEAKYRELU, 0.0001),
-        Layer::new(128, 256, activations::LEAKYRELU, 0.0001),
-        Layer::new(256, 512, activations::LEAKYRELU, 0.0001),
-        Layer::new(512, 512, activations::LEAKYRELU, 0.0001),
-        Layer::new(512, 1, activations::LINEAR, 0.0001),
-        1,
-    );
+    let mut nn = models::NeuralNetwork::new(1);
+    nn.add(Layer::new(1, 128, activations::LEAKYRELU, 0.0001));
+    nn.add(BatchNorm::new(128, 0.0001));
+    nn.add(Layer::new(128, 256, activations::LEAKYRELU, 0.0001));
+    nn.add(BatchNorm::new(256, 0.0001));
+    nn.add(Layer::new(256, 512, activations::LEAKYRELU, 0.0001));
+    nn.add(Dropout::new(0.2, DropoutKind::Inverted));
+    nn.add(Layer::new(512, 512, activations::LEAKYRELU, 0.0001));
+    nn.add(Dropout::new(0.2, DropoutKind::Inverted));
+    nn.add(Layer::new(512, 1, activations::LINEAR, 0.0001));
     let mut rng = thread_rng();
-    inputs.shuffle(&mut rng);
-    for i in 0..inputs.len() {
-        nn.train(&inputs[i], &labels[i]);
-        loading(i, inputs.len(), 10);
+    let mut order: Vec<usize> = (0..inputs.len()).collect();
+    order.shuffle(&mut rng);
+    let batches = order.chunks(32).collect::<Vec<_>>();
+    for (i, batch) in batches.iter().enumerate() {
+        let input = DMatrix::hstack(&batch.iter().map(|&k| &inputs[k]).collect::<Vec<_>>());
+        let label = DMatrix::hstack(&batch.iter().map(|&k| &labels[k]).collect::<Vec<_>>());
+        nn.train(&input, &label);
+        loading(i, batches.len(), 10);
     }
 
     let xs: Vec<FloatPrecision> = (0..8192 * 2)
@@ -157,7 +192,7 @@ fn main2() {
 
     let mut nn = models::NeuralNetwork2::new(
         Layer::new(784, 32, activations::SIGMOID, 0.1),
-        Layer::new(32, 10, activations::SIGMOID, 0.1),
+        Layer::new(32, 10, activations::SOFTMAX, 0.1),
         //Layer::new(10, 10, activations::SIGMOID, 0.1),
         10,
     );
@@ -230,6 +265,241 @@ fn main2() {
     );
 }
 
+// Training and test samples of an MNIST-like data set, images scaled around 0 with one hot labels
+fn mnist_samples(path: &str) -> (Vec<(DMatrix, DMatrix)>, Vec<(DMatrix, DMatrix)>) {
+    let mnist = Mnist::new(path);
+    let to_sample = |image: &[u8; 784], label: u8| {
+        let image_data: Vec<FloatPrecision> = image.iter().map(|&x| ((x as FloatPrecision) - 128.) / 255.).collect();
+        (DMatrix::new(image_data, (784, 1)), DMatrix::new(one_hot(label as usize), (10, 1)))
+    };
+    let training_data = (0..mnist.train_data.len())
+        .map(|i| to_sample(&mnist.train_data[i], mnist.train_labels[i]))
+        .collect();
+    let test_data = (0..mnist.test_data.len())
+        .map(|i| to_sample(&mnist.test_data[i], mnist.test_labels[i]))
+        .collect();
+    (training_data, test_data)
+}
+
+fn mnist_cnn() -> models::NeuralNetwork {
+    let mut nn = models::NeuralNetwork::new(10).with_input_shape(&[1, 28, 28]);
+    nn.add(Conv2d::new(1, 8, (28, 28), (3, 3), activations::RELU, 0.05).with_padding((1, 1)));
+    nn.add(MaxPool2d::new(8, (28, 28), (2, 2)));
+    nn.add(Conv2d::new(8, 16, (14, 14), (3, 3), activations::RELU, 0.05).with_padding((1, 1)));
+    nn.add(MaxPool2d::new(16, (14, 14), (2, 2)));
+    nn.add(Flatten::new());
+    nn.add(Layer::new(nn.output_size(), 10, activations::SOFTMAX, 0.05));
+    nn
+}
+
+// One epoch in shuffled mini-batches of 32
+fn train_epoch(nn: &mut models::NeuralNetwork, training_data: &mut [(DMatrix, DMatrix)]) {
+    let mut rng = thread_rng();
+    training_data.shuffle(&mut rng);
+    let batches: Vec<&[(DMatrix, DMatrix)]> = training_data.chunks(32).collect();
+    for (i, batch) in batches.iter().enumerate() {
+        let images = DMatrix::hstack(&batch.iter().map(|(image, _)| image).collect::<Vec<_>>());
+        let labels = DMatrix::hstack(&batch.iter().map(|(_, label)| label).collect::<Vec<_>>());
+        nn.train(&images, &labels);
+        loading(i, batches.len(), 10);
+    }
+}
+
+// Row of the largest entry of column j
+fn column_argmax(m: &DMatrix, j: usize) -> usize {
+    let (n, k) = m.shape;
+    (0..n).max_by(|&a, &b| m.data[a * k + j].partial_cmp(&m.data[b * k + j]).unwrap()).unwrap()
+}
+
+// Tests in batches of 100 on 4 threads that share one inference model
+fn test_accuracy(model: &InferenceModel, test_data: &[(DMatrix, DMatrix)]) -> FloatPrecision {
+    let part = (test_data.len() + 3) / 4;
+    let correct: usize = thread::scope(|s| {
+        let threads: Vec<_> = test_data
+            .chunks(part)
+            .map(|samples| {
+                s.spawn(move || {
+                    let mut scratch = Scratch::new();
+                    let mut correct = 0;
+                    for batch in samples.chunks(100) {
+                        let images = DMatrix::hstack(&batch.iter().map(|(image, _)| image).collect::<Vec<_>>());
+                        let labels = DMatrix::hstack(&batch.iter().map(|(_, label)| label).collect::<Vec<_>>());
+                        let prediction = model.predict_with(&images, &mut scratch);
+                        correct += (0..batch.len()).filter(|&j| column_argmax(prediction, j) == column_argmax(&labels, j)).count();
+                    }
+                    correct
+                })
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).sum()
+    });
+    correct as FloatPrecision * 100. / (test_data.len() as FloatPrecision)
+}
+
+// A small CNN on the same MNIST data as main2
+fn main3() {
+    println!("Reading data ....");
+    let (mut training_data, test_data) = mnist_samples("C:/users/antga/documents/uni/neuralnets/MNIST/");
+    let mut nn = mnist_cnn();
+
+    println!("Starting to train ...");
+    train_epoch(&mut nn, &mut training_data);
+
+    println!("\nStarting to test ...");
+    println!("\nPercentage correct: {}", test_accuracy(&nn.inference().fold(), &test_data));
+    nn.save("mnist_cnn.nn").expect("Could not save the network.");
+}
+
+// Fine-tunes the CNN of main3 on Fashion-MNIST: the convolutions stay as
+// learned on digits and only a new head is trained
+fn main5() {
+    println!("Reading data ....");
+    let (mut training_data, test_data) = mnist_samples("C:/users/antga/documents/uni/neuralnets/FashionMNIST/");
+    let mut nn = mnist_cnn();
+    nn.load("mnist_cnn.nn").expect("Train and save the network with main3 first.");
+
+    nn.pop();
+    nn.add(Layer::new(nn.output_size(), 32, activations::RELU, 0.05));
+    nn.add(Layer::new(32, 10, activations::SOFTMAX, 0.05));
+    for layer in 0..4 {
+        nn.set_trainable(layer, false);
+    }
+
+    println!("Starting to train ...");
+    train_epoch(&mut nn, &mut training_data);
+
+    println!("\nStarting to test ...");
+    println!("\nPercentage correct: {}", test_accuracy(&nn.inference().fold(), &test_data));
+}
+
+// Accuracy of the int8 versions of a model against the float one, with the
+// input ranges calibrated on the first 1000 training samples
+fn report_quantization(nn: &models::NeuralNetwork, training_data: &[(DMatrix, DMatrix)], test_data: &[(DMatrix, DMatrix)]) {
+    let calibration: Vec<DMatrix> = training_data[..1000]
+        .chunks(100)
+        .map(|batch| DMatrix::hstack(&batch.iter().map(|(image, _)| image).collect::<Vec<_>>()))
+        .collect();
+    let float = nn.inference();
+    let baseline = test_accuracy(&float, test_data);
+    println!("float32: {:.2}% with {} bytes", baseline, float.bytes());
+    for granularity in [Granularity::PerTensor, Granularity::PerChannel] {
+        let int8 = nn.quantized(&calibration, granularity);
+        let accuracy = test_accuracy(&int8, test_data);
+        println!(
+            "int8 {:?}: {:.2}% with {} bytes, {:.2} points less",
+            granularity,
+            accuracy,
+            int8.bytes(),
+            baseline - accuracy
+        );
+    }
+}
+
+fn mnist_mlp() -> models::NeuralNetwork {
+    let mut nn = models::NeuralNetwork::new(10).with_input_shape(&[784]);
+    nn.add(Layer::new(784, 128, activations::RELU, 0.05));
+    nn.add(Layer::new(128, 64, activations::RELU, 0.05));
+    nn.add(Layer::new(64, 10, activations::SOFTMAX, 0.05));
+    nn
+}
+
+// Quantizes a dense MNIST classifier after training
+fn main6() {
+    println!("Reading data ....");
+    let (mut training_data, test_data) = mnist_samples("C:/users/antga/documents/uni/neuralnets/MNIST/");
+    let mut nn = mnist_mlp();
+
+    println!("Starting to train ...");
+    train_epoch(&mut nn, &mut training_data);
+    println!();
+    report_quantization(&nn, &training_data, &test_data);
+}
+
+// Prunes a dense MNIST classifier step by step. Every step fine-tunes for
+// one epoch while the sparsity grows to the next target, then reports the
+// accuracy and the size of the sparse inference model.
+fn main7() {
+    println!("Reading data ....");
+    let (mut training_data, test_data) = mnist_samples("C:/users/antga/documents/uni/neuralnets/MNIST/");
+    let mut nn = mnist_mlp();
+
+    println!("Starting to train ...");
+    train_epoch(&mut nn, &mut training_data);
+    let dense = nn.inference();
+    println!("\nsparsity 0.00: {:.2}% with {} bytes", test_accuracy(&dense, &test_data), dense.bytes());
+
+    let mut rng = thread_rng();
+    for target in [0.5, 0.75, 0.9, 0.95, 0.98] {
+        training_data.shuffle(&mut rng);
+        let batches: Vec<&[(DMatrix, DMatrix)]> = training_data.chunks(32).collect();
+        let schedule = Schedule::new(target, 0, batches.len() / 2, 100).with_initial(nn.sparsity());
+        for (step, batch) in batches.iter().enumerate() {
+            if let Some(sparsity) = schedule.at(step) {
+                nn.prune(sparsity, Scope::Global);
+            }
+            let images = DMatrix::hstack(&batch.iter().map(|(image, _)| image).collect::<Vec<_>>());
+            let labels = DMatrix::hstack(&batch.iter().map(|(_, label)| label).collect::<Vec<_>>());
+            nn.train(&images, &labels);
+            loading(step, batches.len(), 10);
+        }
+        let sparse = nn.inference().sparse(0.7);
+        println!(
+            "\nsparsity {:.2}: {:.2}% with {} bytes",
+            nn.sparsity(),
+            test_accuracy(&sparse, &test_data),
+            sparse.bytes()
+        );
+    }
+}
+
+// Predicts the next height from the previous ones, treating the heights as a time series
+fn main4() {
+    let path = "C:/users/antga/documents/uni/neuralnets/Hhwayli.dat";
+    let heights: Vec<FloatPrecision> = match read_floats(path) {
+        Err(_) => panic!("Could not read file at {}.", path),
+        Ok(value) => value,
+    };
+    let window = 16;
+    let samples: Vec<(DMatrix, DMatrix)> = (0..heights.len() - window)
+        .map(|i| {
+            (
+                DMatrix::new(heights[i..i + window].to_vec(), (window, 1)),
+                DMatrix::new(vec![heights[i + window]], (1, 1)),
+            )
+        })
+        .collect();
+
+    let mut nn = models::NeuralNetwork::new(1).with_input_shape(&[window, 1]);
+    nn.add(Lstm::new(1, 32, 0.01).with_bptt(8));
+    nn.add(Layer::new(nn.output_size(), 1, activations::LINEAR, 0.01));
+
+    let mut rng = thread_rng();
+    let mut order: Vec<usize> = (0..samples.len()).collect();
+    order.shuffle(&mut rng);
+    let batches = order.chunks(32).collect::<Vec<_>>();
+    for (i, batch) in batches.iter().enumerate() {
+        let input = DMatrix::hstack(&batch.iter().map(|&k| &samples[k].0).collect::<Vec<_>>());
+        let label = DMatrix::hstack(&batch.iter().map(|&k| &samples[k].1).collect::<Vec<_>>());
+        nn.train(&input, &label);
+        loading(i, batches.len(), 10);
+    }
+
+    let xs: Vec<FloatPrecision> = (0..samples.len()).map(|i| 0.05 * ((i + window) as FloatPrecision)).collect();
+    let ys: Vec<FloatPrecision> = samples.iter().map(|(_, label)| label.data[0]).collect();
+    let pys: Vec<FloatPrecision> = samples.iter().map(|(input, _)| nn.predict(input).data[0]).collect();
+    plot2(
+        "Heights vs. LSTM predictions",
+        "C:/users/antga/documents/uni/neuralnets/rust/plots/plot2.png",
+        &xs,
+        &ys,
+        &xs,
+        &pys,
+        (1000, 400),
+        (xs[0], xs[xs.len() - 1]),
+        (min(&ys), max(&ys)),
+    );
+}
+
 /*fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     let training_data = [
@@ -99,6 +99,7 @@ pub fn naive_mulm_assign(lhs: &mut DMatrix, rhs: &DMatrix) {
     }
 }
 
+// lhs * rhs + q, where the column q is added to every column of the product
 pub fn linm(lhs: &DMatrix, rhs: &DMatrix, q: &DMatrix, result: &mut DMatrix) {
     let n = lhs.shape.0;
     let K = lhs.shape.1;
@@ -113,7 +114,25 @@ pub fn linm(lhs: &DMatrix, rhs: &DMatrix, q: &DMatrix, result: &mut DMatrix) {
             for k in 0..K {
                 result.data[index] += lhs.data[rowlhs + k] * rhs.data[k * m + j];
             }
-            result.data[index] += q.data[index];
+            result.data[index] += q.data[i];
+        }
+    }
+}
+
+// f(lhs * rhs + q) in one pass, without storing lhs * rhs + q
+pub fn linm_map(lhs: &DMatrix, rhs: &DMatrix, q: &DMatrix, f: &dyn Fn(FloatPrecision) -> FloatPrecision, result: &mut DMatrix) {
+    let n = lhs.shape.0;
+    let K = lhs.shape.1;
+    let m = rhs.shape.1;
+
+    for i in 0..n {
+        let rowlhs = i * K;
+        for j in 0..m {
+            let mut sum = q.data[i];
+            for k in 0..K {
+                sum += lhs.data[rowlhs + k] * rhs.data[k * m + j];
+            }
+            result.data[i * m + j] = f(sum);
         }
     }
 }
@@ -134,6 +153,23 @@ pub fn smmulmt(s: FloatPrecision, lhs: &DMatrix, rhs: &DMatrix, result: &mut DMa
     }
 }
 
+// result += lhs * rhsT, e.g. to accumulate weight gradients over time steps
+pub fn mmulmt_add(lhs: &DMatrix, rhs: &DMatrix, result: &mut DMatrix) {
+    let n = lhs.shape.0;
+    let K = rhs.shape.1;
+    let m = rhs.shape.0;
+
+    for i in 0..n {
+        for j in 0..m {
+            let mut sum = 0.;
+            for k in 0..K {
+                sum += lhs.data[i * K + k] * rhs.data[j * K + k];
+            }
+            result.data[i * m + j] += sum;
+        }
+    }
+}
+
 pub fn addm(lhs: &DMatrix, rhs: &DMatrix, result: &mut DMatrix) {
     let (n, m) = lhs.shape;
     for i in 0..n {
@@ -156,6 +192,24 @@ pub fn addm_assign(lhs: &mut DMatrix, rhs: &DMatrix) {
     }
 }
 
+// Sums every row of m into the column result
+pub fn rowsum(m: &DMatrix, result: &mut DMatrix) {
+    let (n, k) = m.shape;
+    for i in 0..n {
+        result.data[i] = 0.;
+        for j in 0..k {
+            result.data[i] += m.data[i * k + j];
+        }
+    }
+}
+
+// lhs += s * rhs
+pub fn saddm_assign(s: FloatPrecision, lhs: &mut DMatrix, rhs: &DMatrix) {
+    for i in 0..lhs.data.len() {
+        lhs.data[i] += s * rhs.data[i];
+    }
+}
+
 pub fn subm(lhs: &DMatrix, rhs: &DMatrix, result: &mut DMatrix) {
     let (n, m) = lhs.shape;
     for i in 0..n {
@@ -178,8 +232,8 @@ pub fn ssubm(s: FloatPrecision, lhs: &DMatrix, rhs: &DMatrix, result: &mut DMatr
     }
 }
 
-// A dynamically sized Matrix implementation.
-#[derive(Debug, Clone)]
+// A dynamically sized Matrix implementation. The default is the empty 0 x 0 matrix.
+#[derive(Debug, Clone, Default)]
 pub struct DMatrix {
     pub data: Vec<FloatPrecision>,
     pub shape: (usize, usize),
@@ -198,6 +252,33 @@ impl DMatrix {
         }
     }
 
+    pub fn zeros(shape: (usize, usize)) -> Self {
+        Self {
+            data: vec![0.; shape.0 * shape.1],
+            shape,
+        }
+    }
+
+    // Puts the columns of all matrices next to each other, e.g. to build a batch out of samples
+    pub fn hstack(matrices: &[&DMatrix]) -> Self {
+        let n = matrices[0].shape.0;
+        let m: usize = matrices.iter().map(|x| x.shape.1).sum();
+        let mut result = Self::zeros((n, m));
+        let mut offset = 0;
+        for x in matrices {
+            if x.shape.0 != n {
+                panic!("Cannot stack a matrix with {} rows next to one with {} rows.", x.shape.0, n);
+            }
+            for i in 0..n {
+                for j in 0..x.shape.1 {
+                    result.data[i * m + offset + j] = x.data[i * x.shape.1 + j];
+                }
+            }
+            offset += x.shape.1;
+        }
+        result
+    }
+
     pub fn abs(&self) -> FloatPrecision {
         self.data.iter().map(|&x| x * x).sum::<FloatPrecision>().sqrt()
     }
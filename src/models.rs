@@ -1,3 +1,7 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::iter::FlatMap;
 use std::process::exit;
 use std::ptr::null;
@@ -6,59 +10,869 @@ use crate::activations;
 use crate::activations::mwrap;
 use crate::activations::Activation;
 
+use crate::clipping::{clip, Clipping};
 use crate::constants::FloatPrecision;
+use crate::inference::InferenceModel;
+use crate::pruning::{is_prunable, magnitude_masks, sparsity, Scope};
+use crate::quantization::Granularity;
 
 use crate::layers::Layer;
+use crate::layers::Module;
+use crate::layers::Parameter;
 
+use crate::losses::Loss;
+use crate::losses::Mse;
+
+use crate::math::addm_assign;
 use crate::math::max;
 use crate::math::mulm;
 use crate::math::naive_mulm;
+use crate::math::naive_mulm_assign;
 use crate::math::ssubm;
 use crate::math::subm;
 use crate::math::mtmulm;
 use crate::math::DMatrix;
+use crate::registry::Registry;
+
+// Version 2 stores the activations of layers by name
+const MAGIC: &[u8; 4] = b"NNT2";
 
-// Someday...
-/*pub struct NeuralNetwork {
-    layers: Vec<Layer>,
+// A stack of modules, each one fed with the output of the previous one
+pub struct NeuralNetwork {
+    layers: Vec<Box<dyn Module>>,
+    trainable: Vec<bool>,
+    rate_multipliers: Vec<FloatPrecision>,
+    masks: Vec<Vec<(String, DMatrix)>>, // pruning masks of the weights of every layer
+    input_shape: Option<Vec<usize>>,
+    training: bool,
+    loss: Box<dyn Loss>,
+    clipping: Option<Clipping>,
+    gradient_norm: FloatPrecision,
     pub error: DMatrix,
     delta: DMatrix,
-    fdnet: DMatrix,
 }
 
 impl NeuralNetwork {
     pub fn new(output_size: usize) -> Self {
         Self {
             layers: Vec::new(),
+            trainable: Vec::new(),
+            rate_multipliers: Vec::new(),
+            masks: Vec::new(),
+            input_shape: None,
+            training: true,
+            loss: Box::new(Mse),
+            clipping: None,
+            gradient_norm: 0.,
             error: DMatrix::new(vec![0.; output_size], (output_size, 1)),
             delta: DMatrix::new(vec![0.; output_size], (output_size, 1)),
-            fdnet: DMatrix::new(vec![0.; output_size], (output_size, 1)),
         }
     }
 
-    pub fn predict(&mut self, input: &DMatrix) -> &DMatrix {
-        self.layers[0].forward(input);
-        let i = 0;
+    // Shape of one input sample, e.g. [1, 28, 28] for MNIST images. With it
+    // the network checks the shapes of added layers and can report its output shape.
+    pub fn with_input_shape(mut self, shape: &[usize]) -> Self {
+        self.input_shape = Some(shape.to_vec());
+        self
+    }
+
+    // Mse by default
+    pub fn with_loss<L: Loss + 'static>(mut self, loss: L) -> Self {
+        self.loss = Box::new(loss);
+        self
+    }
+
+    pub fn with_clipping(mut self, clipping: Clipping) -> Self {
+        self.clipping = Some(clipping);
+        self
+    }
+
+    pub fn add<M: Module + 'static>(&mut self, layer: M) {
+        if self.input_shape.is_some() {
+            layer.output_shape(&self.output_shape()); // panics if the layer does not fit
+        }
+        self.layers.push(Box::new(layer));
+        self.trainable.push(true);
+        self.rate_multipliers.push(1.);
+        self.masks.push(Vec::new());
+    }
+
+    // Removes the last layer, e.g. to replace the head of a loaded network
+    pub fn pop(&mut self) -> Option<Box<dyn Module>> {
+        self.trainable.pop();
+        self.rate_multipliers.pop();
+        self.masks.pop();
+        self.layers.pop()
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    // Frozen layers keep their parameters while training, e.g. the pretrained
+    // layers when fine-tuning a new head
+    pub fn set_trainable(&mut self, layer: usize, trainable: bool) {
+        self.trainable[layer] = trainable;
+        self.layers[layer].set_trainable(trainable);
+    }
+
+    pub fn is_trainable(&self, layer: usize) -> bool {
+        self.trainable[layer]
+    }
+
+    // Scales the gradient steps of one layer, e.g. to fine-tune pretrained
+    // layers more carefully than a new head
+    pub fn set_rate_multiplier(&mut self, layer: usize, multiplier: FloatPrecision) {
+        self.rate_multipliers[layer] = multiplier;
+        self.layers[layer].set_rate_multiplier(multiplier);
+    }
+
+    // Shape of one sample after the layers added so far
+    pub fn output_shape(&self) -> Vec<usize> {
+        let mut shape = match &self.input_shape {
+            Some(shape) => shape.clone(),
+            None => panic!("The input shape is unknown, see NeuralNetwork::with_input_shape."),
+        };
         for layer in self.layers.iter() {
-            layer.forward(&self.layers[i].out);
-            i += 1;
+            shape = layer.output_shape(&shape);
+        }
+        shape
+    }
+
+    // Number of outputs per sample, i.e. the input size of a dense layer added next
+    pub fn output_size(&self) -> usize {
+        self.output_shape().iter().product()
+    }
+
+    // Training mode is used by train and inference mode by predict, this only
+    // has to be called to run forward passes with training behaviour by hand.
+    pub fn set_training(&mut self, training: bool) {
+        if self.training != training {
+            self.training = training;
+            for layer in self.layers.iter_mut() {
+                layer.set_training(training);
+            }
         }
-        &self.layers[self.layers.len() - 1].out
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn forward(&mut self, input: &DMatrix) {
+        self.layers[0].forward(input);
+        for i in 1..self.layers.len() {
+            let (done, rest) = self.layers.split_at_mut(i);
+            rest[0].forward(done[i - 1].out());
+        }
+    }
+
+    pub fn predict(&mut self, input: &DMatrix) -> &DMatrix {
+        self.set_training(false);
+        self.forward(input);
+        self.layers[self.layers.len() - 1].out()
+    }
+
+    // Immutable copy for predicting, which can be shared between threads.
+    // Later training does not change it.
+    pub fn inference(&self) -> InferenceModel {
+        let ops = self.layers.iter().enumerate().map(|(i, layer)| match layer.inference() {
+            Some(op) => op,
+            None => panic!("Layer {} has no inference form.", i),
+        });
+        InferenceModel::new(ops.collect())
+    }
+
+    // Inference model with int8 dense layers, whose input ranges are
+    // calibrated on a few batches of typical inputs. Other layers stay floats.
+    pub fn quantized(&self, calibration: &[DMatrix], granularity: Granularity) -> InferenceModel {
+        let ranges = self.inference().input_ranges(calibration);
+        let ops = self.layers.iter().enumerate().map(|(i, layer)| {
+            match layer.quantized(ranges[i], granularity).or_else(|| layer.inference()) {
+                Some(op) => op,
+                None => panic!("Layer {} has no inference form.", i),
+            }
+        });
+        InferenceModel::new(ops.collect())
+    }
+
+    // Loss of the output for label including the penalties of regularized
+    // layers, in the current mode (see set_training)
+    pub fn loss(&mut self, input: &DMatrix, label: &DMatrix) -> FloatPrecision {
+        self.forward(input);
+        let penalty: FloatPrecision = self.layers.iter().map(|layer| layer.penalty()).sum();
+        self.loss.loss(self.layers[self.layers.len() - 1].out(), label) + penalty
     }
 
     pub fn train(&mut self, input: &DMatrix, label: &DMatrix) {
-        self.layer0.forward(input);
-        self.layer1.forward(&self.layer0.out);
+        self.gradients(input, label);
 
-        subm(label, &self.layer1.out, &mut self.layer1.delta); // dE
-        subm(label, &self.layer1.out, &mut self.error); // dE
+        let mut params = Vec::new();
+        let mut multipliers = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if self.trainable[i] {
+                let group = layer.parameters();
+                multipliers.resize(multipliers.len() + group.len(), self.rate_multipliers[i]);
+                params.extend(group);
+            }
+        }
+        self.gradient_norm = clip_scaled(self.clipping, &mut params, &multipliers);
 
-        self.layer1.backward(&self.layer0.out);
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if self.trainable[i] {
+                layer.update();
+            }
+        }
+        self.apply_masks();
+    }
 
-        tmulm(&self.layer1.weights, &self.layer1.delta, &mut self.layer0.delta);
-        self.layer0.backward(&input);
+    // Sets the given fraction of the weights with the smallest magnitudes to
+    // zero. They stay zero while training until prune is called again. The
+    // masks are not saved, after load prune again with the same sparsity to
+    // keep the loaded zeros.
+    pub fn prune(&mut self, sparsity: FloatPrecision, scope: Scope) {
+        let mut params = Vec::new();
+        let mut owners = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            for p in layer.parameters().into_iter().filter(is_prunable) {
+                owners.push(i);
+                params.push(p);
+            }
+        }
+        let masks = magnitude_masks(&params, sparsity, scope);
+        self.masks.iter_mut().for_each(|masks| masks.clear());
+        for ((p, mask), &i) in params.iter_mut().zip(masks).zip(owners.iter()) {
+            naive_mulm_assign(p.value, &mask);
+            self.masks[i].push((p.name.clone(), mask));
+        }
+    }
+
+    fn apply_masks(&mut self) {
+        for (layer, masks) in self.layers.iter_mut().zip(self.masks.iter()) {
+            if masks.is_empty() {
+                continue;
+            }
+            for p in layer.parameters() {
+                if let Some((_, mask)) = masks.iter().find(|(name, _)| *name == p.name) {
+                    naive_mulm_assign(p.value, mask);
+                }
+            }
+        }
+    }
+
+    // Fraction of the prunable weights that are zero
+    pub fn sparsity(&mut self) -> FloatPrecision {
+        let params: Vec<Parameter> = self.parameters().into_iter().filter(is_prunable).collect();
+        sparsity(&params)
+    }
+
+    // Global norm of the gradients of the last train, before clipping
+    pub fn gradient_norm(&self) -> FloatPrecision {
+        self.gradient_norm
+    }
+
+    // Forward and backward pass in training mode without updating, the
+    // gradients are then available from parameters
+    pub fn gradients(&mut self, input: &DMatrix, label: &DMatrix) {
+        self.set_training(true);
+        self.forward(input);
+
+        let last = self.layers.len() - 1;
+        let out = self.layers[last].out();
+        if self.delta.shape != label.shape {
+            self.delta = DMatrix::zeros(label.shape);
+            self.error = DMatrix::zeros(label.shape);
+        }
+        self.loss.delta(out, label, &mut self.delta); // delta = -dE
+        self.error.data.copy_from_slice(&self.delta.data);
+
+        // Frozen layers in front need no deltas, nothing before them learns
+        let first = self.trainable.iter().position(|&t| t).unwrap_or(last + 1);
+        for i in (first..=last).rev() {
+            let (done, rest) = self.layers.split_at_mut(i);
+            let (layer, after) = rest.split_first_mut().unwrap();
+            let layer_input = if i == 0 { input } else { done[i - 1].out() };
+            let delta = if i == last { &self.delta } else { after[0].delta() };
+            layer.backward(layer_input, delta);
+        }
+    }
+
+    // Parameters of all layers, prefixed with the index of their layer
+    pub fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            params.extend(layer.parameters().into_iter().map(|p| p.prefixed(&format!("layer{}", i))));
+        }
+        params
+    }
+
+    pub fn get_error(&self) -> FloatPrecision {
+        self.delta.abs()
+    }
+
+    // Stores the parameters and running statistics of all layers. They can
+    // only be loaded into a network that is built the same way.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_u64::<LittleEndian>(self.layers.len() as u64)?;
+        for layer in self.layers.iter() {
+            layer.save(&mut w)?;
+        }
+        w.flush()
+    }
+
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        self.load_with(path, &Registry::default())
+    }
+
+    // Like load, with a registry that also knows the activations a user registered
+    pub fn load_with(&mut self, path: &str, registry: &Registry) -> io::Result<()> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a saved network.", path)));
+        }
+        let n = r.read_u64::<LittleEndian>()? as usize;
+        if n != self.layers.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Saved network has {} layers but this one has {}.", n, self.layers.len()),
+            ));
+        }
+        for layer in self.layers.iter_mut() {
+            layer.load(&mut r, registry)?;
+        }
+        // Masks of earlier prunings would zero loaded weights
+        self.masks.iter_mut().for_each(|masks| masks.clear());
+        Ok(())
+    }
+}
+
+// Clips the gradients of the trainable parameters together, then scales
+// them by the rate multipliers of their layers. Returns the norm before clipping.
+fn clip_scaled(clipping: Option<Clipping>, params: &mut [Parameter], multipliers: &[FloatPrecision]) -> FloatPrecision {
+    let norm = clip(clipping, params);
+    for (p, &multiplier) in params.iter_mut().zip(multipliers.iter()) {
+        if multiplier != 1. {
+            p.grad.data.iter_mut().for_each(|g| *g *= multiplier);
+        }
+    }
+    norm
+}
+
+// A node of a Graph, returned when it is added and used to connect later nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+enum Op {
+    Input(usize), // index into the inputs passed to predict and train
+    Layer(Box<dyn Module>, usize),
+    Sum(Vec<usize>),
+    Concat(Vec<usize>),
+}
+
+struct Node {
+    op: Op,
+    shape: Vec<usize>,
+    out: DMatrix, // unused by layers, they keep their own
+    delta: DMatrix, // sum of the deltas of all consumers
+    trainable: bool,
+    rate_multiplier: FloatPrecision,
+}
+
+impl Node {
+    fn out(&self) -> &DMatrix {
+        match &self.op {
+            Op::Layer(layer, _) => layer.out(),
+            _ => &self.out,
+        }
+    }
+}
+
+// A model whose modules form a directed acyclic graph, for residual
+// connections and networks with several inputs or outputs. Nodes can only
+// consume nodes added before them, so the graph cannot have cycles.
+pub struct Graph {
+    nodes: Vec<Node>,
+    inputs: Vec<usize>,
+    outputs: Vec<usize>,
+    order: Vec<usize>, // the nodes the outputs depend on, in topological order
+    training: bool,
+    loss: Box<dyn Loss>,
+    clipping: Option<Clipping>,
+    gradient_norm: FloatPrecision,
+    pub errors: Vec<DMatrix>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            order: Vec::new(),
+            training: true,
+            loss: Box::new(Mse),
+            clipping: None,
+            gradient_norm: 0.,
+            errors: Vec::new(),
+        }
+    }
+
+    // Mse by default, used for every output
+    pub fn with_loss<L: Loss + 'static>(mut self, loss: L) -> Self {
+        self.loss = Box::new(loss);
+        self
+    }
+
+    pub fn with_clipping(mut self, clipping: Clipping) -> Self {
+        self.clipping = Some(clipping);
+        self
+    }
+
+    fn push(&mut self, op: Op, shape: Vec<usize>) -> NodeId {
+        self.nodes.push(Node {
+            op,
+            shape,
+            out: DMatrix::zeros((0, 0)),
+            delta: DMatrix::zeros((0, 0)),
+            trainable: true,
+            rate_multiplier: 1.,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    fn check(&self, node: NodeId) -> usize {
+        if node.0 >= self.nodes.len() {
+            panic!("Node {} is not part of this graph.", node.0);
+        }
+        node.0
+    }
+
+    // Inputs are fed in the order they were added
+    pub fn input(&mut self, shape: &[usize]) -> NodeId {
+        self.inputs.push(self.nodes.len());
+        self.push(Op::Input(self.inputs.len() - 1), shape.to_vec())
+    }
+
+    pub fn layer<M: Module + 'static>(&mut self, layer: M, from: NodeId) -> NodeId {
+        let from = self.check(from);
+        let shape = layer.output_shape(&self.nodes[from].shape); // panics if the layer does not fit
+        self.push(Op::Layer(Box::new(layer), from), shape)
+    }
+
+    // Elementwise sum of nodes with the same size, e.g. for residual connections
+    pub fn sum(&mut self, nodes: &[NodeId]) -> NodeId {
+        let nodes: Vec<usize> = nodes.iter().map(|&n| self.check(n)).collect();
+        if nodes.is_empty() {
+            panic!("Cannot sum no nodes.");
+        }
+        let shape = self.nodes[nodes[0]].shape.clone();
+        for &n in nodes.iter() {
+            let other = &self.nodes[n].shape;
+            if other.iter().product::<usize>() != shape.iter().product::<usize>() {
+                panic!("Cannot sum nodes of shapes {:?} and {:?}.", shape, other);
+            }
+        }
+        self.push(Op::Sum(nodes), shape)
+    }
+
+    // Stacks the rows of the nodes. Images of the same size are concatenated
+    // along the channels, anything else is flattened.
+    pub fn concat(&mut self, nodes: &[NodeId]) -> NodeId {
+        let nodes: Vec<usize> = nodes.iter().map(|&n| self.check(n)).collect();
+        if nodes.is_empty() {
+            panic!("Cannot concatenate no nodes.");
+        }
+        let shapes: Vec<&Vec<usize>> = nodes.iter().map(|&n| &self.nodes[n].shape).collect();
+        let first = shapes[0];
+        let shape = if first.len() > 1 && shapes.iter().all(|s| s.len() == first.len() && s[1..] == first[1..]) {
+            let mut shape = first.clone();
+            shape[0] = shapes.iter().map(|s| s[0]).sum();
+            shape
+        } else {
+            vec![shapes.iter().map(|s| s.iter().product::<usize>()).sum()]
+        };
+        self.push(Op::Concat(nodes), shape)
+    }
+
+    // Marks a node as output, they are returned and trained in the order they were marked
+    pub fn output(&mut self, node: NodeId) {
+        let node = self.check(node);
+        self.outputs.push(node);
+
+        // Every node comes after the nodes it consumes, so the needed nodes in
+        // the order they were added are already topologically sorted.
+        let mut needed = vec![false; self.nodes.len()];
+        for &n in self.outputs.iter() {
+            needed[n] = true;
+        }
+        for n in (0..self.nodes.len()).rev() {
+            if !needed[n] {
+                continue;
+            }
+            match &self.nodes[n].op {
+                Op::Input(_) => {}
+                Op::Layer(_, from) => needed[*from] = true,
+                Op::Sum(from) | Op::Concat(from) => {
+                    for &f in from.iter() {
+                        needed[f] = true;
+                    }
+                }
+            }
+        }
+        self.order = (0..self.nodes.len()).filter(|&n| needed[n]).collect();
+    }
+
+    pub fn shape(&self, node: NodeId) -> Vec<usize> {
+        self.nodes[self.check(node)].shape.clone()
+    }
+
+    fn layer_mut(&mut self, node: NodeId) -> &mut Box<dyn Module> {
+        let n = self.check(node);
+        match &mut self.nodes[n].op {
+            Op::Layer(layer, _) => layer,
+            _ => panic!("Node {} is not a layer.", n),
+        }
+    }
+
+    // Like NeuralNetwork::set_trainable, for the layer of a node
+    pub fn set_trainable(&mut self, node: NodeId, trainable: bool) {
+        self.layer_mut(node).set_trainable(trainable);
+        self.nodes[node.0].trainable = trainable;
+    }
+
+    pub fn is_trainable(&self, node: NodeId) -> bool {
+        self.nodes[self.check(node)].trainable
+    }
+
+    // Like NeuralNetwork::set_rate_multiplier, for the layer of a node
+    pub fn set_rate_multiplier(&mut self, node: NodeId, multiplier: FloatPrecision) {
+        self.layer_mut(node).set_rate_multiplier(multiplier);
+        self.nodes[node.0].rate_multiplier = multiplier;
+    }
+
+    pub fn set_training(&mut self, training: bool) {
+        if self.training != training {
+            self.training = training;
+            for node in self.nodes.iter_mut() {
+                if let Op::Layer(layer, _) = &mut node.op {
+                    layer.set_training(training);
+                }
+            }
+        }
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn forward(&mut self, inputs: &[&DMatrix]) {
+        if inputs.len() != self.inputs.len() {
+            panic!("Graph has {} inputs, got {}.", self.inputs.len(), inputs.len());
+        }
+        if self.outputs.is_empty() {
+            panic!("Graph has no outputs, see Graph::output.");
+        }
+        for &i in self.order.iter() {
+            let (done, rest) = self.nodes.split_at_mut(i);
+            let node = &mut rest[0];
+            match &mut node.op {
+                Op::Input(k) => {
+                    node.out.shape = inputs[*k].shape;
+                    node.out.data.clear();
+                    node.out.data.extend_from_slice(&inputs[*k].data);
+                }
+                Op::Layer(layer, from) => layer.forward(done[*from].out()),
+                Op::Sum(from) => {
+                    let first = done[from[0]].out();
+                    node.out.shape = first.shape;
+                    node.out.data.clear();
+                    node.out.data.extend_from_slice(&first.data);
+                    for &f in from[1..].iter() {
+                        addm_assign(&mut node.out, done[f].out());
+                    }
+                }
+                Op::Concat(from) => {
+                    // Row major, so stacking rows just appends the data
+                    node.out.data.clear();
+                    let mut rows = 0;
+                    for &f in from.iter() {
+                        let out = done[f].out();
+                        rows += out.shape.0;
+                        node.out.data.extend_from_slice(&out.data);
+                    }
+                    node.out.shape = (rows, done[from[0]].out().shape.1);
+                }
+            }
+        }
+    }
+
+    // Deltas of the outputs have to be added to their nodes before
+    fn backward(&mut self) {
+        for &i in self.order.iter().rev() {
+            let (done, rest) = self.nodes.split_at_mut(i);
+            let Node { op, delta, .. } = &mut rest[0];
+            match op {
+                Op::Input(_) => {}
+                Op::Layer(layer, from) => {
+                    layer.backward(done[*from].out(), delta);
+                    addm_assign(&mut done[*from].delta, layer.delta());
+                }
+                Op::Sum(from) => {
+                    for &f in from.iter() {
+                        addm_assign(&mut done[f].delta, delta);
+                    }
+                }
+                Op::Concat(from) => {
+                    let mut offset = 0;
+                    for &f in from.iter() {
+                        let d = &mut done[f].delta;
+                        let len = d.data.len();
+                        for (x, y) in d.data.iter_mut().zip(delta.data[offset..offset + len].iter()) {
+                            *x += y;
+                        }
+                        offset += len;
+                    }
+                }
+            }
+        }
+    }
+
+    fn zero_deltas(&mut self) {
+        for &i in self.order.iter() {
+            let shape = self.nodes[i].out().shape;
+            let delta = &mut self.nodes[i].delta;
+            if delta.shape != shape {
+                *delta = DMatrix::zeros(shape);
+            } else {
+                delta.data.iter_mut().for_each(|x| *x = 0.);
+            }
+        }
+    }
+
+    fn update_layers(&mut self) {
+        for &i in self.order.iter() {
+            let node = &mut self.nodes[i];
+            if let (Op::Layer(layer, _), true) = (&mut node.op, node.trainable) {
+                layer.update();
+            }
+        }
+    }
+
+    pub fn predict(&mut self, inputs: &[&DMatrix]) -> Vec<&DMatrix> {
+        self.set_training(false);
+        self.forward(inputs);
+        self.outputs.iter().map(|&n| self.nodes[n].out()).collect()
+    }
+
+    // Sum of the losses of all outputs including the penalties of
+    // regularized layers, in the current mode (see set_training)
+    pub fn loss(&mut self, inputs: &[&DMatrix], labels: &[&DMatrix]) -> FloatPrecision {
+        if labels.len() != self.outputs.len() {
+            panic!("Graph has {} outputs, got {} labels.", self.outputs.len(), labels.len());
+        }
+        self.forward(inputs);
+        let penalty: FloatPrecision = self.layers().map(|layer| layer.penalty()).sum();
+        let losses = self.outputs.iter().zip(labels.iter()).map(|(&n, label)| self.loss.loss(self.nodes[n].out(), label));
+        losses.sum::<FloatPrecision>() + penalty
+    }
+
+    // One label per output
+    pub fn train(&mut self, inputs: &[&DMatrix], labels: &[&DMatrix]) {
+        self.gradients(inputs, labels);
+
+        let mut params = Vec::new();
+        let mut multipliers = Vec::new();
+        for node in self.nodes.iter_mut() {
+            if let (Op::Layer(layer, _), true) = (&mut node.op, node.trainable) {
+                let group = layer.parameters();
+                multipliers.resize(multipliers.len() + group.len(), node.rate_multiplier);
+                params.extend(group);
+            }
+        }
+        self.gradient_norm = clip_scaled(self.clipping, &mut params, &multipliers);
+        self.update_layers();
+    }
+
+    // Forward and backward pass in training mode without updating, the
+    // gradients are then available from parameters
+    pub fn gradients(&mut self, inputs: &[&DMatrix], labels: &[&DMatrix]) {
+        if labels.len() != self.outputs.len() {
+            panic!("Graph has {} outputs, got {} labels.", self.outputs.len(), labels.len());
+        }
+        self.set_training(true);
+        self.forward(inputs);
+        self.zero_deltas();
+
+        self.errors.resize_with(labels.len(), || DMatrix::zeros((0, 0)));
+        for (k, &n) in self.outputs.iter().enumerate() {
+            let label = labels[k];
+            if self.errors[k].shape != label.shape {
+                self.errors[k] = DMatrix::zeros(label.shape);
+            }
+            self.loss.delta(self.nodes[n].out(), label, &mut self.errors[k]); // -dE
+            addm_assign(&mut self.nodes[n].delta, &self.errors[k]);
+        }
+        self.backward();
+    }
+
+    // Global norm of the gradients of the last train, before clipping
+    pub fn gradient_norm(&self) -> FloatPrecision {
+        self.gradient_norm
+    }
+
+    pub fn get_error(&self) -> FloatPrecision {
+        self.errors.iter().map(|e| e.abs()).sum()
+    }
+
+    // Parameters of all layers, prefixed with the index of their node
+    pub fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = Vec::new();
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if let Op::Layer(layer, _) = &mut node.op {
+                params.extend(layer.parameters().into_iter().map(|p| p.prefixed(&format!("node{}", i))));
+            }
+        }
+        params
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &Box<dyn Module>> {
+        self.nodes.iter().filter_map(|node| match &node.op {
+            Op::Layer(layer, _) => Some(layer),
+            _ => None,
+        })
+    }
+
+    fn save_layers(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_u64::<LittleEndian>(self.layers().count() as u64)?;
+        for layer in self.layers() {
+            layer.save(w)?;
+        }
+        Ok(())
+    }
+
+    fn load_layers(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        let n = r.read_u64::<LittleEndian>()? as usize;
+        let count = self.layers().count();
+        if n != count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Saved graph has {} layers but this one has {}.", n, count),
+            ));
+        }
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer, _) = &mut node.op {
+                layer.load(r, registry)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Same format as NeuralNetwork::save
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        self.save_layers(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        self.load_with(path, &Registry::default())
+    }
+
+    // See NeuralNetwork::load_with
+    pub fn load_with(&mut self, path: &str, registry: &Registry) -> io::Result<()> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a saved network.", path)));
+        }
+        self.load_layers(&mut r, registry)
+    }
+}
+
+// A graph with one input and one output can be used as a module, e.g. as
+// residual block inside a NeuralNetwork
+impl Module for Graph {
+    fn forward(&mut self, input: &DMatrix) {
+        Graph::forward(self, &[input]);
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        if self.outputs.len() != 1 {
+            panic!("Only graphs with one output can be used as module, this one has {}.", self.outputs.len());
+        }
+        self.zero_deltas();
+        addm_assign(&mut self.nodes[self.outputs[0]].delta, delta);
+        Graph::backward(self);
+    }
+
+    fn update(&mut self) {
+        self.update_layers();
+    }
+
+    fn out(&self) -> &DMatrix {
+        self.nodes[self.outputs[0]].out()
+    }
+
+    // Inputs that no output depends on get no delta
+    fn delta(&self) -> &DMatrix {
+        &self.nodes[self.inputs[0]].delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if self.inputs.len() != 1 || self.outputs.len() != 1 {
+            panic!("Only graphs with one input and one output can be used as module.");
+        }
+        let expected = &self.nodes[self.inputs[0]].shape;
+        if input_shape.iter().product::<usize>() != expected.iter().product::<usize>() {
+            panic!("Graph expects inputs of shape {:?}, got {:?}.", expected, input_shape);
+        }
+        self.nodes[self.outputs[0]].shape.clone()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        Graph::set_training(self, training);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer, _) = &mut node.op {
+                layer.set_trainable(trainable);
+                node.trainable = trainable;
+            }
+        }
+    }
+
+    // The outer model scales the gradients, so the nodes only pass it on
+    fn set_rate_multiplier(&mut self, multiplier: FloatPrecision) {
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer, _) = &mut node.op {
+                layer.set_rate_multiplier(multiplier);
+            }
+        }
     }
-}*/
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.save_layers(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.load_layers(r, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        Graph::parameters(self)
+    }
+
+    fn penalty(&self) -> FloatPrecision {
+        self.layers().map(|layer| layer.penalty()).sum()
+    }
+}
 
 pub struct NeuralNetwork2 {
     layer0: Layer,
@@ -221,3 +1035,126 @@ impl NeuralNetwork5 {
         self.layer4.delta.abs()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::LeakyRelu;
+    use crate::conv::Conv2d;
+    use crate::gradcheck::{assert_passed, check_graph, check_model, matrix};
+    use crate::layers::Flatten;
+    use crate::losses::CrossEntropy;
+    use crate::regularization::Constraint;
+
+    fn input() -> DMatrix {
+        DMatrix::new((0..12).map(|i| (i as FloatPrecision * 0.9).sin()).collect(), (4, 3))
+    }
+
+    #[test]
+    fn network_gradients() {
+        let mut nn = NeuralNetwork::new(3).with_input_shape(&[1, 4, 4]).with_loss(CrossEntropy);
+        nn.add(Conv2d::new(1, 2, (4, 4), (2, 2), activations::TANH, 0.1));
+        nn.add(Flatten::new());
+        nn.add(Layer::new(18, 3, activations::SOFTMAX, 0.1));
+        let label = DMatrix::new(vec![1., 0., 0., 1., 0., 0.], (3, 2));
+        let checks = check_model(&mut nn, &matrix((16, 2), 1), &label);
+        assert_eq!(checks.len(), 4);
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn graph_gradients() {
+        let mut graph = Graph::new();
+        let a = graph.input(&[3]);
+        let b = graph.input(&[2]);
+        let ha = graph.layer(Layer::new(3, 4, activations::TANH, 0.1), a);
+        let hb = graph.layer(Layer::new(2, 4, activations::TANH, 0.1), b);
+        let sum = graph.sum(&[ha, hb]);
+        let concat = graph.concat(&[sum, ha]);
+        let out1 = graph.layer(Layer::new(8, 2, activations::LINEAR, 0.1), concat);
+        let out2 = graph.layer(Layer::new(4, 1, activations::TANH, 0.1), sum);
+        graph.output(out1);
+        graph.output(out2);
+
+        let (input_a, input_b) = (matrix((3, 2), 1), matrix((2, 2), 2));
+        let (label1, label2) = (matrix((2, 2), 3), matrix((1, 2), 4));
+        let checks = check_graph(&mut graph, &[&input_a, &input_b], &[&label1, &label2]);
+        assert_eq!(checks.len(), 8);
+        assert_passed(&checks);
+    }
+
+    fn values(params: Vec<Parameter>) -> Vec<Vec<FloatPrecision>> {
+        params.iter().map(|p| p.value.data.clone()).collect()
+    }
+
+    #[test]
+    fn graph_fine_tuning() {
+        let mut graph = Graph::new().with_clipping(Clipping::Norm(1e-3));
+        let input = graph.input(&[3]);
+        let frozen = graph.layer(Layer::new(3, 4, activations::TANH, 0.1), input);
+        let head = Layer::new(4, 2, activations::LINEAR, 0.1).with_weight_decay(0.5).with_constraint(Constraint::MaxNorm(0.1));
+        let head = graph.layer(head, frozen);
+        graph.output(head);
+        graph.set_trainable(frozen, false);
+        graph.set_rate_multiplier(head, 0.);
+
+        // Neither the gradient step nor decay and constraint change the head
+        let (input, label) = (matrix((3, 2), 1), matrix((2, 2), 2));
+        let before = values(graph.parameters());
+        graph.train(&[&input], &[&label]);
+        assert_eq!(before, values(graph.parameters()));
+        assert!(graph.gradient_norm() > 0.);
+
+        graph.set_rate_multiplier(head, 1.);
+        graph.train(&[&input], &[&label]);
+        let after = values(graph.parameters());
+        assert_eq!(before[..2], after[..2]);
+        assert_ne!(before[2], after[2]);
+        assert_ne!(before[3], after[3]);
+    }
+
+    #[test]
+    fn replace_head() {
+        let path = std::env::temp_dir().join("replace_head.nn");
+        let path = path.to_str().unwrap();
+        let mut pretrained = NeuralNetwork::new(2);
+        pretrained.add(Layer::new(4, 5, activations::TANH, 0.1));
+        pretrained.add(Layer::new(5, 2, activations::SOFTMAX, 0.1));
+        pretrained.save(path).unwrap();
+
+        let mut nn = NeuralNetwork::new(2);
+        nn.add(Layer::new(4, 5, activations::TANH, 0.1));
+        nn.add(Layer::new(5, 2, activations::SOFTMAX, 0.1));
+        nn.load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        nn.pop();
+        nn.add(Layer::new(5, 3, activations::LINEAR, 0.1));
+        nn.set_trainable(0, false);
+        assert!(!nn.is_trainable(0) && nn.is_trainable(1));
+
+        let before = values(nn.parameters());
+        nn.train(&input(), &matrix((3, 3), 1));
+        let after = values(nn.parameters());
+        assert_eq!(before[..2], after[..2]);
+        assert_eq!(values(pretrained.parameters())[..2], after[..2]);
+        assert_ne!(before[2], after[2]);
+        assert_ne!(before[3], after[3]);
+    }
+
+    #[test]
+    fn load_restores_activations() {
+        let path = std::env::temp_dir().join("load_restores_activations.nn");
+        let path = path.to_str().unwrap();
+        let mut saved = NeuralNetwork::new(2);
+        saved.add(Layer::new(4, 5, LeakyRelu::new(0.1), 0.1));
+        saved.add(Layer::new(5, 2, activations::SOFTMAX, 0.1));
+        saved.save(path).unwrap();
+
+        let mut loaded = NeuralNetwork::new(2);
+        loaded.add(Layer::new(4, 5, activations::RELU, 0.1));
+        loaded.add(Layer::new(5, 2, activations::SOFTMAX, 0.1));
+        loaded.load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(saved.predict(&input()).data, loaded.predict(&input()).data);
+    }
+}
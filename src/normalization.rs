@@ -0,0 +1,569 @@
+use std::io;
+use std::io::{Read, Write};
+
+use crate::constants::FloatPrecision;
+use crate::inference;
+use crate::inference::Inference;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::math::saddm_assign;
+use crate::math::DMatrix;
+use crate::registry::Registry;
+use crate::serialize::{read_matrix, write_matrix};
+
+const EPSILON: FloatPrecision = 1e-5;
+
+// Batch normalization (Ioffe & Szegedy, 2015). While training every feature
+// is normalized with the mean and variance over the batch, at inference time
+// and when frozen with running averages of those.
+//
+// A feature is one row by default, which is right after dense layers. After
+// Conv2d that would be one pixel of one channel, see BatchNorm::spatial for
+// statistics per channel.
+pub struct BatchNorm {
+    size: usize,
+    span: usize, // consecutive rows of one feature
+    momentum: FloatPrecision,
+    training: bool,
+    pub gamma: DMatrix, // one per feature
+    pub beta: DMatrix,
+    pub dgamma: DMatrix,
+    pub dbeta: DMatrix,
+    trainable: bool,
+    pub running_mean: DMatrix,
+    pub running_var: DMatrix,
+    inv_std: DMatrix, // 1 / sqrt(var + eps) of the last forward
+    xhat: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl BatchNorm {
+    pub fn new(size: usize, rate: FloatPrecision) -> Self {
+        Self::with_span(size, 1, rate)
+    }
+
+    // For channel first images, normalizes every channel over the batch and
+    // all of its pixels
+    pub fn spatial(channels: usize, input: (usize, usize), rate: FloatPrecision) -> Self {
+        Self::with_span(channels, input.0 * input.1, rate)
+    }
+
+    fn with_span(features: usize, span: usize, rate: FloatPrecision) -> Self {
+        Self {
+            size: features * span,
+            span,
+            momentum: 0.1,
+            training: true,
+            gamma: DMatrix::new(vec![1.; features], (features, 1)),
+            beta: DMatrix::zeros((features, 1)),
+            dgamma: DMatrix::zeros((features, 1)),
+            dbeta: DMatrix::zeros((features, 1)),
+            trainable: true,
+            running_mean: DMatrix::zeros((features, 1)),
+            running_var: DMatrix::new(vec![1.; features], (features, 1)),
+            inv_std: DMatrix::zeros((features, 1)),
+            xhat: DMatrix::zeros((features * span, 1)),
+            out: DMatrix::zeros((features * span, 1)),
+            input_delta: DMatrix::zeros((features * span, 1)),
+            rate,
+        }
+    }
+
+    // Weight of the current batch in the running averages, 0.1 by default
+    pub fn with_momentum(mut self, momentum: FloatPrecision) -> Self {
+        self.momentum = momentum;
+        self
+    }
+
+    // Entries of the input that belong to feature f
+    fn entries(&self, f: usize, batch: usize) -> std::ops::Range<usize> {
+        f * self.span * batch..(f + 1) * self.span * batch
+    }
+
+    // A frozen layer keeps its running statistics, e.g. while fine tuning
+    fn batch_statistics(&self) -> bool {
+        self.training && self.trainable
+    }
+}
+
+impl Module for BatchNorm {
+    fn forward(&mut self, input: &DMatrix) {
+        let (n, m) = input.shape;
+        if n != self.size {
+            panic!("BatchNorm of {} rows got {} rows.", self.size, n);
+        }
+        let count = self.span * m;
+        if self.batch_statistics() && count == 1 {
+            panic!("BatchNorm cannot normalize a single value per feature, train with batches of at least 2 samples.");
+        }
+        if self.out.shape != input.shape {
+            self.xhat = DMatrix::zeros(input.shape);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        for f in 0..self.gamma.data.len() {
+            let entries = self.entries(f, m);
+            let values = &input.data[entries.clone()];
+            let (mean, var) = if self.batch_statistics() {
+                let mean = values.iter().sum::<FloatPrecision>() / count as FloatPrecision;
+                let var = values.iter().map(|x| (x - mean) * (x - mean)).sum::<FloatPrecision>() / count as FloatPrecision;
+
+                // The running variance is the unbiased estimate
+                let unbiased = var * count as FloatPrecision / (count - 1) as FloatPrecision;
+                self.running_mean.data[f] += self.momentum * (mean - self.running_mean.data[f]);
+                self.running_var.data[f] += self.momentum * (unbiased - self.running_var.data[f]);
+                (mean, var)
+            } else {
+                (self.running_mean.data[f], self.running_var.data[f])
+            };
+
+            let inv_std = 1. / (var + EPSILON).sqrt();
+            self.inv_std.data[f] = inv_std;
+            for index in entries {
+                self.xhat.data[index] = (input.data[index] - mean) * inv_std;
+                self.out.data[index] = self.gamma.data[f] * self.xhat.data[index] + self.beta.data[f];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let m = delta.shape.1;
+        let count = (self.span * m) as FloatPrecision;
+        for f in 0..self.gamma.data.len() {
+            let mut dgamma = 0.;
+            let mut dbeta = 0.;
+            if self.trainable {
+                for index in self.entries(f, m) {
+                    dgamma += delta.data[index] * self.xhat.data[index];
+                    dbeta += delta.data[index];
+                }
+                self.dgamma.data[f] = dgamma;
+                self.dbeta.data[f] = dbeta;
+            }
+
+            let s = self.gamma.data[f] * self.inv_std.data[f];
+            for index in self.entries(f, m) {
+                self.input_delta.data[index] = if self.batch_statistics() {
+                    // The batch statistics depend on every sample of the batch as well
+                    s * (delta.data[index] - (dbeta + self.xhat.data[index] * dgamma) / count)
+                } else {
+                    s * delta.data[index]
+                };
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(self.rate, &mut self.gamma, &self.dgamma);
+        saddm_assign(self.rate, &mut self.beta, &self.dbeta);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.gamma)?;
+        write_matrix(w, &self.beta)?;
+        write_matrix(w, &self.running_mean)?;
+        write_matrix(w, &self.running_var)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.gamma)?;
+        read_matrix(r, &mut self.beta)?;
+        read_matrix(r, &mut self.running_mean)?;
+        read_matrix(r, &mut self.running_var)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("gamma", &mut self.gamma, &mut self.dgamma),
+            Parameter::new("beta", &mut self.beta, &mut self.dbeta),
+        ]
+    }
+
+    // The running statistics turn the normalization into scale * x + shift
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        let mut scale = DMatrix::zeros((self.size, 1));
+        let mut shift = DMatrix::zeros((self.size, 1));
+        for i in 0..self.size {
+            let f = i / self.span;
+            scale.data[i] = self.gamma.data[f] / (self.running_var.data[f] + EPSILON).sqrt();
+            shift.data[i] = self.beta.data[f] - self.running_mean.data[f] * scale.data[i];
+        }
+        Some(Box::new(inference::Affine { scale, shift }))
+    }
+}
+
+// Layer normalization (Ba et al., 2016). Normalizes every sample over its
+// features, so it behaves the same for any batch size and in inference mode.
+pub struct LayerNorm {
+    size: usize,
+    pub gamma: DMatrix,
+    pub beta: DMatrix,
+    pub dgamma: DMatrix,
+    pub dbeta: DMatrix,
+    trainable: bool,
+    inv_std: DMatrix, // one per sample
+    xhat: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl LayerNorm {
+    pub fn new(size: usize, rate: FloatPrecision) -> Self {
+        Self {
+            size,
+            gamma: DMatrix::new(vec![1.; size], (size, 1)),
+            beta: DMatrix::zeros((size, 1)),
+            dgamma: DMatrix::zeros((size, 1)),
+            dbeta: DMatrix::zeros((size, 1)),
+            trainable: true,
+            inv_std: DMatrix::zeros((1, 1)),
+            xhat: DMatrix::zeros((size, 1)),
+            out: DMatrix::zeros((size, 1)),
+            input_delta: DMatrix::zeros((size, 1)),
+            rate,
+        }
+    }
+}
+
+impl Module for LayerNorm {
+    fn forward(&mut self, input: &DMatrix) {
+        let (n, m) = input.shape;
+        if self.out.shape != input.shape {
+            self.inv_std = DMatrix::zeros((1, m));
+            self.xhat = DMatrix::zeros(input.shape);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for j in 0..m {
+            let mut mean = 0.;
+            for i in 0..n {
+                mean += input.data[i * m + j];
+            }
+            mean /= n as FloatPrecision;
+            let mut var = 0.;
+            for i in 0..n {
+                let d = input.data[i * m + j] - mean;
+                var += d * d;
+            }
+            var /= n as FloatPrecision;
+
+            let inv_std = 1. / (var + EPSILON).sqrt();
+            self.inv_std.data[j] = inv_std;
+            for i in 0..n {
+                let index = i * m + j;
+                self.xhat.data[index] = (input.data[index] - mean) * inv_std;
+                self.out.data[index] = self.gamma.data[i] * self.xhat.data[index] + self.beta.data[i];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let (n, m) = delta.shape;
+        if self.trainable {
+            for i in 0..n {
+                self.dgamma.data[i] = 0.;
+                self.dbeta.data[i] = 0.;
+                for j in 0..m {
+                    let index = i * m + j;
+                    self.dgamma.data[i] += delta.data[index] * self.xhat.data[index];
+                    self.dbeta.data[i] += delta.data[index];
+                }
+            }
+        }
+        for j in 0..m {
+            // Means over the features of gamma * delta and gamma * delta * xhat
+            let mut mean = 0.;
+            let mut mean_xhat = 0.;
+            for i in 0..n {
+                let index = i * m + j;
+                let g = self.gamma.data[i] * delta.data[index];
+                mean += g;
+                mean_xhat += g * self.xhat.data[index];
+            }
+            mean /= n as FloatPrecision;
+            mean_xhat /= n as FloatPrecision;
+            for i in 0..n {
+                let index = i * m + j;
+                let g = self.gamma.data[i] * delta.data[index];
+                self.input_delta.data[index] = self.inv_std.data[j] * (g - mean - self.xhat.data[index] * mean_xhat);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(self.rate, &mut self.gamma, &self.dgamma);
+        saddm_assign(self.rate, &mut self.beta, &self.dbeta);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.gamma)?;
+        write_matrix(w, &self.beta)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.gamma)?;
+        read_matrix(r, &mut self.beta)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("gamma", &mut self.gamma, &mut self.dgamma),
+            Parameter::new("beta", &mut self.beta, &mut self.dbeta),
+        ]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Normalize {
+            gamma: self.gamma.clone(),
+            beta: self.beta.clone(),
+            center: true,
+            epsilon: EPSILON,
+        }))
+    }
+}
+
+// RMS normalization (Zhang & Sennrich, 2019). Like LayerNorm but only divides
+// by the root mean square of each sample, without subtracting the mean.
+pub struct RMSNorm {
+    size: usize,
+    pub gamma: DMatrix,
+    pub beta: DMatrix,
+    pub dgamma: DMatrix,
+    pub dbeta: DMatrix,
+    trainable: bool,
+    inv_rms: DMatrix, // one per sample
+    xhat: DMatrix,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl RMSNorm {
+    pub fn new(size: usize, rate: FloatPrecision) -> Self {
+        Self {
+            size,
+            gamma: DMatrix::new(vec![1.; size], (size, 1)),
+            beta: DMatrix::zeros((size, 1)),
+            dgamma: DMatrix::zeros((size, 1)),
+            dbeta: DMatrix::zeros((size, 1)),
+            trainable: true,
+            inv_rms: DMatrix::zeros((1, 1)),
+            xhat: DMatrix::zeros((size, 1)),
+            out: DMatrix::zeros((size, 1)),
+            input_delta: DMatrix::zeros((size, 1)),
+            rate,
+        }
+    }
+}
+
+impl Module for RMSNorm {
+    fn forward(&mut self, input: &DMatrix) {
+        let (n, m) = input.shape;
+        if self.out.shape != input.shape {
+            self.inv_rms = DMatrix::zeros((1, m));
+            self.xhat = DMatrix::zeros(input.shape);
+            self.out = DMatrix::zeros(input.shape);
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for j in 0..m {
+            let mut ms = 0.;
+            for i in 0..n {
+                let x = input.data[i * m + j];
+                ms += x * x;
+            }
+            ms /= n as FloatPrecision;
+
+            let inv_rms = 1. / (ms + EPSILON).sqrt();
+            self.inv_rms.data[j] = inv_rms;
+            for i in 0..n {
+                let index = i * m + j;
+                self.xhat.data[index] = input.data[index] * inv_rms;
+                self.out.data[index] = self.gamma.data[i] * self.xhat.data[index] + self.beta.data[i];
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let (n, m) = delta.shape;
+        if self.trainable {
+            for i in 0..n {
+                self.dgamma.data[i] = 0.;
+                self.dbeta.data[i] = 0.;
+                for j in 0..m {
+                    let index = i * m + j;
+                    self.dgamma.data[i] += delta.data[index] * self.xhat.data[index];
+                    self.dbeta.data[i] += delta.data[index];
+                }
+            }
+        }
+        for j in 0..m {
+            let mut mean_xhat = 0.;
+            for i in 0..n {
+                let index = i * m + j;
+                mean_xhat += self.gamma.data[i] * delta.data[index] * self.xhat.data[index];
+            }
+            mean_xhat /= n as FloatPrecision;
+            for i in 0..n {
+                let index = i * m + j;
+                let g = self.gamma.data[i] * delta.data[index];
+                self.input_delta.data[index] = self.inv_rms.data[j] * (g - self.xhat.data[index] * mean_xhat);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(self.rate, &mut self.gamma, &self.dgamma);
+        saddm_assign(self.rate, &mut self.beta, &self.dbeta);
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.trainable = trainable;
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.gamma)?;
+        write_matrix(w, &self.beta)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        read_matrix(r, &mut self.gamma)?;
+        read_matrix(r, &mut self.beta)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("gamma", &mut self.gamma, &mut self.dgamma),
+            Parameter::new("beta", &mut self.beta, &mut self.dbeta),
+        ]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Normalize {
+            gamma: self.gamma.clone(),
+            beta: self.beta.clone(),
+            center: false,
+            epsilon: EPSILON,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{assert_passed, check_module, matrix};
+    use crate::losses::Mse;
+
+    fn check(module: &mut dyn Module, shape: (usize, usize)) {
+        module.set_training(true);
+        let checks = check_module(module, &Mse, &matrix(shape, 1), &matrix(shape, 2));
+        assert!(checks.iter().any(|c| c.name == "input"));
+        assert!(checks.iter().any(|c| c.name == "gamma"));
+        assert!(checks.iter().any(|c| c.name == "beta"));
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn batch_norm() {
+        check(&mut BatchNorm::new(3, 0.1), (3, 4));
+    }
+
+    #[test]
+    fn spatial_batch_norm() {
+        let mut norm = BatchNorm::spatial(2, (2, 3), 0.1);
+        assert_eq!(norm.gamma.shape, (2, 1));
+        check(&mut norm, (12, 2));
+
+        // A single image still has several values per channel
+        check(&mut BatchNorm::spatial(2, (2, 2), 0.1), (8, 1));
+    }
+
+    #[test]
+    fn layer_norm() {
+        let mut norm = LayerNorm::new(5, 0.1);
+        norm.gamma = matrix((5, 1), 3);
+        norm.beta = matrix((5, 1), 4);
+        check(&mut norm, (5, 3));
+    }
+
+    #[test]
+    fn rms_norm() {
+        let mut norm = RMSNorm::new(5, 0.1);
+        norm.gamma = matrix((5, 1), 3);
+        norm.beta = matrix((5, 1), 4);
+        check(&mut norm, (5, 3));
+    }
+
+    #[test]
+    fn frozen_batch_norm() {
+        let mut norm = BatchNorm::new(3, 0.1);
+        norm.running_mean = matrix((3, 1), 5);
+        norm.set_trainable(false);
+        norm.set_training(true);
+        // Frozen parameters get no gradient, only the input delta is checked
+        let checks = check_module(&mut norm, &Mse, &matrix((3, 4), 1), &matrix((3, 4), 2));
+        assert_passed(&checks[..1]);
+        assert_eq!(norm.running_mean.data, matrix((3, 1), 5).data);
+        assert_eq!(norm.running_var.data, vec![1.; 3]);
+        assert!(norm.dgamma.data.iter().chain(norm.dbeta.data.iter()).all(|&g| g == 0.));
+
+        // A single sample is fine with the running statistics
+        norm.forward(&matrix((3, 1), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "single value per feature")]
+    fn batch_norm_single_sample() {
+        let mut norm = BatchNorm::new(3, 0.1);
+        norm.set_training(true);
+        norm.forward(&matrix((3, 1), 0));
+    }
+}
@@ -0,0 +1,427 @@
+use crate::conv::Window;
+use crate::constants::FloatPrecision;
+use crate::inference;
+use crate::inference::Inference;
+use crate::layers::Module;
+use crate::math::DMatrix;
+
+// Pooling layers work on the same channel first layout as Conv2d, one image per column.
+
+// Takes the maximum of every window, the gradient only flows to that element
+pub struct MaxPool2d {
+    pub window: Window,
+    argmax: Vec<usize>, // input row of the maximum, per output element
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl MaxPool2d {
+    // The stride defaults to the kernel size, so that windows do not overlap
+    pub fn new(channels: usize, input: (usize, usize), kernel: (usize, usize)) -> Self {
+        let mut window = Window::new(channels, input, kernel);
+        window.stride = kernel;
+        Self {
+            window,
+            argmax: Vec::new(),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    pub fn with_stride(mut self, stride: (usize, usize)) -> Self {
+        self.window.stride = stride;
+        self
+    }
+
+    // Padded positions never win the maximum. The padding has to be smaller
+    // than the kernel, so that every window contains an input position.
+    pub fn with_padding(mut self, padding: (usize, usize)) -> Self {
+        if padding.0 >= self.window.kernel.0 || padding.1 >= self.window.kernel.1 {
+            panic!("Max pooling padding {:?} must be smaller than the kernel {:?}.", padding, self.window.kernel);
+        }
+        self.window.padding = padding;
+        self
+    }
+}
+
+impl Module for MaxPool2d {
+    fn forward(&mut self, input: &DMatrix) {
+        let (h, w) = self.window.input;
+        let (kh, kw) = self.window.kernel;
+        let (ho, wo) = self.window.output();
+        let batch = input.shape.1;
+        let rows = self.window.channels * ho * wo;
+        if self.out.shape != (rows, batch) {
+            self.argmax = vec![0; rows * batch];
+            self.out = DMatrix::zeros((rows, batch));
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        for c in 0..self.window.channels {
+            for oy in 0..ho {
+                for ox in 0..wo {
+                    let row = (c * ho + oy) * wo + ox;
+                    for b in 0..batch {
+                        let mut best = FloatPrecision::NEG_INFINITY;
+                        let mut best_row = 0;
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                if let Some((y, x)) = self.window.source(oy, ox, ky, kx) {
+                                    let source = c * h * w + y * w + x;
+                                    if input.data[source * batch + b] > best {
+                                        best = input.data[source * batch + b];
+                                        best_row = source;
+                                    }
+                                }
+                            }
+                        }
+                        self.out.data[row * batch + b] = best;
+                        self.argmax[row * batch + b] = best_row;
+                    }
+                }
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        self.input_delta.data.iter_mut().for_each(|x| *x = 0.);
+        for row in 0..delta.shape.0 {
+            for b in 0..batch {
+                let index = row * batch + b;
+                self.input_delta.data[self.argmax[index] * batch + b] += delta.data[index];
+            }
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.window.output_shape(input_shape, self.window.channels)
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Pool { window: self.window, max: true }))
+    }
+}
+
+// Averages every window, the gradient is spread evenly over it
+pub struct AvgPool2d {
+    pub window: Window,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl AvgPool2d {
+    // The stride defaults to the kernel size, so that windows do not overlap
+    pub fn new(channels: usize, input: (usize, usize), kernel: (usize, usize)) -> Self {
+        let mut window = Window::new(channels, input, kernel);
+        window.stride = kernel;
+        Self {
+            window,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    pub fn with_stride(mut self, stride: (usize, usize)) -> Self {
+        self.window.stride = stride;
+        self
+    }
+
+    // Padded positions count as zeros, i.e. every window is divided by the full kernel size
+    pub fn with_padding(mut self, padding: (usize, usize)) -> Self {
+        self.window.padding = padding;
+        self
+    }
+}
+
+impl Module for AvgPool2d {
+    fn forward(&mut self, input: &DMatrix) {
+        let (h, w) = self.window.input;
+        let (kh, kw) = self.window.kernel;
+        let (ho, wo) = self.window.output();
+        let batch = input.shape.1;
+        let rows = self.window.channels * ho * wo;
+        if self.out.shape != (rows, batch) {
+            self.out = DMatrix::zeros((rows, batch));
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+
+        let size = (kh * kw) as FloatPrecision;
+        for c in 0..self.window.channels {
+            for oy in 0..ho {
+                for ox in 0..wo {
+                    let row = (c * ho + oy) * wo + ox;
+                    for b in 0..batch {
+                        let mut sum = 0.;
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                if let Some((y, x)) = self.window.source(oy, ox, ky, kx) {
+                                    sum += input.data[(c * h * w + y * w + x) * batch + b];
+                                }
+                            }
+                        }
+                        self.out.data[row * batch + b] = sum / size;
+                    }
+                }
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let (h, w) = self.window.input;
+        let (kh, kw) = self.window.kernel;
+        let (ho, wo) = self.window.output();
+        let batch = delta.shape.1;
+        let size = (kh * kw) as FloatPrecision;
+        self.input_delta.data.iter_mut().for_each(|x| *x = 0.);
+        for c in 0..self.window.channels {
+            for oy in 0..ho {
+                for ox in 0..wo {
+                    let row = (c * ho + oy) * wo + ox;
+                    for b in 0..batch {
+                        let share = delta.data[row * batch + b] / size;
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                if let Some((y, x)) = self.window.source(oy, ox, ky, kx) {
+                                    self.input_delta.data[(c * h * w + y * w + x) * batch + b] += share;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.window.output_shape(input_shape, self.window.channels)
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Pool { window: self.window, max: false }))
+    }
+}
+
+// Panics unless the input holds channels images of size pixels, like Window::output_shape
+fn check_input(input_shape: &[usize], channels: usize, size: usize) {
+    if input_shape.iter().product::<usize>() != channels * size {
+        panic!("Expected inputs of {} channels with {} pixels each, got {:?}.", channels, size, input_shape);
+    }
+}
+
+// Reduces every channel to its mean, e.g. as the last step before a dense head
+pub struct GlobalAvgPool {
+    channels: usize,
+    size: usize, // pixels per channel
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl GlobalAvgPool {
+    pub fn new(channels: usize, input: (usize, usize)) -> Self {
+        Self {
+            channels,
+            size: input.0 * input.1,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for GlobalAvgPool {
+    fn forward(&mut self, input: &DMatrix) {
+        let batch = input.shape.1;
+        if self.out.shape != (self.channels, batch) {
+            self.out = DMatrix::zeros((self.channels, batch));
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for c in 0..self.channels {
+            for b in 0..batch {
+                let mut sum = 0.;
+                for p in 0..self.size {
+                    sum += input.data[(c * self.size + p) * batch + b];
+                }
+                self.out.data[c * batch + b] = sum / self.size as FloatPrecision;
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        for c in 0..self.channels {
+            for b in 0..batch {
+                let share = delta.data[c * batch + b] / self.size as FloatPrecision;
+                for p in 0..self.size {
+                    self.input_delta.data[(c * self.size + p) * batch + b] = share;
+                }
+            }
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        check_input(input_shape, self.channels, self.size);
+        vec![self.channels]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::GlobalPool {
+            channels: self.channels,
+            size: self.size,
+            max: false,
+        }))
+    }
+}
+
+// Reduces every channel to its maximum
+pub struct GlobalMaxPool {
+    channels: usize,
+    size: usize, // pixels per channel
+    argmax: Vec<usize>,
+    pub out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl GlobalMaxPool {
+    pub fn new(channels: usize, input: (usize, usize)) -> Self {
+        Self {
+            channels,
+            size: input.0 * input.1,
+            argmax: Vec::new(),
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+}
+
+impl Module for GlobalMaxPool {
+    fn forward(&mut self, input: &DMatrix) {
+        let batch = input.shape.1;
+        if self.out.shape != (self.channels, batch) {
+            self.argmax = vec![0; self.channels * batch];
+            self.out = DMatrix::zeros((self.channels, batch));
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+        for c in 0..self.channels {
+            for b in 0..batch {
+                let mut best = FloatPrecision::NEG_INFINITY;
+                let mut best_row = 0;
+                for p in 0..self.size {
+                    let row = c * self.size + p;
+                    if input.data[row * batch + b] > best {
+                        best = input.data[row * batch + b];
+                        best_row = row;
+                    }
+                }
+                self.out.data[c * batch + b] = best;
+                self.argmax[c * batch + b] = best_row;
+            }
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        self.input_delta.data.iter_mut().for_each(|x| *x = 0.);
+        for index in 0..delta.data.len() {
+            let b = index % batch;
+            self.input_delta.data[self.argmax[index] * batch + b] = delta.data[index];
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        check_input(input_shape, self.channels, self.size);
+        vec![self.channels]
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::GlobalPool {
+            channels: self.channels,
+            size: self.size,
+            max: true,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{assert_passed, check_module, matrix};
+    use crate::losses::Mse;
+
+    // Values that are all at least 0.05 apart, so that a maximum does not
+    // change within the steps of the central differences
+    fn distinct(shape: (usize, usize), seed: usize) -> DMatrix {
+        DMatrix::new((0..shape.0 * shape.1).map(|i| ((i * 37 + seed) % 101) as FloatPrecision / 20. - 2.5).collect(), shape)
+    }
+
+    // 2 channels of 5 x 5 for 2 images
+    fn check(mut module: impl Module) {
+        let outputs: usize = module.output_shape(&[2, 5, 5]).iter().product();
+        let checks = check_module(&mut module, &Mse, &distinct((50, 2), 1), &matrix((outputs, 2), 2));
+        assert_eq!(checks.len(), 1);
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn max_pool() {
+        check(MaxPool2d::new(2, (5, 5), (2, 2)));
+        check(MaxPool2d::new(2, (5, 5), (3, 3)).with_stride((1, 2)));
+        check(MaxPool2d::new(2, (5, 5), (2, 3)).with_stride((2, 2)).with_padding((1, 1)));
+    }
+
+    #[test]
+    fn avg_pool() {
+        check(AvgPool2d::new(2, (5, 5), (2, 2)));
+        check(AvgPool2d::new(2, (5, 5), (3, 3)).with_stride((1, 2)));
+        check(AvgPool2d::new(2, (5, 5), (2, 3)).with_stride((2, 2)).with_padding((1, 1)));
+    }
+
+    #[test]
+    fn global_pools() {
+        check(GlobalMaxPool::new(2, (5, 5)));
+        check(GlobalAvgPool::new(2, (5, 5)));
+    }
+
+    #[test]
+    #[should_panic(expected = "must be smaller than the kernel")]
+    fn max_pool_padding_beyond_kernel() {
+        MaxPool2d::new(2, (5, 5), (2, 2)).with_padding((0, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected inputs of 2 channels with 25 pixels each")]
+    fn global_pool_shape() {
+        GlobalAvgPool::new(2, (5, 5)).output_shape(&[3, 5, 5]);
+    }
+}
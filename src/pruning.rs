@@ -0,0 +1,294 @@
+use crate::constants::FloatPrecision;
+use crate::layers::Parameter;
+use crate::math::DMatrix;
+
+// Magnitude pruning sets the weights with the smallest absolute values to
+// zero. Only weight matrices are pruned, biases and normalization
+// parameters are few and matter a lot.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    // One threshold for all weights of the model, so layers with many small
+    // weights lose more of them
+    Global,
+    // Every weight matrix to the same sparsity
+    PerLayer,
+}
+
+// Only parameters that are marked so, e.g. not embeddings, whose rows are looked up
+pub fn is_prunable(p: &Parameter) -> bool {
+    p.prunable
+}
+
+// Fraction of zeros in the given tensors
+pub fn sparsity(params: &[Parameter]) -> FloatPrecision {
+    let total: usize = params.iter().map(|p| p.value.data.len()).sum();
+    let zeros: usize = params.iter().map(|p| p.value.data.iter().filter(|&&w| w == 0.).count()).sum();
+    if total == 0 {
+        0.
+    } else {
+        zeros as FloatPrecision / total as FloatPrecision
+    }
+}
+
+// Keep flags that drop exactly the round(sparsity * n) smallest magnitudes
+fn keep(magnitudes: &[FloatPrecision], sparsity: FloatPrecision) -> Vec<bool> {
+    let pruned = (sparsity * magnitudes.len() as FloatPrecision).round() as usize;
+    let mut order: Vec<usize> = (0..magnitudes.len()).collect();
+    order.sort_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b]));
+    let mut keep = vec![true; magnitudes.len()];
+    for &i in order[..pruned].iter() {
+        keep[i] = false;
+    }
+    keep
+}
+
+// One mask of ones and zeros per tensor. Weights that are already zero have
+// the smallest magnitude, so pruning again to a higher sparsity keeps them pruned.
+pub fn magnitude_masks(params: &[Parameter], sparsity: FloatPrecision, scope: Scope) -> Vec<DMatrix> {
+    if !(0. ..=1.).contains(&sparsity) {
+        panic!("Sparsity must be in [0, 1], got {}.", sparsity);
+    }
+    let to_mask = |keep: &[bool], shape| DMatrix::new(keep.iter().map(|&k| if k { 1. } else { 0. }).collect(), shape);
+    match scope {
+        Scope::PerLayer => params
+            .iter()
+            .map(|p| {
+                let magnitudes: Vec<FloatPrecision> = p.value.data.iter().map(|w| w.abs()).collect();
+                to_mask(&keep(&magnitudes, sparsity), p.value.shape)
+            })
+            .collect(),
+        Scope::Global => {
+            let magnitudes: Vec<FloatPrecision> = params.iter().flat_map(|p| p.value.data.iter().map(|w| w.abs())).collect();
+            let keep = keep(&magnitudes, sparsity);
+            let mut offset = 0;
+            params
+                .iter()
+                .map(|p| {
+                    let n = p.value.data.len();
+                    offset += n;
+                    to_mask(&keep[offset - n..offset], p.value.shape)
+                })
+                .collect()
+        }
+    }
+}
+
+// Gradual pruning (Zhu & Gupta, 2017): between the steps begin and end the
+// sparsity grows from initial to target as
+// target + (initial - target) * (1 - (step - begin) / (end - begin))^3,
+// i.e. quickly while there are many redundant weights and slowly at the end.
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    pub initial: FloatPrecision,
+    pub target: FloatPrecision,
+    pub begin: usize,
+    pub end: usize,
+    pub frequency: usize, // steps between prunings
+}
+
+impl Schedule {
+    pub fn new(target: FloatPrecision, begin: usize, end: usize, frequency: usize) -> Self {
+        if end < begin || frequency == 0 {
+            panic!("Pruning schedule needs begin <= end and a frequency of at least 1, got {}, {} and {}.", begin, end, frequency);
+        }
+        Self {
+            initial: 0.,
+            target,
+            begin,
+            end,
+            frequency,
+        }
+    }
+
+    pub fn with_initial(mut self, initial: FloatPrecision) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    // Sparsity to prune to at this training step, None if the schedule does not prune now.
+    // The last pruning is always at end, so the target is reached whatever the frequency.
+    pub fn at(&self, step: usize) -> Option<FloatPrecision> {
+        if step < self.begin || step > self.end || (step != self.end && (step - self.begin) % self.frequency != 0) {
+            return None;
+        }
+        if step == self.end {
+            return Some(self.target); // also for begin == end, where progress would be 0/0
+        }
+        let progress = (step - self.begin) as FloatPrecision / (self.end - self.begin) as FloatPrecision;
+        Some(self.target + (self.initial - self.target) * (1. - progress).powi(3))
+    }
+}
+
+// Compressed sparse rows: the nonzeros of row i are values[starts[i]..starts[i + 1]]
+// in the columns with the same indices
+#[derive(Debug, Clone)]
+pub struct SparseMatrix {
+    pub values: Vec<FloatPrecision>,
+    pub columns: Vec<usize>,
+    pub starts: Vec<usize>,
+    pub shape: (usize, usize),
+}
+
+impl SparseMatrix {
+    pub fn new(m: &DMatrix) -> Self {
+        let (n, k) = m.shape;
+        let mut sparse = Self {
+            values: Vec::new(),
+            columns: Vec::new(),
+            starts: vec![0],
+            shape: m.shape,
+        };
+        for i in 0..n {
+            for j in 0..k {
+                if m.data[i * k + j] != 0. {
+                    sparse.values.push(m.data[i * k + j]);
+                    sparse.columns.push(j);
+                }
+            }
+            sparse.starts.push(sparse.values.len());
+        }
+        sparse
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.values.len() * std::mem::size_of::<FloatPrecision>()
+            + (self.columns.len() + self.starts.len()) * std::mem::size_of::<usize>()
+    }
+
+    // f(self * rhs + q) like math::linm_map, touching only the nonzeros
+    pub fn linm_map(&self, rhs: &DMatrix, q: &DMatrix, f: &dyn Fn(FloatPrecision) -> FloatPrecision, result: &mut DMatrix) {
+        let m = rhs.shape.1;
+        for i in 0..self.shape.0 {
+            let row = &mut result.data[i * m..(i + 1) * m];
+            row.iter_mut().for_each(|x| *x = q.data[i]);
+            for e in self.starts[i]..self.starts[i + 1] {
+                let w = self.values[e];
+                let k = self.columns[e];
+                for (x, &r) in row.iter_mut().zip(rhs.data[k * m..(k + 1) * m].iter()) {
+                    *x += w * r;
+                }
+            }
+            row.iter_mut().for_each(|x| *x = f(*x));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{RELU, SOFTMAX, TANH};
+    use crate::gradcheck::matrix;
+    use crate::layers::{Embedding, Layer, Module};
+    use crate::math::linm_map;
+    use crate::models::NeuralNetwork;
+
+    #[test]
+    fn schedule() {
+        let schedule = Schedule::new(0.8, 10, 25, 10).with_initial(0.2);
+        assert_eq!(schedule.at(9), None);
+        assert!((schedule.at(10).unwrap() - 0.2).abs() < 1e-12);
+        assert_eq!(schedule.at(15), None);
+        let middle = schedule.at(20).unwrap();
+        assert!(0.2 < middle && middle < 0.8);
+        // 25 is no multiple of the frequency after begin, but the end
+        assert_eq!(schedule.at(25), Some(0.8));
+        assert_eq!(schedule.at(30), None);
+
+        let once = Schedule::new(0.5, 7, 7, 3);
+        assert_eq!(once.at(7), Some(0.5));
+        assert_eq!(once.at(8), None);
+    }
+
+    #[test]
+    fn prunable() {
+        let mut layer = Layer::new(3, 2, RELU, 0.1);
+        let names: Vec<String> = layer.parameters().into_iter().filter(is_prunable).map(|p| p.name).collect();
+        assert_eq!(names, ["weights"]);
+        let mut embedding = Embedding::new(5, 4, 0.1);
+        assert!(!embedding.parameters().iter().any(is_prunable));
+    }
+
+    // NaN weights sort as the largest magnitudes instead of panicking
+    #[test]
+    fn keep_with_nan() {
+        assert_eq!(keep(&[0.3, FloatPrecision::NAN, 0.1, 0.2], 0.5), [true, true, false, false]);
+    }
+
+    // A tensor of small weights loses all of them to one global threshold,
+    // but only its share per layer
+    #[test]
+    fn scopes() {
+        let (mut small, mut large) = (DMatrix::new(vec![0.1, -0.4, 0.3, 0.2], (2, 2)), DMatrix::new(vec![-1., 4., 3., 2.], (1, 4)));
+        let (mut ds, mut dl) = (DMatrix::zeros((2, 2)), DMatrix::zeros((1, 4)));
+        let params = [Parameter::new("small", &mut small, &mut ds), Parameter::new("large", &mut large, &mut dl)];
+        let global = magnitude_masks(&params, 0.5, Scope::Global);
+        assert_eq!(global[0].data, [0., 0., 0., 0.]);
+        assert_eq!(global[1].data, [1., 1., 1., 1.]);
+        assert_eq!(global[0].shape, (2, 2));
+        let per_layer = magnitude_masks(&params, 0.5, Scope::PerLayer);
+        assert_eq!(per_layer[0].data, [0., 1., 1., 0.]);
+        assert_eq!(per_layer[1].data, [0., 1., 1., 0.]);
+        assert_eq!(magnitude_masks(&params, 0.25, Scope::Global)[0].data, [0., 1., 1., 0.]);
+    }
+
+    #[test]
+    fn sparse_matrix() {
+        let m = DMatrix::new(vec![0., 2., 0., 0., 0., 0., 0., 0., -1., 0., 3., 0.], (3, 4));
+        let sparse = SparseMatrix::new(&m);
+        assert_eq!(sparse.values, [2., -1., 3.]);
+        assert_eq!(sparse.columns, [1, 0, 2]);
+        assert_eq!(sparse.starts, [0, 1, 1, 3]);
+        assert!(sparse.bytes() < m.data.len() * std::mem::size_of::<FloatPrecision>());
+
+        let (rhs, q) = (matrix((4, 2), 1), DMatrix::new(vec![0.5, -0.5, 1.], (3, 1)));
+        let (mut expected, mut result) = (DMatrix::zeros((3, 2)), DMatrix::new(vec![9.; 6], (3, 2)));
+        linm_map(&m, &rhs, &q, &|x| x.tanh(), &mut expected);
+        sparse.linm_map(&rhs, &q, &|x| x.tanh(), &mut result);
+        for (a, b) in result.data.iter().zip(expected.data.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
+    fn zeros(nn: &mut NeuralNetwork) -> Vec<Vec<bool>> {
+        nn.parameters().iter().filter(|p| is_prunable(p)).map(|p| p.value.data.iter().map(|&w| w == 0.).collect()).collect()
+    }
+
+    // Training keeps the pruned weights at zero, and loading forgets the
+    // masks until prune is called again
+    #[test]
+    fn masks_hold() {
+        let build = || {
+            let mut nn = NeuralNetwork::new(3);
+            nn.add(Layer::new(4, 6, TANH, 0.1));
+            nn.add(Layer::new(6, 3, SOFTMAX, 0.1));
+            nn
+        };
+        let mut nn = build();
+        nn.prune(0.5, Scope::Global);
+        let pruned = zeros(&mut nn);
+        let label = DMatrix::new(vec![1., 0., 0., 0., 1., 0., 0., 0., 1., 1., 0., 0.], (3, 4));
+        for seed in 0..5 {
+            nn.train(&matrix((4, 4), seed), &label);
+        }
+        assert_eq!(zeros(&mut nn), pruned);
+        assert!((nn.sparsity() - 0.5).abs() < 1e-12);
+
+        let path = std::env::temp_dir().join("masks_hold.nn");
+        let path = path.to_str().unwrap();
+        nn.save(path).unwrap();
+        let mut stale = build();
+        stale.prune(0.9, Scope::Global);
+        stale.load(path).unwrap();
+        assert_eq!(zeros(&mut stale), pruned);
+        stale.train(&matrix((4, 4), 0), &label);
+        assert!(stale.sparsity() < 0.5);
+
+        let mut loaded = build();
+        loaded.load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        loaded.prune(0.5, Scope::Global);
+        loaded.train(&matrix((4, 4), 0), &label);
+        assert_eq!(zeros(&mut loaded), pruned);
+    }
+}
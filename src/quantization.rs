@@ -0,0 +1,277 @@
+use crate::activations::Activation;
+use crate::activations::Pointwise;
+use crate::constants::FloatPrecision;
+use crate::inference::buffers;
+use crate::inference::fit;
+use crate::inference::Inference;
+use crate::inference::Work;
+use crate::math::DMatrix;
+
+// Symmetric post-training quantization to int8: x is stored as
+// q = round(x / scale) in [-127, 127], so that x is about scale * q.
+
+const QMAX: FloatPrecision = 127.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granularity {
+    PerTensor,  // one scale for the whole weight matrix
+    PerChannel, // one scale per output unit, i.e. per row of the weights
+}
+
+// Scale that maps [-range, range] onto [-127, 127]
+pub fn scale_for(range: FloatPrecision) -> FloatPrecision {
+    if range > 0. {
+        range / QMAX
+    } else {
+        1.
+    }
+}
+
+fn quantize(x: FloatPrecision, scale: FloatPrecision) -> i8 {
+    (x / scale).round().clamp(-QMAX, QMAX) as i8
+}
+
+fn largest(values: &[FloatPrecision]) -> FloatPrecision {
+    values.iter().fold(0., |a, x| a.max(x.abs()))
+}
+
+pub struct QuantizedMatrix {
+    pub data: Vec<i8>,
+    pub scales: Vec<FloatPrecision>, // one per row, or a single one for the whole matrix
+    pub shape: (usize, usize),
+}
+
+impl QuantizedMatrix {
+    pub fn new(m: &DMatrix, granularity: Granularity) -> Self {
+        let (n, k) = m.shape;
+        let scales = match granularity {
+            Granularity::PerTensor => vec![scale_for(largest(&m.data))],
+            Granularity::PerChannel => (0..n).map(|i| scale_for(largest(&m.data[i * k..(i + 1) * k]))).collect(),
+        };
+        let mut quantized = Self {
+            data: Vec::with_capacity(n * k),
+            scales,
+            shape: m.shape,
+        };
+        for i in 0..n {
+            let scale = quantized.scale(i);
+            quantized.data.extend(m.data[i * k..(i + 1) * k].iter().map(|&x| quantize(x, scale)));
+        }
+        quantized
+    }
+
+    pub fn scale(&self, row: usize) -> FloatPrecision {
+        if self.scales.len() == 1 {
+            self.scales[0]
+        } else {
+            self.scales[row]
+        }
+    }
+
+    pub fn dequantize(&self) -> DMatrix {
+        let k = self.shape.1;
+        DMatrix::new(self.data.iter().enumerate().map(|(i, &q)| self.scale(i / k) * q as FloatPrecision).collect(), self.shape)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.data.len() + self.scales.len() * std::mem::size_of::<FloatPrecision>()
+    }
+}
+
+// result = lhs * rhs for row major lhs (n, k) and rhs (k, m). The sums are
+// exact as long as k < 2^31 / 127^2, i.e. about 133000.
+pub fn gemm_i8(lhs: &[i8], rhs: &[i8], (n, k, m): (usize, usize, usize), result: &mut [i32]) {
+    for i in 0..n {
+        let row = &mut result[i * m..(i + 1) * m];
+        row.iter_mut().for_each(|x| *x = 0);
+        for p in 0..k {
+            let a = lhs[i * k + p] as i32;
+            if a == 0 {
+                continue;
+            }
+            for (x, &b) in row.iter_mut().zip(rhs[p * m..(p + 1) * m].iter()) {
+                *x += a * b as i32;
+            }
+        }
+    }
+}
+
+// act(Wx + b) with int8 weights and inputs. The inputs are quantized with a
+// fixed scale from calibration, larger values are clipped. The bias stays a
+// float and is added after the int32 products are scaled back.
+pub struct QuantizedDense {
+    pub weights: QuantizedMatrix,
+    pub bias: DMatrix,
+    pub input_scale: FloatPrecision,
+    pub activation: Box<dyn Activation>,
+    pointwise: Option<Pointwise>,
+}
+
+impl QuantizedDense {
+    // input_range is the largest absolute input seen while calibrating
+    pub fn new(
+        weights: &DMatrix,
+        bias: &DMatrix,
+        activation: Box<dyn Activation>,
+        input_range: FloatPrecision,
+        granularity: Granularity,
+    ) -> Self {
+        Self {
+            weights: QuantizedMatrix::new(weights, granularity),
+            bias: bias.clone(),
+            input_scale: scale_for(input_range),
+            pointwise: activation.pointwise(),
+            activation,
+        }
+    }
+}
+
+impl Inference for QuantizedDense {
+    fn infer(&self, input: &DMatrix, out: &mut DMatrix, work: &mut Work) {
+        let (n, k) = self.weights.shape;
+        if input.shape.0 != k {
+            panic!("Quantized layer expects {} inputs, got shape {:?}.", k, input.shape);
+        }
+        let m = input.shape.1;
+        work.bytes.resize(k * m, 0);
+        work.integers.resize(n * m, 0);
+        for (q, &x) in work.bytes.iter_mut().zip(input.data.iter()) {
+            *q = quantize(x, self.input_scale);
+        }
+        gemm_i8(&self.weights.data, &work.bytes, (n, k, m), &mut work.integers);
+
+        fit(out, (n, m));
+        let [buffer] = buffers(&mut work.matrices);
+        if self.pointwise.is_none() {
+            fit(buffer, (n, m));
+        }
+        let net = match self.pointwise {
+            Some(_) => &mut *out,
+            None => &mut *buffer,
+        };
+        for i in 0..n {
+            let scale = self.weights.scale(i) * self.input_scale;
+            for j in 0..m {
+                let x = scale * work.integers[i * m + j] as FloatPrecision + self.bias.data[i];
+                net.data[i * m + j] = match &self.pointwise {
+                    Some(f) => f(x),
+                    None => x,
+                };
+            }
+        }
+        if self.pointwise.is_none() {
+            self.activation.forward(buffer, out);
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.weights.bytes() + self.bias.data.len() * std::mem::size_of::<FloatPrecision>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{RELU, SOFTMAX};
+    use crate::gradcheck::matrix;
+    use crate::inference::Dense;
+    use crate::layers::Layer;
+    use crate::models::NeuralNetwork;
+
+    fn assert_close(out: &DMatrix, expected: &DMatrix, tolerance: FloatPrecision) {
+        assert_eq!(out.shape, expected.shape);
+        for (a, b) in out.data.iter().zip(expected.data.iter()) {
+            assert!((a - b).abs() < tolerance, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn gemm() {
+        let (n, k, m) = (3, 5, 4);
+        let lhs: Vec<i8> = (0..n * k).map(|i| [127, -127, 0, 3, -58][i % 5]).collect();
+        let rhs: Vec<i8> = (0..k * m).map(|i| ((i * 37 % 255) as i32 - 127) as i8).collect();
+        let mut result = vec![0; n * m];
+        gemm_i8(&lhs, &rhs, (n, k, m), &mut result);
+        for i in 0..n {
+            for j in 0..m {
+                let expected: i32 = (0..k).map(|p| lhs[i * k + p] as i32 * rhs[p * m + j] as i32).sum();
+                assert_eq!(result[i * m + j], expected);
+            }
+        }
+    }
+
+    // A single scale for all rows loses the precision of the small row
+    #[test]
+    fn granularity() {
+        let m = DMatrix::new(vec![2., -1., 0.5, 0.01, -0.02, 0.003], (2, 3));
+        let tensor = QuantizedMatrix::new(&m, Granularity::PerTensor);
+        let channel = QuantizedMatrix::new(&m, Granularity::PerChannel);
+        assert_eq!(tensor.scales, vec![2. / QMAX]);
+        assert_eq!(channel.scales, vec![2. / QMAX, 0.02 / QMAX]);
+        assert_eq!(tensor.data[..3], channel.data[..3]);
+        assert_eq!(tensor.data[3..], [1, -1, 0]);
+        assert_eq!(channel.data[3..], [64, -127, 19]);
+        for q in [&tensor, &channel] {
+            for (i, (x, y)) in q.dequantize().data.iter().zip(m.data.iter()).enumerate() {
+                assert!((x - y).abs() <= q.scale(i / 3) / 2. + 1e-15);
+            }
+        }
+        assert!(tensor.bytes() < channel.bytes());
+    }
+
+    // Int8 results stay within a few quantization steps of the float layer,
+    // for fused activations and for softmax that needs the whole net
+    #[test]
+    fn quantized_dense() {
+        let weights = DMatrix::new((0..12).map(|i| (i as FloatPrecision * 0.7).sin()).collect(), (3, 4));
+        let bias = DMatrix::new(vec![0.1, -0.2, 0.3], (3, 1));
+        let input = DMatrix::new((0..8).map(|i| (i as FloatPrecision * 1.3).cos()).collect(), (4, 2));
+        for granularity in [Granularity::PerTensor, Granularity::PerChannel] {
+            for activation in [RELU.boxed(), SOFTMAX.boxed()] {
+                let float = Dense::new(weights.clone(), bias.clone(), activation.boxed());
+                let quantized = QuantizedDense::new(&weights, &bias, activation, 1., granularity);
+                let (mut expected, mut out, mut work) = (DMatrix::zeros((0, 0)), DMatrix::zeros((0, 0)), Work::default());
+                float.infer(&input, &mut expected, &mut work);
+                quantized.infer(&input, &mut out, &mut work);
+                assert_close(&out, &expected, 0.05);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Quantized layer expects 4 inputs, got shape (3, 2).")]
+    fn wrong_input() {
+        let weights = DMatrix::zeros((3, 4));
+        let quantized = QuantizedDense::new(&weights, &DMatrix::zeros((3, 1)), RELU.boxed(), 1., Granularity::PerTensor);
+        quantized.infer(&DMatrix::zeros((3, 2)), &mut DMatrix::default(), &mut Work::default());
+    }
+
+    // Every dense layer is calibrated on the outputs of the layer before it
+    #[test]
+    fn calibration() {
+        let mut nn = NeuralNetwork::new(3);
+        nn.add(Layer::new(4, 6, RELU, 0.1));
+        nn.add(Layer::new(6, 3, SOFTMAX, 0.1));
+        let batches: Vec<DMatrix> = (0..3).map(|seed| matrix((4, 5), seed)).collect();
+
+        let hidden = {
+            let params = nn.parameters();
+            Dense::new(params[0].value.clone(), params[1].value.clone(), RELU.boxed())
+        };
+        let (mut out, mut work) = (DMatrix::default(), Work::default());
+        let mut expected: [FloatPrecision; 2] = [0., 0.];
+        for batch in batches.iter() {
+            hidden.infer(batch, &mut out, &mut work);
+            expected[0] = expected[0].max(largest(&batch.data));
+            expected[1] = expected[1].max(largest(&out.data));
+        }
+        assert_eq!(nn.inference().input_ranges(&batches), expected);
+
+        let quantized = nn.quantized(&batches, Granularity::PerChannel);
+        assert!(quantized.bytes() < nn.inference().bytes());
+        for batch in batches.iter() {
+            quantized.predict(batch, &mut out);
+            assert_close(&out, nn.predict(batch), 0.02);
+        }
+    }
+}
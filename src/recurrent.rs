@@ -0,0 +1,856 @@
+use std::io;
+use std::io::{Read, Write};
+
+use rand::Rng;
+
+use crate::activations::sigmoid;
+use crate::activations::Activation;
+use crate::constants::FloatPrecision;
+use crate::inference;
+use crate::inference::fit;
+use crate::inference::Cell;
+use crate::inference::Inference;
+use crate::layers::Module;
+use crate::layers::Parameter;
+use crate::math::addm_assign;
+use crate::math::linm;
+use crate::math::mmulmt_add;
+use crate::math::mtmulm;
+use crate::math::mulm;
+use crate::math::saddm_assign;
+use crate::math::DMatrix;
+use crate::registry::Registry;
+use crate::serialize::{read_activation, read_matrix, write_activation, write_matrix};
+
+// A sequence of length T with F features is stored time major in one column,
+// i.e. feature f of step t is row t * F + f. Rows of one step are therefore
+// contiguous in the data of a batch and can be copied out at once.
+
+// Copies step t into a buffer of the shape of one step
+pub fn get_step(m: &DMatrix, t: usize, step: &mut DMatrix) {
+    let len = step.data.len();
+    step.data.copy_from_slice(&m.data[t * len..(t + 1) * len]);
+}
+
+pub fn set_step(m: &mut DMatrix, t: usize, step: &DMatrix) {
+    let len = step.data.len();
+    m.data[t * len..(t + 1) * len].copy_from_slice(&step.data);
+}
+
+// Keeps n buffers of the given shape, which are only reallocated when the
+// number of steps or the batch size changes
+fn step_buffers(list: &mut Vec<DMatrix>, n: usize, shape: (usize, usize)) {
+    list.resize_with(n, DMatrix::default);
+    list.iter_mut().for_each(|m| fit(m, shape));
+}
+
+// State t - 1 to read and state t to write
+fn states(list: &mut [DMatrix], t: usize) -> (&DMatrix, &mut DMatrix) {
+    let (before, after) = list.split_at_mut(t + 1);
+    (&before[t], &mut after[0])
+}
+
+// Options shared by all recurrent layers
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    return_sequences: bool,
+    stateful: bool,
+    bptt: Option<usize>,
+}
+
+// Weights of all gates stacked on top of each other
+pub struct Gates {
+    pub weights: DMatrix,   // (gates * hidden, input), applied to x_t
+    pub recurrent: DMatrix, // (gates * hidden, hidden), applied to h_(t-1)
+    pub bias: DMatrix,
+    pub dw: DMatrix,
+    pub du: DMatrix,
+    pub db: DMatrix,
+    trainable: bool,
+}
+
+impl Gates {
+    fn new(gates: usize, input_size: usize, hidden_size: usize) -> Self {
+        let rows = gates * hidden_size;
+        let bound = 1. / (hidden_size as FloatPrecision).sqrt();
+        let mut rng = rand::thread_rng();
+        let mut random = |n: usize| -> Vec<FloatPrecision> { (0..n).map(|_| rng.gen_range(-bound..bound)).collect() };
+        Self {
+            weights: DMatrix::new(random(rows * input_size), (rows, input_size)),
+            recurrent: DMatrix::new(random(rows * hidden_size), (rows, hidden_size)),
+            bias: DMatrix::new(random(rows), (rows, 1)),
+            dw: DMatrix::zeros((rows, input_size)),
+            du: DMatrix::zeros((rows, hidden_size)),
+            db: DMatrix::zeros((rows, 1)),
+            trainable: true,
+        }
+    }
+
+    fn zero_grad(&mut self) {
+        for m in [&mut self.dw, &mut self.du, &mut self.db] {
+            m.data.iter_mut().for_each(|x| *x = 0.);
+        }
+    }
+
+    // Adds the gradients of one step, dx and dh are the deltas of the gate inputs
+    fn accumulate(&mut self, dx: &DMatrix, x: &DMatrix, dh: &DMatrix, h: &DMatrix) {
+        if !self.trainable {
+            return;
+        }
+        mmulmt_add(dx, x, &mut self.dw);
+        mmulmt_add(dh, h, &mut self.du);
+        let batch = dx.shape.1;
+        for i in 0..self.db.data.len() {
+            self.db.data[i] += dx.data[i * batch..(i + 1) * batch].iter().sum::<FloatPrecision>();
+        }
+    }
+
+    fn update(&mut self, rate: FloatPrecision) {
+        if !self.trainable {
+            return;
+        }
+        saddm_assign(rate, &mut self.weights, &self.dw);
+        saddm_assign(rate, &mut self.recurrent, &self.du);
+        saddm_assign(rate, &mut self.bias, &self.db);
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_matrix(w, &self.weights)?;
+        write_matrix(w, &self.recurrent)?;
+        write_matrix(w, &self.bias)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        read_matrix(r, &mut self.weights)?;
+        read_matrix(r, &mut self.recurrent)?;
+        read_matrix(r, &mut self.bias)
+    }
+
+    pub fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        vec![
+            Parameter::new("weights", &mut self.weights, &mut self.dw).prunable(),
+            Parameter::new("recurrent", &mut self.recurrent, &mut self.du),
+            Parameter::new("bias", &mut self.bias, &mut self.db),
+        ]
+    }
+
+    fn inference(&self, cell: Cell, sequence: &Sequence) -> Option<Box<dyn Inference>> {
+        Some(Box::new(inference::Recurrent {
+            cell,
+            weights: self.weights.clone(),
+            recurrent: self.recurrent.clone(),
+            bias: self.bias.clone(),
+            input_size: sequence.input_size,
+            hidden_size: sequence.hidden_size,
+            return_sequences: sequence.options.return_sequences,
+        }))
+    }
+}
+
+// Bookkeeping that is the same for every recurrent layer
+struct Sequence {
+    input_size: usize,
+    hidden_size: usize,
+    options: Options,
+    steps: usize,
+    out: DMatrix,
+    input_delta: DMatrix,
+}
+
+impl Sequence {
+    fn new(input_size: usize, hidden_size: usize) -> Self {
+        Self {
+            input_size,
+            hidden_size,
+            options: Options {
+                return_sequences: false,
+                stateful: false,
+                bptt: None,
+            },
+            steps: 0,
+            out: DMatrix::zeros((0, 0)),
+            input_delta: DMatrix::zeros((0, 0)),
+        }
+    }
+
+    fn start(&mut self, input: &DMatrix) {
+        if input.shape.0 % self.input_size != 0 {
+            panic!("Expected sequences of {} features, got {} rows.", self.input_size, input.shape.0);
+        }
+        self.steps = input.shape.0 / self.input_size;
+        let batch = input.shape.1;
+        let rows = if self.options.return_sequences { self.steps * self.hidden_size } else { self.hidden_size };
+        if self.out.shape != (rows, batch) {
+            self.out = DMatrix::zeros((rows, batch));
+        }
+        if self.input_delta.shape != input.shape {
+            self.input_delta = DMatrix::zeros(input.shape);
+        }
+    }
+
+    // Initial state, the last state of the previous batch in stateful mode
+    fn initial(&self, state: &DMatrix, initial: &mut DMatrix) {
+        if self.options.stateful && state.shape == initial.shape {
+            initial.data.copy_from_slice(&state.data);
+        } else {
+            initial.data.iter_mut().for_each(|x| *x = 0.);
+        }
+    }
+
+    fn emit(&mut self, t: usize, h: &DMatrix) {
+        if self.options.return_sequences {
+            set_step(&mut self.out, t, h);
+        } else if t == self.steps - 1 {
+            self.out.data.copy_from_slice(&h.data);
+        }
+    }
+
+    // Delta of h_t coming from the output of the layer
+    fn output_delta(&self, delta: &DMatrix, t: usize, dh: &mut DMatrix) {
+        if self.options.return_sequences {
+            get_step(delta, t, dh);
+        } else if t == self.steps - 1 {
+            dh.data.copy_from_slice(&delta.data);
+        } else {
+            dh.data.iter_mut().for_each(|x| *x = 0.);
+        }
+    }
+
+    // With truncated BPTT the gradient stops flowing to earlier steps every k steps
+    fn truncated(&self, t: usize) -> bool {
+        match self.options.bptt {
+            Some(k) => t % k == 0,
+            None => false,
+        }
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let n: usize = input_shape.iter().product();
+        if n % self.input_size != 0 {
+            panic!("Expected sequences of {} features, got shape {:?}.", self.input_size, input_shape);
+        }
+        if self.options.return_sequences {
+            vec![n / self.input_size, self.hidden_size]
+        } else {
+            vec![self.hidden_size]
+        }
+    }
+}
+
+macro_rules! sequence_options {
+    ($t:ty) => {
+        impl $t {
+            // Outputs the hidden state of every step instead of only the last one
+            pub fn return_sequences(mut self) -> Self {
+                self.sequence.options.return_sequences = true;
+                self
+            }
+
+            // Starts every batch from the last state of the previous one, which
+            // needs the same batch size. Gradients do not flow across batches.
+            // The inference form always starts from zeros.
+            pub fn stateful(mut self) -> Self {
+                self.sequence.options.stateful = true;
+                self
+            }
+
+            // Truncated backpropagation through time, gradients only flow back
+            // within chunks of k steps
+            pub fn with_bptt(mut self, k: usize) -> Self {
+                self.sequence.options.bptt = Some(k);
+                self
+            }
+        }
+    };
+}
+
+// Elman RNN, h_t = f(W x_t + U h_(t-1) + b)
+pub struct Rnn {
+    sequence: Sequence,
+    pub gates: Gates,
+    pub activation: Box<dyn Activation>,
+    nets: Vec<DMatrix>,
+    hs: Vec<DMatrix>, // h_0 .. h_T
+    state: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl Rnn {
+    pub fn new<A: Activation + 'static>(input_size: usize, hidden_size: usize, activation: A, rate: FloatPrecision) -> Self {
+        Self {
+            sequence: Sequence::new(input_size, hidden_size),
+            gates: Gates::new(1, input_size, hidden_size),
+            activation: Box::new(activation),
+            nets: Vec::new(),
+            hs: Vec::new(),
+            state: DMatrix::zeros((0, 0)),
+            rate,
+        }
+    }
+
+    pub fn reset_state(&mut self) {
+        self.state = DMatrix::zeros((0, 0));
+    }
+}
+
+sequence_options!(Rnn);
+
+impl Module for Rnn {
+    fn forward(&mut self, input: &DMatrix) {
+        self.sequence.start(input);
+        let batch = input.shape.1;
+        let hidden = self.sequence.hidden_size;
+        let steps = self.sequence.steps;
+        step_buffers(&mut self.nets, steps, (hidden, batch));
+        step_buffers(&mut self.hs, steps + 1, (hidden, batch));
+        self.sequence.initial(&self.state, &mut self.hs[0]);
+
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut recurrent = DMatrix::zeros((hidden, batch));
+        for t in 0..steps {
+            get_step(input, t, &mut x);
+            let (previous, h) = states(&mut self.hs, t);
+            let net = &mut self.nets[t];
+            linm(&self.gates.weights, &x, &self.gates.bias, net); // Wx+b
+            mulm(&self.gates.recurrent, previous, &mut recurrent); // Uh
+            addm_assign(net, &recurrent);
+            self.activation.forward(net, h);
+            self.sequence.emit(t, h);
+        }
+        if self.sequence.options.stateful {
+            self.state = self.hs[steps].clone();
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        let hidden = self.sequence.hidden_size;
+        self.gates.zero_grad();
+        let mut carry = DMatrix::zeros((hidden, batch)); // delta of h_t from step t + 1
+        let mut dx = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut dnet = DMatrix::zeros((hidden, batch));
+        // Learnable activations only keep the gradient of one call, the sums over all steps are kept here
+        let mut activation_grads: Vec<DMatrix> = self.activation.parameters().iter().map(|p| DMatrix::zeros(p.grad.shape)).collect();
+        for t in (0..self.sequence.steps).rev() {
+            self.sequence.output_delta(delta, t, &mut dnet);
+            get_step(input, t, &mut x);
+            addm_assign(&mut dnet, &carry);
+            self.activation.backward(&self.nets[t], &self.hs[t + 1], &mut dnet);
+            for (sum, p) in activation_grads.iter_mut().zip(self.activation.parameters()) {
+                addm_assign(sum, p.grad);
+            }
+
+            self.gates.accumulate(&dnet, &x, &dnet, &self.hs[t]);
+            mtmulm(&self.gates.weights, &dnet, &mut dx);
+            set_step(&mut self.sequence.input_delta, t, &dx);
+            if self.sequence.truncated(t) {
+                carry.data.iter_mut().for_each(|x| *x = 0.);
+            } else {
+                mtmulm(&self.gates.recurrent, &dnet, &mut carry);
+            }
+        }
+        for (sum, p) in activation_grads.iter().zip(self.activation.parameters()) {
+            p.grad.data.copy_from_slice(&sum.data);
+        }
+    }
+
+    fn update(&mut self) {
+        self.gates.update(self.rate);
+        if self.gates.trainable {
+            self.activation.update(self.rate);
+        }
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.sequence.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.sequence.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.sequence.output_shape(input_shape)
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.gates.trainable = trainable;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.gates.save(w)?;
+        write_activation(w, &*self.activation)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.gates.load(r)?;
+        read_activation(r, &mut self.activation, registry)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        let mut params = self.gates.parameters();
+        params.extend(self.activation.parameters().into_iter().map(|p| p.prefixed("activation")));
+        params
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        self.gates.inference(Cell::Rnn(self.activation.boxed()), &self.sequence)
+    }
+}
+
+// Long short-term memory (Hochreiter & Schmidhuber, 1997) with the gates
+// stacked in the order input, forget, cell, output.
+pub struct Lstm {
+    sequence: Sequence,
+    pub gates: Gates,
+    acts: Vec<DMatrix>, // activated gates of every step
+    hs: Vec<DMatrix>,   // h_0 .. h_T
+    cs: Vec<DMatrix>,   // c_0 .. c_T
+    state: (DMatrix, DMatrix),
+    rate: FloatPrecision,
+}
+
+impl Lstm {
+    pub fn new(input_size: usize, hidden_size: usize, rate: FloatPrecision) -> Self {
+        let mut gates = Gates::new(4, input_size, hidden_size);
+        // A forget bias of 1 lets the cell remember by default (Jozefowicz et al., 2015)
+        for i in hidden_size..2 * hidden_size {
+            gates.bias.data[i] = 1.;
+        }
+        Self {
+            sequence: Sequence::new(input_size, hidden_size),
+            gates,
+            acts: Vec::new(),
+            hs: Vec::new(),
+            cs: Vec::new(),
+            state: (DMatrix::zeros((0, 0)), DMatrix::zeros((0, 0))),
+            rate,
+        }
+    }
+
+    pub fn reset_state(&mut self) {
+        self.state = (DMatrix::zeros((0, 0)), DMatrix::zeros((0, 0)));
+    }
+}
+
+sequence_options!(Lstm);
+
+impl Module for Lstm {
+    fn forward(&mut self, input: &DMatrix) {
+        self.sequence.start(input);
+        let batch = input.shape.1;
+        let hidden = self.sequence.hidden_size;
+        let n = hidden * batch; // elements of one gate
+        let steps = self.sequence.steps;
+        step_buffers(&mut self.acts, steps, (4 * hidden, batch));
+        step_buffers(&mut self.hs, steps + 1, (hidden, batch));
+        step_buffers(&mut self.cs, steps + 1, (hidden, batch));
+        self.sequence.initial(&self.state.0, &mut self.hs[0]);
+        self.sequence.initial(&self.state.1, &mut self.cs[0]);
+
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut recurrent = DMatrix::zeros((4 * hidden, batch));
+        for t in 0..steps {
+            get_step(input, t, &mut x);
+            let (h_previous, h) = states(&mut self.hs, t);
+            let (c_previous, c) = states(&mut self.cs, t);
+            let z = &mut self.acts[t];
+            linm(&self.gates.weights, &x, &self.gates.bias, z);
+            mulm(&self.gates.recurrent, h_previous, &mut recurrent);
+            addm_assign(z, &recurrent);
+
+            for e in 0..n {
+                let i = sigmoid(z.data[e]);
+                let f = sigmoid(z.data[n + e]);
+                let g = z.data[2 * n + e].tanh();
+                let o = sigmoid(z.data[3 * n + e]);
+                c.data[e] = f * c_previous.data[e] + i * g;
+                h.data[e] = o * c.data[e].tanh();
+                z.data[e] = i;
+                z.data[n + e] = f;
+                z.data[2 * n + e] = g;
+                z.data[3 * n + e] = o;
+            }
+            self.sequence.emit(t, h);
+        }
+        if self.sequence.options.stateful {
+            self.state = (self.hs[steps].clone(), self.cs[steps].clone());
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        let hidden = self.sequence.hidden_size;
+        let n = hidden * batch;
+        self.gates.zero_grad();
+        let mut dh_carry = DMatrix::zeros((hidden, batch));
+        let mut dc_carry = DMatrix::zeros((hidden, batch));
+        let mut dz = DMatrix::zeros((4 * hidden, batch));
+        let mut dx = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut dh = DMatrix::zeros((hidden, batch));
+        for t in (0..self.sequence.steps).rev() {
+            self.sequence.output_delta(delta, t, &mut dh);
+            get_step(input, t, &mut x);
+            addm_assign(&mut dh, &dh_carry);
+            let a = &self.acts[t];
+            for e in 0..n {
+                let (i, f, g, o) = (a.data[e], a.data[n + e], a.data[2 * n + e], a.data[3 * n + e]);
+                let tc = self.cs[t + 1].data[e].tanh();
+                let dc = dc_carry.data[e] + dh.data[e] * o * (1. - tc * tc);
+                dz.data[e] = dc * g * i * (1. - i);
+                dz.data[n + e] = dc * self.cs[t].data[e] * f * (1. - f);
+                dz.data[2 * n + e] = dc * i * (1. - g * g);
+                dz.data[3 * n + e] = dh.data[e] * tc * o * (1. - o);
+                dc_carry.data[e] = dc * f;
+            }
+
+            self.gates.accumulate(&dz, &x, &dz, &self.hs[t]);
+            mtmulm(&self.gates.weights, &dz, &mut dx);
+            set_step(&mut self.sequence.input_delta, t, &dx);
+            if self.sequence.truncated(t) {
+                dh_carry.data.iter_mut().for_each(|x| *x = 0.);
+                dc_carry.data.iter_mut().for_each(|x| *x = 0.);
+            } else {
+                mtmulm(&self.gates.recurrent, &dz, &mut dh_carry);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        self.gates.update(self.rate);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.sequence.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.sequence.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.sequence.output_shape(input_shape)
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.gates.trainable = trainable;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.gates.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.gates.load(r)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.gates.parameters()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        self.gates.inference(Cell::Lstm, &self.sequence)
+    }
+}
+
+// Gated recurrent unit (Cho et al., 2014) with the gates stacked in the order
+// reset, update, candidate. The reset gate is applied after the recurrent
+// product, n = tanh(W_n x + b_n + r * (U_n h)), as in cuDNN.
+pub struct Gru {
+    sequence: Sequence,
+    pub gates: Gates,
+    acts: Vec<DMatrix>,       // r, z and n of every step
+    recurrents: Vec<DMatrix>, // U h_(t-1) of every step
+    hs: Vec<DMatrix>,         // h_0 .. h_T
+    state: DMatrix,
+    rate: FloatPrecision,
+}
+
+impl Gru {
+    pub fn new(input_size: usize, hidden_size: usize, rate: FloatPrecision) -> Self {
+        Self {
+            sequence: Sequence::new(input_size, hidden_size),
+            gates: Gates::new(3, input_size, hidden_size),
+            acts: Vec::new(),
+            recurrents: Vec::new(),
+            hs: Vec::new(),
+            state: DMatrix::zeros((0, 0)),
+            rate,
+        }
+    }
+
+    pub fn reset_state(&mut self) {
+        self.state = DMatrix::zeros((0, 0));
+    }
+}
+
+sequence_options!(Gru);
+
+impl Module for Gru {
+    fn forward(&mut self, input: &DMatrix) {
+        self.sequence.start(input);
+        let batch = input.shape.1;
+        let hidden = self.sequence.hidden_size;
+        let n = hidden * batch;
+        let steps = self.sequence.steps;
+        step_buffers(&mut self.acts, steps, (3 * hidden, batch));
+        step_buffers(&mut self.recurrents, steps, (3 * hidden, batch));
+        step_buffers(&mut self.hs, steps + 1, (hidden, batch));
+        self.sequence.initial(&self.state, &mut self.hs[0]);
+
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        for t in 0..steps {
+            get_step(input, t, &mut x);
+            let (previous, h) = states(&mut self.hs, t);
+            let (a, u) = (&mut self.acts[t], &mut self.recurrents[t]);
+            linm(&self.gates.weights, &x, &self.gates.bias, a);
+            mulm(&self.gates.recurrent, previous, u);
+
+            for e in 0..n {
+                let r = sigmoid(a.data[e] + u.data[e]);
+                let z = sigmoid(a.data[n + e] + u.data[n + e]);
+                let c = (a.data[2 * n + e] + r * u.data[2 * n + e]).tanh();
+                h.data[e] = (1. - z) * c + z * previous.data[e];
+                a.data[e] = r;
+                a.data[n + e] = z;
+                a.data[2 * n + e] = c;
+            }
+            self.sequence.emit(t, h);
+        }
+        if self.sequence.options.stateful {
+            self.state = self.hs[steps].clone();
+        }
+    }
+
+    fn backward(&mut self, input: &DMatrix, delta: &DMatrix) {
+        let batch = delta.shape.1;
+        let hidden = self.sequence.hidden_size;
+        let n = hidden * batch;
+        self.gates.zero_grad();
+        let mut carry = DMatrix::zeros((hidden, batch));
+        let mut dh_prev = DMatrix::zeros((hidden, batch));
+        let mut da = DMatrix::zeros((3 * hidden, batch)); // delta of W x + b
+        let mut du = DMatrix::zeros((3 * hidden, batch)); // delta of U h
+        let mut dx = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut x = DMatrix::zeros((self.sequence.input_size, batch));
+        let mut dh = DMatrix::zeros((hidden, batch));
+        for t in (0..self.sequence.steps).rev() {
+            self.sequence.output_delta(delta, t, &mut dh);
+            get_step(input, t, &mut x);
+            addm_assign(&mut dh, &carry);
+            let a = &self.acts[t];
+            let u = &self.recurrents[t];
+            for e in 0..n {
+                let (r, z, c) = (a.data[e], a.data[n + e], a.data[2 * n + e]);
+                let dc = dh.data[e] * (1. - z) * (1. - c * c);
+                let dr = dc * u.data[2 * n + e] * r * (1. - r);
+                let dz = dh.data[e] * (self.hs[t].data[e] - c) * z * (1. - z);
+                da.data[e] = dr;
+                da.data[n + e] = dz;
+                da.data[2 * n + e] = dc;
+                du.data[e] = dr;
+                du.data[n + e] = dz;
+                du.data[2 * n + e] = dc * r;
+                dh_prev.data[e] = dh.data[e] * z;
+            }
+
+            self.gates.accumulate(&da, &x, &du, &self.hs[t]);
+            mtmulm(&self.gates.weights, &da, &mut dx);
+            set_step(&mut self.sequence.input_delta, t, &dx);
+            if self.sequence.truncated(t) {
+                carry.data.iter_mut().for_each(|x| *x = 0.);
+            } else {
+                mtmulm(&self.gates.recurrent, &du, &mut carry);
+                addm_assign(&mut carry, &dh_prev);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        self.gates.update(self.rate);
+    }
+
+    fn out(&self) -> &DMatrix {
+        &self.sequence.out
+    }
+
+    fn delta(&self) -> &DMatrix {
+        &self.sequence.input_delta
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.sequence.output_shape(input_shape)
+    }
+
+    fn set_trainable(&mut self, trainable: bool) {
+        self.gates.trainable = trainable;
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.gates.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read, registry: &Registry) -> io::Result<()> {
+        self.gates.load(r)
+    }
+
+    fn parameters(&mut self) -> Vec<Parameter<'_>> {
+        self.gates.parameters()
+    }
+
+    fn inference(&self) -> Option<Box<dyn Inference>> {
+        self.gates.inference(Cell::Gru, &self.sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{PRelu, TANH};
+    use crate::gradcheck::{assert_passed, check_module, matrix};
+    use crate::inference::Work;
+    use crate::losses::Mse;
+
+    // 4 steps of 3 features for 2 sequences
+    fn sequences(rows: usize, seed: usize) -> DMatrix {
+        matrix((rows, 2), seed)
+    }
+
+    fn check(module: &mut dyn Module, outputs: usize) {
+        let checks = check_module(module, &Mse, &sequences(12, 1), &sequences(outputs, 2));
+        assert_passed(&checks);
+    }
+
+    #[test]
+    fn rnn() {
+        check(&mut Rnn::new(3, 5, TANH, 0.1), 5);
+        check(&mut Rnn::new(3, 5, TANH, 0.1).return_sequences(), 20);
+        check(&mut Rnn::new(3, 5, PRelu::new(5), 0.1).return_sequences(), 20);
+    }
+
+    #[test]
+    fn lstm() {
+        check(&mut Lstm::new(3, 4, 0.1), 4);
+        check(&mut Lstm::new(3, 4, 0.1).return_sequences(), 16);
+    }
+
+    #[test]
+    fn gru() {
+        check(&mut Gru::new(3, 4, 0.1), 4);
+        check(&mut Gru::new(3, 4, 0.1).return_sequences(), 16);
+    }
+
+    // The inference form computes the same as forward, twice in a row to
+    // see that the state in the reused work starts from zeros again
+    fn check_inference(module: &mut dyn Module) {
+        let op = module.inference().unwrap();
+        let (mut out, mut work) = (DMatrix::zeros((0, 0)), Work::default());
+        for _ in 0..2 {
+            module.forward(&sequences(12, 1));
+            op.infer(&sequences(12, 1), &mut out, &mut work);
+            assert_eq!(out.shape, module.out().shape);
+            for (a, b) in out.data.iter().zip(module.out().data.iter()) {
+                assert!((a - b).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn inference() {
+        check_inference(&mut Rnn::new(3, 5, PRelu::new(5), 0.1));
+        check_inference(&mut Rnn::new(3, 5, TANH, 0.1).return_sequences());
+        check_inference(&mut Lstm::new(3, 4, 0.1));
+        check_inference(&mut Lstm::new(3, 4, 0.1).return_sequences());
+        check_inference(&mut Gru::new(3, 4, 0.1));
+        check_inference(&mut Gru::new(3, 4, 0.1).return_sequences());
+    }
+
+    // Rows of the steps in range of a sequence with the given features per step
+    fn steps(m: &DMatrix, range: std::ops::Range<usize>, features: usize) -> DMatrix {
+        let (start, end) = (range.start * features * m.shape.1, range.end * features * m.shape.1);
+        DMatrix::new(m.data[start..end].to_vec(), ((range.end - range.start) * features, m.shape.1))
+    }
+
+    // Truncated BPTT over 4 steps in chunks of 2 against a stateful copy that
+    // is unrolled over the two chunks one after another. The state carries
+    // over, the gradient does not, so outputs, deltas and summed gradients agree.
+    fn check_truncation(truncated: &mut dyn Module, chunked: &mut dyn Module, hidden: usize) {
+        for (p, q) in truncated.parameters().into_iter().zip(chunked.parameters()) {
+            q.value.data.copy_from_slice(&p.value.data);
+        }
+        let (input, delta) = (sequences(12, 1), sequences(4 * hidden, 2));
+        truncated.forward(&input);
+        truncated.backward(&input, &delta);
+
+        let mut grads: Vec<DMatrix> = chunked.parameters().iter().map(|p| DMatrix::zeros(p.grad.shape)).collect();
+        for chunk in [0..2, 2..4] {
+            let input = steps(&input, chunk.clone(), 3);
+            chunked.forward(&input);
+            chunked.backward(&input, &steps(&delta, chunk.clone(), hidden));
+            assert_eq!(chunked.out().data, steps(truncated.out(), chunk.clone(), hidden).data);
+            for (a, b) in chunked.delta().data.iter().zip(steps(truncated.delta(), chunk, 3).data.iter()) {
+                assert!((a - b).abs() < 1e-12);
+            }
+            for (sum, p) in grads.iter_mut().zip(chunked.parameters()) {
+                addm_assign(sum, p.grad);
+            }
+        }
+        for (sum, p) in grads.iter().zip(truncated.parameters()) {
+            for (a, b) in sum.data.iter().zip(p.grad.data.iter()) {
+                assert!((a - b).abs() < 1e-12, "{}", p.name);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_bptt() {
+        check_truncation(
+            &mut Rnn::new(3, 4, TANH, 0.1).return_sequences().with_bptt(2),
+            &mut Rnn::new(3, 4, TANH, 0.1).return_sequences().stateful(),
+            4,
+        );
+        check_truncation(
+            &mut Lstm::new(3, 4, 0.1).return_sequences().with_bptt(2),
+            &mut Lstm::new(3, 4, 0.1).return_sequences().stateful(),
+            4,
+        );
+        check_truncation(
+            &mut Gru::new(3, 4, 0.1).return_sequences().with_bptt(2),
+            &mut Gru::new(3, 4, 0.1).return_sequences().stateful(),
+            4,
+        );
+    }
+
+    // A stateful layer continues from the previous batch until reset_state
+    fn check_state<M: Module>(mut module: M, reset: fn(&mut M)) {
+        let input = sequences(12, 1);
+        module.forward(&input);
+        let fresh = module.out().clone();
+        module.forward(&input);
+        assert_ne!(module.out().data, fresh.data);
+        reset(&mut module);
+        module.forward(&input);
+        assert_eq!(module.out().data, fresh.data);
+    }
+
+    #[test]
+    fn stateful() {
+        check_state(Rnn::new(3, 4, TANH, 0.1).stateful(), Rnn::reset_state);
+        check_state(Lstm::new(3, 4, 0.1).stateful(), Lstm::reset_state);
+        check_state(Gru::new(3, 4, 0.1).stateful(), Gru::reset_state);
+    }
+
+    #[test]
+    fn frozen_rnn_keeps_activation() {
+        let mut rnn = Rnn::new(3, 5, PRelu::new(5), 0.1);
+        rnn.set_trainable(false);
+        let input = sequences(12, 1);
+        rnn.forward(&input);
+        rnn.backward(&input, &sequences(5, 2));
+        let alpha = rnn.parameters().last().unwrap().value.clone();
+        rnn.update();
+        assert_eq!(rnn.parameters().last().unwrap().value.data, alpha.data);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::activations;
+use crate::activations::Activation;
+use crate::activations::Elu;
+use crate::activations::LeakyRelu;
+use crate::activations::PRelu;
+use crate::activations::Swish;
+use crate::constants::FloatPrecision;
+
+// Builds an activation from the numbers given in parentheses after its name
+pub type ActivationBuilder = fn(&[FloatPrecision]) -> Result<Box<dyn Activation>, String>;
+
+// Maps activation names to builders, so that architectures can be described by
+// strings like "relu" or "leaky_relu(0.1)" in config files and saved models.
+pub struct Registry {
+    builders: HashMap<String, ActivationBuilder>,
+}
+
+impl Registry {
+    // An empty registry, see Registry::default for one with the built-in activations
+    pub fn new() -> Self {
+        Self {
+            builders: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, builder: ActivationBuilder) {
+        self.builders.insert(name.to_string(), builder);
+    }
+
+    // Parses "name" or "name(arg, ...)" and builds the activation
+    pub fn get(&self, spec: &str) -> Result<Box<dyn Activation>, String> {
+        let (name, args) = parse(spec)?;
+        match self.builders.get(name) {
+            Some(builder) => builder(&args),
+            None => Err(format!("Unknown activation \"{}\".", name)),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register("sigmoid", |args| fixed("sigmoid", args, activations::SIGMOID));
+        registry.register("relu", |args| fixed("relu", args, activations::RELU));
+        registry.register("linear", |args| fixed("linear", args, activations::LINEAR));
+        registry.register("tanh", |args| fixed("tanh", args, activations::TANH));
+        registry.register("selu", |args| fixed("selu", args, activations::SELU));
+        registry.register("gelu", |args| fixed("gelu", args, activations::GELU));
+        registry.register("softplus", |args| fixed("softplus", args, activations::SOFTPLUS));
+        registry.register("mish", |args| fixed("mish", args, activations::MISH));
+        registry.register("hard_sigmoid", |args| fixed("hard_sigmoid", args, activations::HARDSIGMOID));
+        registry.register("softmax", |args| fixed("softmax", args, activations::SOFTMAX));
+        registry.register("log_softmax", |args| fixed("log_softmax", args, activations::LOGSOFTMAX));
+        registry.register("sparsemax", |args| fixed("sparsemax", args, activations::SPARSEMAX));
+        registry.register("silu", |args| fixed("silu", args, activations::SILU));
+        registry.register("leaky_relu", |args| {
+            let alpha = optional("leaky_relu", args, activations::LEAKYRELU.alpha)?;
+            Ok(Box::new(LeakyRelu::new(alpha)))
+        });
+        registry.register("elu", |args| {
+            let alpha = optional("elu", args, activations::ELU.alpha)?;
+            Ok(Box::new(Elu::new(alpha)))
+        });
+        registry.register("swish", |args| {
+            let beta = optional("swish", args, activations::SWISH.beta)?;
+            Ok(Box::new(Swish::new(beta)))
+        });
+        registry.register("prelu", |args| {
+            if args.len() != 1 || args[0] < 1. || args[0].fract() != 0. {
+                return Err("prelu expects the number of units, e.g. \"prelu(128)\".".to_string());
+            }
+            Ok(Box::new(PRelu::new(args[0] as usize)))
+        });
+        registry
+    }
+}
+
+// Splits "name(1, 2.5)" into ("name", [1., 2.5])
+fn parse(spec: &str) -> Result<(&str, Vec<FloatPrecision>), String> {
+    let spec = spec.trim();
+    let open = match spec.find('(') {
+        None => return Ok((spec, Vec::new())),
+        Some(open) => open,
+    };
+    if !spec.ends_with(')') {
+        return Err(format!("Missing closing parenthesis in \"{}\".", spec));
+    }
+    let name = spec[..open].trim();
+    let inner = spec[open + 1..spec.len() - 1].trim();
+    if inner.is_empty() {
+        return Ok((name, Vec::new()));
+    }
+    let mut args = Vec::new();
+    for arg in inner.split(',') {
+        match arg.trim().parse::<FloatPrecision>() {
+            Ok(value) => args.push(value),
+            Err(_) => return Err(format!("Could not parse \"{}\" in \"{}\" as a number.", arg.trim(), spec)),
+        }
+    }
+    Ok((name, args))
+}
+
+fn fixed<A: Activation + 'static>(name: &str, args: &[FloatPrecision], activation: A) -> Result<Box<dyn Activation>, String> {
+    if !args.is_empty() {
+        return Err(format!("{} takes no parameters, got {}.", name, args.len()));
+    }
+    Ok(Box::new(activation))
+}
+
+fn optional(name: &str, args: &[FloatPrecision], default: FloatPrecision) -> Result<FloatPrecision, String> {
+    match args.len() {
+        0 => Ok(default),
+        1 => Ok(args[0]),
+        n => Err(format!("{} takes at most one parameter, got {}.", name, n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameterized() {
+        let registry = Registry::default();
+        assert_eq!(registry.get("leaky_relu(0.1)").unwrap().name(), "leaky_relu(0.1)");
+        assert_eq!(registry.get(" swish( 2 ) ").unwrap().name(), "swish(2)");
+        assert_eq!(registry.get("elu").unwrap().name(), "elu(1)");
+        assert_eq!(registry.get("prelu(16)").unwrap().name(), "prelu(16)");
+    }
+
+    #[test]
+    fn errors() {
+        let registry = Registry::default();
+        assert_eq!(registry.get("unknown").err(), Some("Unknown activation \"unknown\".".to_string()));
+        assert!(registry.get("relu(1)").is_err());
+        assert!(registry.get("leaky_relu(0.1, 0.2)").is_err());
+        assert!(registry.get("prelu(1.5)").is_err());
+        assert!(registry.get("elu(0.5").is_err());
+    }
+}
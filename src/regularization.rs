@@ -0,0 +1,186 @@
+use crate::constants::FloatPrecision;
+use crate::math::DMatrix;
+
+// Penalty on the size of weights that is added to the loss
+#[derive(Debug, Clone, Copy)]
+pub enum Regularizer {
+    L1(FloatPrecision),                                   // l1 * sum(|w|)
+    L2(FloatPrecision),                                   // l2 * sum(w^2)
+    ElasticNet { l1: FloatPrecision, l2: FloatPrecision }, // both of the above
+}
+
+impl Regularizer {
+    fn coefficients(&self) -> (FloatPrecision, FloatPrecision) {
+        match *self {
+            Regularizer::L1(l1) => (l1, 0.),
+            Regularizer::L2(l2) => (0., l2),
+            Regularizer::ElasticNet { l1, l2 } => (l1, l2),
+        }
+    }
+
+    pub fn penalty(&self, w: &DMatrix) -> FloatPrecision {
+        let (l1, l2) = self.coefficients();
+        w.data.iter().map(|x| l1 * x.abs() + l2 * x * x).sum()
+    }
+
+    // Adds the penalty to a gradient, which like all gradients is -dE/dw
+    pub fn apply(&self, w: &DMatrix, grad: &mut DMatrix) {
+        let (l1, l2) = self.coefficients();
+        for (g, &x) in grad.data.iter_mut().zip(w.data.iter()) {
+            let sign = if x > 0. { 1. } else if x < 0. { -1. } else { 0. };
+            *g -= l1 * sign + 2. * l2 * x;
+        }
+    }
+}
+
+// Projection of the weights after every update. Rows are the incoming
+// weights of one unit, which the norms are taken over.
+#[derive(Debug, Clone, Copy)]
+pub enum Constraint {
+    MaxNorm(FloatPrecision),
+    UnitNorm,
+    NonNegative,
+}
+
+impl Constraint {
+    pub fn apply(&self, w: &mut DMatrix) {
+        let (n, m) = w.shape;
+        for i in 0..n {
+            let row = &mut w.data[i * m..(i + 1) * m];
+            let norm = row.iter().map(|x| x * x).sum::<FloatPrecision>().sqrt();
+            match *self {
+                Constraint::MaxNorm(max) if norm > max => row.iter_mut().for_each(|x| *x *= max / norm),
+                Constraint::UnitNorm if norm > 0. => row.iter_mut().for_each(|x| *x /= norm),
+                Constraint::NonNegative => row.iter_mut().for_each(|x| *x = x.max(0.)),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Regularization of a layer with weights and bias, see the with_* methods of
+// Layer and Conv2d
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Penalties {
+    pub kernel: Option<Regularizer>,
+    pub bias: Option<Regularizer>,
+    // Decoupled weight decay (Loshchilov & Hutter, 2019): every update also
+    // subtracts rate * weight_decay * w, independent of the gradient
+    pub weight_decay: FloatPrecision,
+    pub constraint: Option<Constraint>,
+}
+
+impl Penalties {
+    pub fn penalty(&self, weights: &DMatrix, bias: &DMatrix) -> FloatPrecision {
+        self.kernel.map_or(0., |r| r.penalty(weights)) + self.bias.map_or(0., |r| r.penalty(bias))
+    }
+
+    pub fn gradients(&self, weights: &DMatrix, bias: &DMatrix, dw: &mut DMatrix, db: &mut DMatrix) {
+        if let Some(r) = self.kernel {
+            r.apply(weights, dw);
+        }
+        if let Some(r) = self.bias {
+            r.apply(bias, db);
+        }
+    }
+
+    // After the gradient step, with the rate scaled by the rate multiplier of
+    // the layer. A rate of zero leaves the weights unchanged.
+    pub fn update(&self, rate: FloatPrecision, weights: &mut DMatrix) {
+        if rate == 0. {
+            return;
+        }
+        if self.weight_decay != 0. {
+            let factor = 1. - rate * self.weight_decay;
+            weights.data.iter_mut().for_each(|x| *x *= factor);
+        }
+        if let Some(c) = self.constraint {
+            c.apply(weights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::TANH;
+    use crate::conv::Conv2d;
+    use crate::gradcheck::{assert_passed, check_module, check_parameters, matrix};
+    use crate::layers::{Layer, Module};
+    use crate::losses::Mse;
+
+    fn weights() -> DMatrix {
+        DMatrix::new(vec![1.5, -2., 0., 0., 3., -4.], (2, 3))
+    }
+
+    #[test]
+    fn penalties() {
+        assert!((Regularizer::L1(0.1).penalty(&weights()) - 1.05).abs() < 1e-12);
+        assert!((Regularizer::L2(0.1).penalty(&weights()) - 3.125).abs() < 1e-12);
+        let elastic = Regularizer::ElasticNet { l1: 0.1, l2: 0.1 };
+        assert!((elastic.penalty(&weights()) - 4.175).abs() < 1e-12);
+
+        // -dE/dw, where the kink of |w| at 0 gets no gradient
+        let mut grad = DMatrix::zeros((2, 3));
+        elastic.apply(&weights(), &mut grad);
+        let expected = [-0.4, 0.5, 0., 0., -0.7, 0.9];
+        for (g, e) in grad.data.iter().zip(expected.iter()) {
+            assert!((g - e).abs() < 1e-12);
+        }
+    }
+
+    // The penalties are part of the error the gradients are checked against
+    #[test]
+    fn regularized_gradients() {
+        for regularizer in [Regularizer::L1(0.05), Regularizer::L2(0.1), Regularizer::ElasticNet { l1: 0.05, l2: 0.1 }] {
+            let mut layer = Layer::new(4, 3, TANH, 0.1).with_kernel_regularizer(regularizer).with_bias_regularizer(regularizer);
+            assert!(layer.penalty() > 0.);
+            assert_passed(&check_parameters(&mut layer, &Mse, &matrix((4, 3), 1), &matrix((3, 3), 2)));
+        }
+        let mut conv = Conv2d::new(1, 2, (4, 4), (3, 3), TANH, 0.1).with_kernel_regularizer(Regularizer::L2(0.1));
+        assert_passed(&check_module(&mut conv, &Mse, &matrix((16, 2), 1), &matrix((8, 2), 2)));
+    }
+
+    #[test]
+    fn weight_decay() {
+        let penalties = Penalties {
+            weight_decay: 0.1,
+            ..Default::default()
+        };
+        let mut w = weights();
+        penalties.update(0.5, &mut w);
+        for (x, y) in w.data.iter().zip(weights().data.iter()) {
+            assert!((x - 0.95 * y).abs() < 1e-12);
+        }
+
+        // Decoupled from the gradient, a layer without error still shrinks
+        let mut layer = Layer::new(3, 2, TANH, 0.5).with_weight_decay(0.1);
+        let input = matrix((3, 2), 1);
+        Module::forward(&mut layer, &input);
+        let before = layer.weights.clone();
+        Module::backward(&mut layer, &input, &DMatrix::zeros((2, 2)));
+        Module::update(&mut layer);
+        for (x, y) in layer.weights.data.iter().zip(before.data.iter()) {
+            assert!((x - 0.95 * y).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn constraints() {
+        // Row norms are 2.5 and 5
+        let mut w = weights();
+        Constraint::MaxNorm(3.).apply(&mut w);
+        assert_eq!(w.data[..3], weights().data[..3]);
+        let norm = w.data[3..].iter().map(|x| x * x).sum::<FloatPrecision>().sqrt();
+        assert!((norm - 3.).abs() < 1e-12);
+        assert!((w.data[4] / w.data[5] - 3. / -4.).abs() < 1e-12);
+
+        let mut w = weights();
+        Constraint::UnitNorm.apply(&mut w);
+        assert!((w.data[0] - 0.6).abs() < 1e-12 && (w.data[5] + 0.8).abs() < 1e-12);
+
+        let mut w = weights();
+        Constraint::NonNegative.apply(&mut w);
+        assert_eq!(w.data, vec![1.5, 0., 0., 0., 3., 0.]);
+    }
+}
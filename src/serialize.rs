@@ -0,0 +1,99 @@
+//! Binary format for model parameters. Matrices are stored as their shape
+//! followed by the data, activations as their name followed by their own
+//! parameters, all little endian.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+use std::io::{Read, Write};
+
+use crate::activations::Activation;
+use crate::constants::FloatPrecision;
+use crate::math::DMatrix;
+use crate::registry::Registry;
+
+pub fn write_matrix(w: &mut dyn Write, m: &DMatrix) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(m.shape.0 as u64)?;
+    w.write_u64::<LittleEndian>(m.shape.1 as u64)?;
+    for &x in m.data.iter() {
+        w.write_f64::<LittleEndian>(x as f64)?;
+    }
+    Ok(())
+}
+
+// Reads a matrix into m, which must already have the stored shape
+pub fn read_matrix(r: &mut dyn Read, m: &mut DMatrix) -> io::Result<()> {
+    let n = r.read_u64::<LittleEndian>()? as usize;
+    let k = r.read_u64::<LittleEndian>()? as usize;
+    if (n, k) != m.shape {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Stored matrix has shape {n} x {k} but {} x {} was expected.", m.shape.0, m.shape.1),
+        ));
+    }
+    for i in 0..m.data.len() {
+        m.data[i] = r.read_f64::<LittleEndian>()? as FloatPrecision;
+    }
+    Ok(())
+}
+
+pub fn write_activation(w: &mut dyn Write, activation: &dyn Activation) -> io::Result<()> {
+    let name = activation.name();
+    w.write_u64::<LittleEndian>(name.len() as u64)?;
+    w.write_all(name.as_bytes())?;
+    activation.save(w)
+}
+
+// Longest activation name that is read, so that a corrupt length cannot
+// allocate a huge buffer
+const MAX_NAME: usize = 256;
+
+// Reads an activation into activation. If the stored one is a different
+// activation it is built by the registry, so a network only has to be
+// rebuilt with the right layers and sizes, not the right activations.
+pub fn read_activation(r: &mut dyn Read, activation: &mut Box<dyn Activation>, registry: &Registry) -> io::Result<()> {
+    let n = r.read_u64::<LittleEndian>()? as usize;
+    if n > MAX_NAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Stored activation name has {} bytes, more than the {} allowed.", n, MAX_NAME),
+        ));
+    }
+    let mut name = vec![0; n];
+    r.read_exact(&mut name)?;
+    let name = String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if name != activation.name() {
+        *activation = registry.get(&name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    activation.load(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activations::{RELU, TANH};
+
+    fn stored(name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u64::<LittleEndian>(name.len() as u64).unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn registered_activation() {
+        let mut registry = Registry::default();
+        registry.register("my_tanh", |_| Ok(TANH.boxed()));
+        let mut activation = RELU.boxed();
+        assert!(read_activation(&mut stored("my_tanh").as_slice(), &mut activation, &Registry::default()).is_err());
+        read_activation(&mut stored("my_tanh").as_slice(), &mut activation, &registry).unwrap();
+        assert_eq!(activation.name(), "tanh");
+    }
+
+    #[test]
+    fn corrupt_name_length() {
+        let mut bytes = Vec::new();
+        bytes.write_u64::<LittleEndian>(u64::MAX).unwrap();
+        let error = read_activation(&mut bytes.as_slice(), &mut RELU.boxed(), &Registry::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
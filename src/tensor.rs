@@ -0,0 +1,239 @@
+use std::fmt;
+
+use crate::constants::FloatPrecision;
+use crate::math::DMatrix;
+
+// An N-dimensional array. Element (i0, i1, ...) is stored at
+// data[i0 * strides[0] + i1 * strides[1] + ...], so permuting the axes
+// only rearranges shape and strides instead of moving the data.
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    pub data: Vec<FloatPrecision>,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+// Strides of a row major (contiguous) tensor, the last axis varies fastest
+fn row_major(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+impl Tensor {
+    pub fn new(data: Vec<FloatPrecision>, shape: &[usize]) -> Self {
+        let n: usize = shape.iter().product();
+        if data.len() != n {
+            panic!("Data does not fit dimensions, got {} elements but shape {:?}.", data.len(), shape);
+        }
+        Self {
+            data,
+            shape: shape.to_vec(),
+            strides: row_major(shape),
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::new(vec![0.; shape.iter().product()], shape)
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.strides == row_major(&self.shape)
+    }
+
+    fn offset(&self, index: &[usize]) -> usize {
+        if index.len() != self.rank() {
+            panic!("Index {:?} does not match a tensor of rank {}.", index, self.rank());
+        }
+        let mut offset = 0;
+        for i in 0..index.len() {
+            if index[i] >= self.shape[i] {
+                panic!("Index {:?} is out of bounds for shape {:?}.", index, self.shape);
+            }
+            offset += index[i] * self.strides[i];
+        }
+        offset
+    }
+
+    pub fn get(&self, index: &[usize]) -> FloatPrecision {
+        self.data[self.offset(index)]
+    }
+
+    pub fn set(&mut self, index: &[usize], value: FloatPrecision) {
+        let offset = self.offset(index);
+        self.data[offset] = value;
+    }
+
+    // Copies the data into row major order if the axes were permuted
+    pub fn contiguous(self) -> Self {
+        if self.is_contiguous() {
+            return self;
+        }
+        let mut data = Vec::with_capacity(self.data.len());
+        let mut index = vec![0; self.rank()];
+        for _ in 0..self.data.len() {
+            data.push(self.get(&index));
+            // Advance the index like an odometer, last axis first
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Self::new(data, &self.shape)
+    }
+
+    // Same elements in row major order with a new shape
+    pub fn reshape(self, shape: &[usize]) -> Self {
+        if shape.iter().product::<usize>() != self.data.len() {
+            panic!("Cannot reshape tensor of shape {:?} into {:?}.", self.shape, shape);
+        }
+        let mut tensor = self.contiguous();
+        tensor.strides = row_major(shape);
+        tensor.shape = shape.to_vec();
+        tensor
+    }
+
+    // Like reshape, but the one axis given as None is inferred from the others
+    pub fn reshape_infer(self, shape: &[Option<usize>]) -> Self {
+        let unknown = shape.iter().filter(|x| x.is_none()).count();
+        if unknown > 1 {
+            panic!("Cannot infer more than one axis of {:?}.", shape);
+        }
+        let known: usize = shape.iter().flatten().product();
+        if known == 0 || self.data.len() % known != 0 {
+            panic!("Cannot reshape {} elements into {:?}.", self.data.len(), shape);
+        }
+        let shape: Vec<usize> = shape.iter().map(|x| x.unwrap_or(self.data.len() / known)).collect();
+        self.reshape(&shape)
+    }
+
+    // Reorders the axes, axis i of the result is axis axes[i] of self
+    pub fn permute(self, axes: &[usize]) -> Self {
+        let mut seen = vec![false; self.rank()];
+        if axes.len() != self.rank() || axes.iter().any(|&a| a >= self.rank() || std::mem::replace(&mut seen[a], true)) {
+            panic!("{:?} is not a permutation of the {} axes.", axes, self.rank());
+        }
+        let shape = axes.iter().map(|&a| self.shape[a]).collect();
+        let strides = axes.iter().map(|&a| self.strides[a]).collect();
+        Self {
+            data: self.data,
+            shape,
+            strides,
+        }
+    }
+
+    // Swaps the last two axes
+    pub fn transpose(self) -> Self {
+        let rank = self.rank();
+        if rank < 2 {
+            panic!("Cannot transpose a tensor of rank {rank}.");
+        }
+        let mut axes: Vec<usize> = (0..rank).collect();
+        axes.swap(rank - 2, rank - 1);
+        self.permute(&axes)
+    }
+
+    // Removes all axes of size 1
+    pub fn squeeze(self) -> Self {
+        let keep: Vec<usize> = (0..self.rank()).filter(|&i| self.shape[i] != 1).collect();
+        Self {
+            shape: keep.iter().map(|&i| self.shape[i]).collect(),
+            strides: keep.iter().map(|&i| self.strides[i]).collect(),
+            data: self.data,
+        }
+    }
+
+    pub fn squeeze_axis(mut self, axis: usize) -> Self {
+        if self.shape[axis] != 1 {
+            panic!("Cannot squeeze axis {} of size {}.", axis, self.shape[axis]);
+        }
+        self.shape.remove(axis);
+        self.strides.remove(axis);
+        self
+    }
+
+    // Inserts an axis of size 1 before axis
+    pub fn unsqueeze(mut self, axis: usize) -> Self {
+        let stride = if axis < self.rank() { self.shape[axis] * self.strides[axis] } else { 1 };
+        self.shape.insert(axis, 1);
+        self.strides.insert(axis, stride);
+        self
+    }
+
+    // Moves the data into a matrix, only copying if the axes were permuted
+    pub fn into_dmatrix(self) -> DMatrix {
+        if self.rank() != 2 {
+            panic!("Only tensors of rank 2 are matrices, got shape {:?}.", self.shape);
+        }
+        let shape = (self.shape[0], self.shape[1]);
+        DMatrix::new(self.contiguous().data, shape)
+    }
+
+    pub fn to_dmatrix(&self) -> DMatrix {
+        self.clone().into_dmatrix()
+    }
+}
+
+impl From<DMatrix> for Tensor {
+    fn from(m: DMatrix) -> Self {
+        Tensor::new(m.data, &[m.shape.0, m.shape.1])
+    }
+}
+
+impl fmt::Display for Tensor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tensor of shape {:?}: [", self.shape)?;
+        let tensor = self.clone().contiguous();
+        for (i, x) in tensor.data.iter().take(10).enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", x)?;
+        }
+        if tensor.data.len() > 10 {
+            write!(f, ", ...")?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reshape_infer() {
+        let t = Tensor::new((0..24).map(|x| x as FloatPrecision).collect(), &[2, 3, 4]);
+        let r = t.clone().reshape_infer(&[Some(4), None]);
+        assert_eq!(r.shape(), &[4, 6]);
+        assert_eq!(r.get(&[1, 2]), 8.);
+        assert_eq!(t.transpose().reshape_infer(&[None]).shape(), &[24]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot reshape 24 elements")]
+    fn reshape_infer_uneven() {
+        Tensor::zeros(&[2, 3, 4]).reshape_infer(&[Some(5), None]);
+    }
+}